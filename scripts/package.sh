@@ -4,8 +4,8 @@ basedir="$(cd $(dirname $0)/.. && pwd -P)"
 target="$basedir/target"
 version="$(git -C "$basedir" describe)"
 arches="x86_64"
-apps="snx-rs snxctl snx-rs-gui"
-assets="snx-rs.conf snx-rs.service snx-rs-gui.desktop"
+apps="snx-rs snxctl snx-rs-gui snx-rs-nm"
+assets="snx-rs.conf snx-rs.service snx-rs-gui.desktop nm-snx-rs-service.name nm-snx-rs-service.conf"
 
 for arch in $arches; do
     name="snx-rs-$version-linux-$arch"
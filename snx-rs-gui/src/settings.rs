@@ -1,4 +1,4 @@
-use std::{path::Path, rc::Rc, sync::Arc, time::Duration};
+use std::{net::IpAddr, path::Path, rc::Rc, sync::Arc, time::Duration};
 
 use anyhow::anyhow;
 use gtk::{
@@ -11,10 +11,10 @@ use tracing::warn;
 
 use snxcore::{
     model::{
-        params::{TunnelParams, TunnelType},
+        params::{DnsBackend, IpsecDatapath, IpsecTransportType, TunnelParams, TunnelType},
         proto::LoginOption,
     },
-    server_info,
+    proxy, server_info,
 };
 
 const CSS_ERROR: &str = r"label {
@@ -41,6 +41,10 @@ struct MyWidgets {
     no_dns: gtk::CheckButton,
     search_domains: gtk::Entry,
     ignored_domains: gtk::Entry,
+    routing_domains: gtk::Entry,
+    dnssec: gtk::ComboBoxText,
+    dns_over_tls: gtk::ComboBoxText,
+    dns_backend: gtk::ComboBoxText,
     no_routing: gtk::CheckButton,
     default_routing: gtk::CheckButton,
     add_routes: gtk::Entry,
@@ -58,6 +62,16 @@ struct MyWidgets {
     ike_lifetime: gtk::Entry,
     esp_lifetime: gtk::Entry,
     ike_port: gtk::Entry,
+    tcpt_port: gtk::Entry,
+    natt_port: gtk::Entry,
+    server_ip: gtk::Entry,
+    mtu: gtk::Entry,
+    mss_clamp: gtk::CheckButton,
+    ipsec_datapath: gtk::ComboBoxText,
+    ipsec_transport: gtk::ComboBoxText,
+    esp_transforms: gtk::Entry,
+    proxy_url: gtk::Entry,
+    proxy_auth: gtk::Entry,
     error: gtk::Label,
 }
 
@@ -96,6 +110,31 @@ impl MyWidgets {
         self.esp_lifetime.text().parse::<u32>()?;
         self.ike_port.text().parse::<u16>()?;
 
+        let tcpt_port = self.tcpt_port.text();
+        if !tcpt_port.is_empty() {
+            tcpt_port.parse::<u16>()?;
+        }
+
+        let natt_port = self.natt_port.text();
+        if !natt_port.is_empty() {
+            natt_port.parse::<u16>()?;
+        }
+
+        let server_ip = self.server_ip.text();
+        if !server_ip.is_empty() {
+            server_ip.parse::<IpAddr>()?;
+        }
+
+        let mtu = self.mtu.text();
+        if !mtu.is_empty() {
+            mtu.parse::<u16>()?;
+        }
+
+        let proxy_url = self.proxy_url.text();
+        if !proxy_url.is_empty() {
+            proxy::parse_proxy_url(&proxy_url)?;
+        }
+
         let add_routes = self.add_routes.text();
         if !add_routes.is_empty() {
             for r in add_routes.split(',') {
@@ -146,6 +185,38 @@ impl SettingsDialog {
             .text(params.ignore_search_domains.join(","))
             .build();
 
+        let routing_domains = gtk::Entry::builder()
+            .placeholder_text("Comma-separated domains")
+            .text(params.routing_domains.join(","))
+            .build();
+
+        let dnssec = gtk::ComboBoxText::builder().build();
+        dnssec.append(Some(""), "Default");
+        for mode in ["yes", "no", "allow-downgrade"] {
+            dnssec.append(Some(mode), mode);
+        }
+        dnssec.set_active_id(Some(params.dnssec.as_deref().unwrap_or_default()));
+
+        let dns_over_tls = gtk::ComboBoxText::builder().build();
+        dns_over_tls.append(Some(""), "Default");
+        for mode in ["yes", "no", "opportunistic"] {
+            dns_over_tls.append(Some(mode), mode);
+        }
+        dns_over_tls.set_active_id(Some(params.dns_over_tls.as_deref().unwrap_or_default()));
+
+        let dns_backend = gtk::ComboBoxText::builder().build();
+        for (backend, text) in [
+            (DnsBackend::Auto, "Automatic"),
+            (DnsBackend::Resolved, "systemd-resolved"),
+            (DnsBackend::NetworkManager, "NetworkManager"),
+            (DnsBackend::Dnsmasq, "dnsmasq"),
+            (DnsBackend::ResolvConf, "/etc/resolv.conf"),
+            (DnsBackend::Stub, "Built-in stub resolver"),
+        ] {
+            dns_backend.append(Some(backend.as_str()), text);
+        }
+        dns_backend.set_active_id(Some(params.dns_backend.as_str()));
+
         let no_routing = gtk::CheckButton::builder().active(params.no_routing).build();
         let default_routing = gtk::CheckButton::builder().active(params.default_route).build();
 
@@ -213,6 +284,38 @@ impl SettingsDialog {
             .text(params.esp_lifetime.as_secs().to_string())
             .build();
         let ike_port = gtk::Entry::builder().text(params.ike_port.to_string()).build();
+        let tcpt_port = gtk::Entry::builder()
+            .placeholder_text("Advertised by the server")
+            .text(params.tcpt_port.map(|p| p.to_string()).unwrap_or_default())
+            .build();
+        let natt_port = gtk::Entry::builder()
+            .placeholder_text("Advertised by the server")
+            .text(params.natt_port.map(|p| p.to_string()).unwrap_or_default())
+            .build();
+        let server_ip = gtk::Entry::builder()
+            .placeholder_text("Advertised by the server")
+            .text(params.server_ip.map(|ip| ip.to_string()).unwrap_or_default())
+            .build();
+        let mtu = gtk::Entry::builder()
+            .placeholder_text("Automatic")
+            .text(params.mtu.map(|mtu| mtu.to_string()).unwrap_or_default())
+            .build();
+        let mss_clamp = gtk::CheckButton::builder().active(params.mss_clamp).build();
+        let ipsec_datapath = gtk::ComboBoxText::builder().build();
+        let ipsec_transport = gtk::ComboBoxText::builder().build();
+        let esp_transforms = gtk::Entry::builder()
+            .placeholder_text("All supported")
+            .text(params.esp_transforms.join(","))
+            .build();
+        let proxy_url = gtk::Entry::builder()
+            .placeholder_text("http://host:port or socks5://host:port")
+            .text(params.proxy_url.as_deref().unwrap_or_default())
+            .build();
+        let proxy_auth = gtk::Entry::builder()
+            .placeholder_text("user:password")
+            .text(params.proxy_auth.as_deref().unwrap_or_default())
+            .visibility(false)
+            .build();
 
         let provider = gtk::CssProvider::new();
         provider.load_from_data(CSS_ERROR.as_bytes()).unwrap();
@@ -221,7 +324,7 @@ impl SettingsDialog {
         error.style_context().add_provider(&provider, 100);
 
         auth_type.connect_active_notify(
-            clone!(@weak auth_type, @weak user_name, @weak password, @weak tunnel_type => move |widget| {
+            clone!(@weak auth_type, @weak user_name, @weak password => move |widget| {
                 if let Some(id) = widget.active_id() {
                     let factors = unsafe { auth_type.data::<Vec<String>>(&id).map(|p| p.as_ref()) };
                     if let Some(factors) = factors {
@@ -229,12 +332,6 @@ impl SettingsDialog {
                         let is_cert = factors.iter().any(|f| f == "certificate");
                         user_name.set_sensitive(!is_saml && !is_cert);
                         password.set_sensitive(!is_saml && !is_cert);
-                        if is_saml {
-                            tunnel_type.set_active(Some(0));
-                            tunnel_type.set_sensitive(false);
-                        } else {
-                            tunnel_type.set_sensitive(true);
-                        }
                     }
                 }
             }),
@@ -324,6 +421,10 @@ impl SettingsDialog {
             no_dns,
             search_domains,
             ignored_domains,
+            routing_domains,
+            dnssec,
+            dns_over_tls,
+            dns_backend,
             no_routing,
             default_routing,
             add_routes,
@@ -341,6 +442,16 @@ impl SettingsDialog {
             ike_lifetime,
             esp_lifetime,
             ike_port,
+            tcpt_port,
+            natt_port,
+            server_ip,
+            mtu,
+            mss_clamp,
+            ipsec_datapath,
+            ipsec_transport,
+            esp_transforms,
+            proxy_url,
+            proxy_auth,
             error,
         });
 
@@ -403,6 +514,32 @@ impl SettingsDialog {
             .split(',')
             .map(|s| s.trim().to_owned())
             .collect();
+        params.routing_domains = self
+            .widgets
+            .routing_domains
+            .text()
+            .split(',')
+            .map(|s| s.trim().to_owned())
+            .filter(|s| !s.is_empty())
+            .collect();
+        params.dnssec = self
+            .widgets
+            .dnssec
+            .active_id()
+            .filter(|id| !id.is_empty())
+            .map(Into::into);
+        params.dns_over_tls = self
+            .widgets
+            .dns_over_tls
+            .active_id()
+            .filter(|id| !id.is_empty())
+            .map(Into::into);
+        params.dns_backend = self
+            .widgets
+            .dns_backend
+            .active_id()
+            .and_then(|id| id.parse().ok())
+            .unwrap_or_default();
         params.no_routing = self.widgets.no_routing.is_active();
         params.default_route = self.widgets.default_routing.is_active();
         params.add_routes = self
@@ -459,6 +596,44 @@ impl SettingsDialog {
         params.ike_lifetime = Duration::from_secs(self.widgets.ike_lifetime.text().parse()?);
         params.esp_lifetime = Duration::from_secs(self.widgets.esp_lifetime.text().parse()?);
         params.ike_port = self.widgets.ike_port.text().parse()?;
+        params.tcpt_port = self.widgets.tcpt_port.text().parse().ok();
+        params.natt_port = self.widgets.natt_port.text().parse().ok();
+        params.server_ip = self.widgets.server_ip.text().parse().ok();
+        params.mtu = self.widgets.mtu.text().parse().ok();
+        params.mss_clamp = self.widgets.mss_clamp.is_active();
+        params.ipsec_datapath = match self.widgets.ipsec_datapath.active().unwrap_or_default() {
+            0 => IpsecDatapath::Kernel,
+            _ => IpsecDatapath::Userspace,
+        };
+        params.ipsec_transport = match self.widgets.ipsec_transport.active().unwrap_or_default() {
+            1 => IpsecTransportType::Udp,
+            2 => IpsecTransportType::Tcpt,
+            _ => IpsecTransportType::Auto,
+        };
+        params.esp_transforms = self
+            .widgets
+            .esp_transforms
+            .text()
+            .split(',')
+            .map(|s| s.trim().to_owned())
+            .filter(|s| !s.is_empty())
+            .collect();
+        params.proxy_url = {
+            let text = self.widgets.proxy_url.text();
+            if text.is_empty() {
+                None
+            } else {
+                Some(text.into())
+            }
+        };
+        params.proxy_auth = {
+            let text = self.widgets.proxy_auth.text();
+            if text.is_empty() {
+                None
+            } else {
+                Some(text.into())
+            }
+        };
 
         params.save()?;
 
@@ -558,6 +733,22 @@ impl SettingsDialog {
         ignored_domains.pack_start(&self.widgets.ignored_domains, false, true, 0);
         dns_box.pack_start(&ignored_domains, false, true, 6);
 
+        let routing_domains = self.form_box("Routing-only domains");
+        routing_domains.pack_start(&self.widgets.routing_domains, false, true, 0);
+        dns_box.pack_start(&routing_domains, false, true, 6);
+
+        let dnssec = self.form_box("DNSSEC");
+        dnssec.pack_start(&self.widgets.dnssec, false, true, 0);
+        dns_box.pack_start(&dnssec, false, true, 6);
+
+        let dns_over_tls = self.form_box("DNS over TLS");
+        dns_over_tls.pack_start(&self.widgets.dns_over_tls, false, true, 0);
+        dns_box.pack_start(&dns_over_tls, false, true, 6);
+
+        let dns_backend = self.form_box("DNS backend");
+        dns_backend.pack_start(&self.widgets.dns_backend, false, true, 0);
+        dns_box.pack_start(&dns_backend, false, true, 6);
+
         dns_box
     }
 
@@ -631,6 +822,65 @@ impl SettingsDialog {
         ike_port.pack_start(&self.widgets.ike_port, false, true, 0);
         misc_box.pack_start(&ike_port, false, true, 6);
 
+        let tcpt_port = self.form_box("SSL tunnel port");
+        tcpt_port.pack_start(&self.widgets.tcpt_port, false, true, 0);
+        misc_box.pack_start(&tcpt_port, false, true, 6);
+
+        let natt_port = self.form_box("NAT-T port");
+        natt_port.pack_start(&self.widgets.natt_port, false, true, 0);
+        misc_box.pack_start(&natt_port, false, true, 6);
+
+        let server_ip = self.form_box("Tunnel server IP address");
+        server_ip.pack_start(&self.widgets.server_ip, false, true, 0);
+        misc_box.pack_start(&server_ip, false, true, 6);
+
+        let mtu = self.form_box("Tunnel MTU");
+        mtu.pack_start(&self.widgets.mtu, false, true, 0);
+        misc_box.pack_start(&mtu, false, true, 6);
+
+        let mss_clamp = self.form_box("Clamp TCP MSS to the tunnel MTU");
+        mss_clamp.pack_start(&self.widgets.mss_clamp, false, true, 0);
+        misc_box.pack_start(&mss_clamp, false, true, 6);
+
+        let ipsec_datapath = self.form_box("IPSec datapath");
+        self.widgets.ipsec_datapath.insert_text(0, "Kernel XFRM");
+        self.widgets.ipsec_datapath.insert_text(1, "Userspace");
+        self.widgets
+            .ipsec_datapath
+            .set_active(if self.params.ipsec_datapath == IpsecDatapath::Kernel {
+                Some(0)
+            } else {
+                Some(1)
+            });
+        ipsec_datapath.pack_start(&self.widgets.ipsec_datapath, false, true, 0);
+        misc_box.pack_start(&ipsec_datapath, false, true, 6);
+
+        let ipsec_transport = self.form_box("IPSec transport");
+        self.widgets.ipsec_transport.insert_text(0, "Automatic");
+        self.widgets.ipsec_transport.insert_text(1, "UDP");
+        self.widgets.ipsec_transport.insert_text(2, "TCPT (visitor mode)");
+        self.widgets
+            .ipsec_transport
+            .set_active(Some(match self.params.ipsec_transport {
+                IpsecTransportType::Auto => 0,
+                IpsecTransportType::Udp => 1,
+                IpsecTransportType::Tcpt => 2,
+            }));
+        ipsec_transport.pack_start(&self.widgets.ipsec_transport, false, true, 0);
+        misc_box.pack_start(&ipsec_transport, false, true, 6);
+
+        let esp_transforms = self.form_box("Allowed ESP algorithms");
+        esp_transforms.pack_start(&self.widgets.esp_transforms, false, true, 0);
+        misc_box.pack_start(&esp_transforms, false, true, 6);
+
+        let proxy_url = self.form_box("Proxy URL");
+        proxy_url.pack_start(&self.widgets.proxy_url, false, true, 0);
+        misc_box.pack_start(&proxy_url, false, true, 6);
+
+        let proxy_auth = self.form_box("Proxy credentials");
+        proxy_auth.pack_start(&self.widgets.proxy_auth, false, true, 0);
+        misc_box.pack_start(&proxy_auth, false, true, 6);
+
         misc_box
     }
 
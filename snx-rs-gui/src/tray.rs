@@ -14,6 +14,7 @@ use snxcore::{
     controller::{ServiceCommand, ServiceController},
     model::{params::TunnelParams, ConnectionStatus},
     prompt::SecurePrompt,
+    util,
 };
 
 const TITLE: &str = "SNX-RS VPN client";
@@ -77,6 +78,8 @@ impl AppTray {
                         } else {
                             format!("Connected since: {}", since.to_rfc2822())
                         }
+                    } else if let Some(ref reason) = status.last_disconnect_reason {
+                        format!("Tunnel disconnected: {}", reason)
                     } else {
                         "Tunnel disconnected".to_owned()
                     }
@@ -86,9 +89,27 @@ impl AppTray {
         }
     }
 
+    fn statistics_label(&self) -> Option<String> {
+        let stats = self.status.as_ref().ok()?.statistics.as_ref()?;
+        let mut label = format!(
+            "Sent: {} ({}/s), received: {} ({}/s)",
+            util::format_bytes(stats.bytes_sent),
+            util::format_bytes(stats.send_rate),
+            util::format_bytes(stats.bytes_received),
+            util::format_bytes(stats.receive_rate)
+        );
+        if let Some(rtt) = stats.keepalive_rtt {
+            label.push_str(&format!(", RTT: {} ms", rtt.as_millis()));
+        }
+        Some(label)
+    }
+
     fn menu(&self) -> anyhow::Result<Box<dyn ContextMenu>> {
         let menu = Menu::new();
         menu.append(&MenuItem::new(self.status_label(), false, None))?;
+        if let Some(label) = self.statistics_label() {
+            menu.append(&MenuItem::new(label, false, None))?;
+        }
         menu.append(&PredefinedMenuItem::separator())?;
         menu.append(&MenuItem::with_id(
             "connect",
@@ -0,0 +1,72 @@
+use anyhow::anyhow;
+use clap::Parser;
+use tokio::sync::mpsc;
+use tracing::{debug, metadata::LevelFilter};
+use zbus::SignalContext;
+
+use crate::plugin::{PluginRunner, VpnPlugin, OBJECT_PATH, SERVICE_NAME};
+
+mod plugin;
+
+#[derive(Parser)]
+#[clap(
+    about = "NetworkManager VPN plugin for Checkpoint security gateway",
+    name = "snx-rs-nm"
+)]
+pub struct CmdlineParams {
+    #[clap(long = "bus-name", default_value = SERVICE_NAME, help = "D-Bus name to own")]
+    bus_name: String,
+    #[clap(long = "persist", help = "Do not exit when idle")]
+    persist: bool,
+    #[clap(
+        long = "log-level",
+        short = 'l',
+        default_value = "info",
+        help = "Enable logging to stdout, one of off, info, warn, error, debug, trace"
+    )]
+    log_level: String,
+}
+
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cmdline_params = CmdlineParams::parse();
+
+    if !is_root() {
+        return Err(anyhow!("Please run me as a root user!"));
+    }
+
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(
+            cmdline_params
+                .log_level
+                .parse::<LevelFilter>()
+                .unwrap_or(LevelFilter::OFF),
+        )
+        .finish();
+    tracing::subscriber::set_global_default(subscriber)?;
+
+    debug!(
+        ">>> Starting snx-rs NetworkManager plugin version {}",
+        env!("CARGO_PKG_VERSION")
+    );
+
+    let (command_sender, command_receiver) = mpsc::channel(16);
+    let plugin = VpnPlugin::new(command_sender);
+    let state = plugin.state_handle();
+
+    let connection = zbus::connection::Builder::system()?
+        .name(cmdline_params.bus_name.as_str())?
+        .serve_at(OBJECT_PATH, plugin)?
+        .build()
+        .await?;
+
+    let ctxt = SignalContext::new(&connection, OBJECT_PATH)?.into_owned();
+
+    PluginRunner::new(ctxt, state, command_receiver)
+        .run(cmdline_params.persist)
+        .await
+}
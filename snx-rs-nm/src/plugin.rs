@@ -0,0 +1,610 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, Ipv4Addr},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use futures::pin_mut;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+use zbus::{
+    fdo,
+    zvariant::{OwnedValue, Value},
+    SignalContext,
+};
+
+use snxcore::{
+    model::{params::TunnelParams, MfaType, SessionState, VpnSession},
+    server_info,
+    tunnel::{self, TunnelConfig, TunnelConnector, TunnelEvent},
+};
+
+pub const SERVICE_NAME: &str = "org.freedesktop.NetworkManager.snx_rs";
+pub const OBJECT_PATH: &str = "/org/freedesktop/NetworkManager/VPN/Plugin";
+
+const VPN_SETTING: &str = "vpn";
+const PASSWORD_SECRET: &str = "password";
+const CERT_PASSWORD_SECRET: &str = "cert-password";
+const CHALLENGE_SECRET: &str = "challenge";
+
+// NetworkManager starts the plugin on demand, it is expected to exit when not used
+const IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+const SECRETS_TIMEOUT: Duration = Duration::from_secs(120);
+
+pub type ConnectionSettings = HashMap<String, HashMap<String, OwnedValue>>;
+
+/// NMVpnServiceState
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(u32)]
+pub enum ServiceState {
+    Init = 1,
+    Starting = 3,
+    Started = 4,
+    Stopping = 5,
+    Stopped = 6,
+}
+
+/// NMVpnPluginFailure
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(u32)]
+pub enum PluginFailure {
+    LoginFailed = 0,
+    ConnectFailed = 1,
+}
+
+#[derive(Debug)]
+pub enum PluginCommand {
+    Connect { params: TunnelParams, interactive: bool },
+    NewSecrets(HashMap<String, String>),
+    Disconnect,
+}
+
+/// Data and secrets of the VPN setting of the NetworkManager connection
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct VpnSettings {
+    pub data: HashMap<String, String>,
+    pub secrets: HashMap<String, String>,
+}
+
+impl VpnSettings {
+    pub fn from_connection(connection: &ConnectionSettings) -> anyhow::Result<Self> {
+        let setting = connection
+            .get(VPN_SETTING)
+            .ok_or_else(|| anyhow!("No VPN setting in the connection"))?;
+
+        Ok(Self {
+            data: string_dict(setting, "data")?,
+            secrets: string_dict(setting, "secrets")?,
+        })
+    }
+
+    /// The data keys are the same as the options of the configuration file
+    pub fn to_params(&self) -> TunnelParams {
+        let mut params = TunnelParams::default();
+
+        for (key, value) in &self.data {
+            let value = value.trim();
+            if !value.is_empty() {
+                params.set_option(key, value.to_owned());
+            }
+        }
+
+        if let Some(password) = self.secrets.get(PASSWORD_SECRET) {
+            password.clone_into(&mut params.password);
+        }
+
+        if let Some(cert_password) = self.secrets.get(CERT_PASSWORD_SECRET) {
+            params.cert_password = Some(cert_password.clone());
+        }
+
+        // the secrets are kept by NetworkManager, there is no user keychain available to the plugin
+        params.no_keychain = true;
+
+        params
+    }
+}
+
+fn string_dict(setting: &HashMap<String, OwnedValue>, key: &str) -> anyhow::Result<HashMap<String, String>> {
+    match setting.get(key) {
+        Some(value) => Ok(HashMap::try_from(value.try_clone()?)?),
+        None => Ok(HashMap::new()),
+    }
+}
+
+// NetworkManager expects IPv4 addresses as integers in the network byte order
+fn ipv4_to_u32(address: Ipv4Addr) -> u32 {
+    u32::from_ne_bytes(address.octets())
+}
+
+/// Tunnel parameters for the connector, the routes and DNS are applied by NetworkManager from the reported configuration
+fn connector_params(params: &TunnelParams) -> TunnelParams {
+    TunnelParams {
+        default_route: false,
+        no_routing: true,
+        add_routes: Vec::new(),
+        no_dns: true,
+        keep_device_managed: true,
+        ..params.clone()
+    }
+}
+
+/// Generic configuration for the Config signal
+pub fn vpn_config(config: &TunnelConfig) -> HashMap<&'static str, Value<'static>> {
+    let mut result = HashMap::new();
+
+    result.insert("tundev", Value::from(config.device.clone()));
+    result.insert(
+        "gateway",
+        match config.gateway {
+            IpAddr::V4(gateway) => ipv4_to_u32(gateway).into(),
+            IpAddr::V6(gateway) => gateway.octets().to_vec().into(),
+        },
+    );
+    result.insert("mtu", u32::from(config.mtu).into());
+    result.insert("has-ip4", true.into());
+    result.insert("has-ip6", false.into());
+
+    result
+}
+
+/// IPv4 configuration for the Ip4Config signal, the routing and DNS options of the connection are applied here
+pub fn ip4_config(config: &TunnelConfig, params: &TunnelParams) -> HashMap<&'static str, Value<'static>> {
+    let mut result = HashMap::new();
+
+    result.insert("address", Value::from(ipv4_to_u32(config.address.addr())));
+    result.insert("prefix", u32::from(config.address.prefix_len()).into());
+
+    let mut routes = params.add_routes.clone();
+
+    if !params.no_routing && !params.default_route {
+        routes.extend(&config.routes);
+    }
+
+    if let IpAddr::V4(gateway) = config.gateway {
+        routes.retain(|route| !route.contains(&gateway));
+    }
+
+    let routes = routes
+        .iter()
+        .map(|route| vec![ipv4_to_u32(route.network()), u32::from(route.prefix_len()), 0, 0])
+        .collect::<Vec<_>>();
+
+    result.insert("routes", routes.into());
+    result.insert("never-default", (params.no_routing || !params.default_route).into());
+
+    if !params.no_dns {
+        let servers = config
+            .dns
+            .servers
+            .iter()
+            .filter_map(|server| match server {
+                IpAddr::V4(server) => Some(ipv4_to_u32(*server)),
+                IpAddr::V6(_) => None,
+            })
+            .collect::<Vec<_>>();
+
+        // the routing-only domains use the same notation as the dns-search option of NetworkManager
+        let domains = config
+            .dns
+            .suffixes
+            .iter()
+            .map(|suffix| {
+                if suffix.routing_only {
+                    format!("~{}", suffix.name)
+                } else {
+                    suffix.name.clone()
+                }
+            })
+            .collect::<Vec<_>>();
+
+        result.insert("dns", servers.into());
+        result.insert("domains", domains.into());
+    }
+
+    result
+}
+
+/// D-Bus side of the plugin, the commands are passed to the runner which owns the tunnel
+pub struct VpnPlugin {
+    state: Arc<AtomicU32>,
+    command_sender: mpsc::Sender<PluginCommand>,
+}
+
+impl VpnPlugin {
+    pub fn new(command_sender: mpsc::Sender<PluginCommand>) -> Self {
+        Self {
+            state: Arc::new(AtomicU32::new(ServiceState::Init as u32)),
+            command_sender,
+        }
+    }
+
+    pub fn state_handle(&self) -> Arc<AtomicU32> {
+        self.state.clone()
+    }
+
+    async fn send_command(&self, command: PluginCommand) -> fdo::Result<()> {
+        self.command_sender
+            .send(command)
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+
+    async fn start(&self, connection: ConnectionSettings, interactive: bool) -> fdo::Result<()> {
+        let settings = VpnSettings::from_connection(&connection).map_err(|e| fdo::Error::InvalidArgs(e.to_string()))?;
+        let params = settings.to_params();
+
+        if params.server_name.is_empty() || params.login_type.is_empty() {
+            return Err(fdo::Error::InvalidArgs(
+                "Missing required parameters: server name and/or login type".to_owned(),
+            ));
+        }
+
+        let state = self.state.load(Ordering::SeqCst);
+        if state != ServiceState::Init as u32 && state != ServiceState::Stopped as u32 {
+            return Err(fdo::Error::Failed("Tunnel is already connected!".to_owned()));
+        }
+
+        self.send_command(PluginCommand::Connect { params, interactive }).await
+    }
+}
+
+#[zbus::interface(name = "org.freedesktop.NetworkManager.VPN.Plugin")]
+impl VpnPlugin {
+    async fn connect(&self, connection: ConnectionSettings) -> fdo::Result<()> {
+        debug!("Handling connect request");
+        self.start(connection, false).await
+    }
+
+    async fn connect_interactive(
+        &self,
+        connection: ConnectionSettings,
+        _details: HashMap<String, OwnedValue>,
+    ) -> fdo::Result<()> {
+        debug!("Handling interactive connect request");
+        self.start(connection, true).await
+    }
+
+    // the password is handled like the rest of the challenges, it is requested during the login if not stored
+    async fn need_secrets(&self, _settings: ConnectionSettings) -> fdo::Result<String> {
+        Ok(String::new())
+    }
+
+    async fn new_secrets(&self, connection: ConnectionSettings) -> fdo::Result<()> {
+        debug!("Handling new secrets");
+        let settings = VpnSettings::from_connection(&connection).map_err(|e| fdo::Error::InvalidArgs(e.to_string()))?;
+        self.send_command(PluginCommand::NewSecrets(settings.secrets)).await
+    }
+
+    async fn disconnect(&self) -> fdo::Result<()> {
+        debug!("Handling disconnect request");
+        self.send_command(PluginCommand::Disconnect).await
+    }
+
+    // the following methods are used by the external helpers to pass the configuration,
+    // the plugin reports it by itself
+    async fn set_config(&self, _config: HashMap<String, OwnedValue>) -> fdo::Result<()> {
+        Ok(())
+    }
+
+    async fn set_ip4_config(&self, _config: HashMap<String, OwnedValue>) -> fdo::Result<()> {
+        Ok(())
+    }
+
+    async fn set_ip6_config(&self, _config: HashMap<String, OwnedValue>) -> fdo::Result<()> {
+        Ok(())
+    }
+
+    async fn set_failure(&self, _reason: String) -> fdo::Result<()> {
+        Ok(())
+    }
+
+    #[zbus(property)]
+    async fn state(&self) -> u32 {
+        self.state.load(Ordering::SeqCst)
+    }
+
+    #[zbus(signal)]
+    async fn state_changed(ctxt: &SignalContext<'_>, state: u32) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn secrets_required(ctxt: &SignalContext<'_>, message: &str, secrets: &[&str]) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn config(ctxt: &SignalContext<'_>, config: HashMap<&str, Value<'_>>) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn ip4_config(ctxt: &SignalContext<'_>, ip4config: HashMap<&str, Value<'_>>) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn failure(ctxt: &SignalContext<'_>, reason: u32) -> zbus::Result<()>;
+}
+
+/// Drives the tunnel connectors and reports the state and the configuration to NetworkManager
+pub struct PluginRunner {
+    ctxt: SignalContext<'static>,
+    state: Arc<AtomicU32>,
+    command_receiver: mpsc::Receiver<PluginCommand>,
+}
+
+impl PluginRunner {
+    pub fn new(
+        ctxt: SignalContext<'static>,
+        state: Arc<AtomicU32>,
+        command_receiver: mpsc::Receiver<PluginCommand>,
+    ) -> Self {
+        Self {
+            ctxt,
+            state,
+            command_receiver,
+        }
+    }
+
+    pub async fn run(mut self, persist: bool) -> anyhow::Result<()> {
+        loop {
+            let command = if persist {
+                self.command_receiver.recv().await
+            } else {
+                match tokio::time::timeout(IDLE_TIMEOUT, self.command_receiver.recv()).await {
+                    Ok(command) => command,
+                    Err(_) => {
+                        debug!("No connection requests, exiting");
+                        break;
+                    }
+                }
+            };
+
+            match command {
+                Some(PluginCommand::Connect { params, interactive }) => {
+                    self.connect(params, interactive).await;
+                    self.set_state(ServiceState::Stopped).await;
+                }
+                Some(PluginCommand::Disconnect) => self.set_state(ServiceState::Stopped).await,
+                Some(PluginCommand::NewSecrets(_)) => debug!("No pending secrets request, ignoring"),
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn set_state(&self, state: ServiceState) {
+        debug!("Plugin state: {:?}", state);
+        self.state.store(state as u32, Ordering::SeqCst);
+        let _ = VpnPlugin::state_changed(&self.ctxt, state as u32).await;
+    }
+
+    async fn fail(&self, failure: PluginFailure, error: anyhow::Error) {
+        warn!("{}", error);
+        let _ = VpnPlugin::failure(&self.ctxt, failure as u32).await;
+    }
+
+    async fn connect(&mut self, params: TunnelParams, interactive: bool) {
+        self.set_state(ServiceState::Starting).await;
+
+        let mut connector = match tunnel::new_tunnel_connector(Arc::new(connector_params(&params))).await {
+            Ok(connector) => connector,
+            Err(e) => return self.fail(PluginFailure::ConnectFailed, e).await,
+        };
+
+        let session = match self.authenticate(connector.as_mut(), &params, interactive).await {
+            Ok(session) => session,
+            Err(e) => return self.fail(PluginFailure::LoginFailed, e).await,
+        };
+
+        if let Err(e) = self.run_tunnel(connector.as_mut(), session, &params).await {
+            self.fail(PluginFailure::ConnectFailed, e).await;
+        }
+    }
+
+    async fn authenticate(
+        &mut self,
+        connector: &mut (dyn TunnelConnector + Send),
+        params: &TunnelParams,
+        interactive: bool,
+    ) -> anyhow::Result<Arc<VpnSession>> {
+        let mut mfa_prompts = if params.server_prompt {
+            server_info::get_mfa_prompts(params).await.unwrap_or_default()
+        } else {
+            VecDeque::default()
+        };
+
+        let mut first_password = !params.password.is_empty();
+        let mut session = connector.authenticate().await?;
+
+        while let SessionState::PendingChallenge(challenge) = session.state.clone() {
+            let input = match challenge.mfa_type {
+                MfaType::PasswordInput if first_password => {
+                    first_password = false;
+                    params.password.clone()
+                }
+                MfaType::PasswordInput if interactive => {
+                    let prompt = mfa_prompts.pop_front().unwrap_or_else(|| challenge.prompt.clone());
+                    self.request_secret(&prompt).await?
+                }
+                MfaType::UserNameInput if interactive => self.request_secret(&challenge.prompt).await?,
+                MfaType::SamlSso => return Err(anyhow!("SAML authentication is not supported by the plugin")),
+                _ => {
+                    return Err(anyhow!(
+                        "User input is required, please activate the connection interactively"
+                    ))
+                }
+            };
+            session = connector.challenge_code(session, &input).await?;
+        }
+
+        Ok(session)
+    }
+
+    async fn request_secret(&mut self, prompt: &str) -> anyhow::Result<String> {
+        VpnPlugin::secrets_required(&self.ctxt, prompt, &[CHALLENGE_SECRET]).await?;
+
+        loop {
+            match tokio::time::timeout(SECRETS_TIMEOUT, self.command_receiver.recv()).await? {
+                Some(PluginCommand::NewSecrets(mut secrets)) => {
+                    if let Some(input) = secrets.remove(CHALLENGE_SECRET) {
+                        return Ok(input);
+                    }
+                }
+                Some(PluginCommand::Connect { .. }) => warn!("Connection is in progress, ignoring connect request"),
+                Some(PluginCommand::Disconnect) | None => return Err(anyhow!("Connection cancelled")),
+            }
+        }
+    }
+
+    async fn run_tunnel(
+        &mut self,
+        connector: &mut (dyn TunnelConnector + Send),
+        session: Arc<VpnSession>,
+        params: &TunnelParams,
+    ) -> anyhow::Result<()> {
+        let (command_sender, command_receiver) = mpsc::channel(16);
+        let tunnel = connector.create_tunnel(session, command_sender).await?;
+
+        let (event_sender, mut event_receiver) = mpsc::channel(16);
+        let tunnel_fut = tunnel.run(command_receiver, event_sender);
+        pin_mut!(tunnel_fut);
+
+        let result = loop {
+            tokio::select! {
+                event = event_receiver.recv() => {
+                    if let Some(event) = event {
+                        match event {
+                            TunnelEvent::Configured(ref config) => {
+                                debug!("Tunnel configuration: {:?}", config);
+                                VpnPlugin::config(&self.ctxt, vpn_config(config)).await?;
+                                VpnPlugin::ip4_config(&self.ctxt, ip4_config(config, params)).await?;
+                            }
+                            TunnelEvent::Connected => {
+                                info!("Tunnel connected");
+                                self.set_state(ServiceState::Started).await;
+                            }
+                            _ => {}
+                        }
+                        let _ = connector.handle_tunnel_event(event).await;
+                    }
+                }
+                command = self.command_receiver.recv() => {
+                    match command {
+                        Some(PluginCommand::Disconnect) | None => {
+                            self.set_state(ServiceState::Stopping).await;
+                            let _ = connector.terminate_tunnel().await;
+                        }
+                        Some(PluginCommand::Connect { .. }) => {
+                            warn!("Tunnel is already connected, ignoring connect request");
+                        }
+                        Some(PluginCommand::NewSecrets(_)) => {}
+                    }
+                }
+                result = &mut tunnel_fut => {
+                    break result;
+                }
+            }
+        };
+
+        // the connector must see the disconnect event to clean up the session
+        while let Ok(event) = event_receiver.try_recv() {
+            let _ = connector.handle_tunnel_event(event).await;
+        }
+
+        info!("Tunnel disconnected");
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use snxcore::platform::{DnsConfig, DnsSuffix};
+
+    use super::*;
+
+    fn tunnel_config() -> TunnelConfig {
+        TunnelConfig {
+            device: "snx-xfrm".to_owned(),
+            address: "10.0.0.10/24".parse().unwrap(),
+            gateway: Ipv4Addr::new(192, 168, 1, 1).into(),
+            routes: vec!["10.0.0.0/8".parse().unwrap(), "192.168.0.0/16".parse().unwrap()],
+            dns: DnsConfig {
+                servers: vec![Ipv4Addr::new(10, 0, 0, 1).into()],
+                suffixes: vec![
+                    DnsSuffix {
+                        name: "corp.example".to_owned(),
+                        routing_only: false,
+                    },
+                    DnsSuffix {
+                        name: "lab.example".to_owned(),
+                        routing_only: true,
+                    },
+                ],
+                ..Default::default()
+            },
+            mtu: 1350,
+        }
+    }
+
+    #[test]
+    fn test_settings_to_params() {
+        let settings = VpnSettings {
+            data: HashMap::from([
+                ("server-name".to_owned(), "vpn.example.com".to_owned()),
+                ("login-type".to_owned(), "vpn_Username_Password".to_owned()),
+                ("search-domains".to_owned(), "corp.example, lab.example".to_owned()),
+                ("no-dns".to_owned(), " ".to_owned()),
+            ]),
+            secrets: HashMap::from([(PASSWORD_SECRET.to_owned(), "secret".to_owned())]),
+        };
+
+        let params = settings.to_params();
+        assert_eq!(params.server_name, "vpn.example.com");
+        assert_eq!(params.login_type, "vpn_Username_Password");
+        assert_eq!(params.search_domains, ["corp.example", "lab.example"]);
+        assert_eq!(params.password, "secret");
+        assert!(!params.no_dns);
+        assert!(params.no_keychain);
+    }
+
+    #[test]
+    fn test_ip4_config() {
+        let config = tunnel_config();
+        let ip4 = ip4_config(&config, &TunnelParams::default());
+
+        assert_eq!(ip4["address"], Value::from(ipv4_to_u32(Ipv4Addr::new(10, 0, 0, 10))));
+        assert_eq!(ip4["prefix"], Value::from(24u32));
+        // the route to the gateway address is dropped
+        assert_eq!(
+            ip4["routes"],
+            Value::from(vec![vec![ipv4_to_u32(Ipv4Addr::new(10, 0, 0, 0)), 8u32, 0, 0]])
+        );
+        assert_eq!(ip4["never-default"], Value::from(true));
+        assert_eq!(ip4["dns"], Value::from(vec![ipv4_to_u32(Ipv4Addr::new(10, 0, 0, 1))]));
+        assert_eq!(
+            ip4["domains"],
+            Value::from(vec!["corp.example".to_owned(), "~lab.example".to_owned()])
+        );
+    }
+
+    #[test]
+    fn test_ip4_config_options() {
+        let config = tunnel_config();
+        let params = TunnelParams {
+            default_route: true,
+            add_routes: vec!["172.16.0.0/12".parse().unwrap()],
+            no_dns: true,
+            ..Default::default()
+        };
+        let ip4 = ip4_config(&config, &params);
+
+        assert_eq!(
+            ip4["routes"],
+            Value::from(vec![vec![ipv4_to_u32(Ipv4Addr::new(172, 16, 0, 0)), 12u32, 0, 0]])
+        );
+        assert_eq!(ip4["never-default"], Value::from(false));
+        assert!(!ip4.contains_key("dns"));
+        assert!(!ip4.contains_key("domains"));
+    }
+}
@@ -1,10 +1,16 @@
-use std::{path::PathBuf, time::Duration};
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
 
 use clap::Parser;
 use ipnet::Ipv4Net;
 use tracing::level_filters::LevelFilter;
 
-use snxcore::model::params::{CertType, OperationMode, TunnelParams, TunnelType};
+use snxcore::model::params::{
+    CertType, DnsBackend, IpsecDatapath, IpsecTransportType, OperationMode, TunnelParams, TunnelType,
+};
 
 #[derive(Parser)]
 #[clap(about = "VPN client for Checkpoint security gateway", name = "snx-rs")]
@@ -46,6 +52,41 @@ pub struct CmdlineParams {
     )]
     pub ignore_search_domains: Vec<String>,
 
+    #[clap(
+        long = "routing-domains",
+        short = 'Y',
+        help = "Domains resolved via the tunnel DNS servers without adding them to the search list"
+    )]
+    pub routing_domains: Vec<String>,
+
+    #[clap(
+        long = "dnssec",
+        short = 'Z',
+        help = "DNSSEC mode of the tunnel link, one of: yes, no, allow-downgrade [default: resolver default]"
+    )]
+    pub dnssec: Option<String>,
+
+    #[clap(
+        long = "dns-over-tls",
+        short = 'O',
+        help = "DNS over TLS mode of the tunnel link, one of: yes, no, opportunistic [default: resolver default]"
+    )]
+    pub dns_over_tls: Option<String>,
+
+    #[clap(
+        long = "dns-backend",
+        short = 'B',
+        help = "DNS backend, one of: auto, resolved, network-manager, dnsmasq, resolvconf, stub [default: auto]"
+    )]
+    pub dns_backend: Option<DnsBackend>,
+
+    #[clap(
+        long = "dns-stub-address",
+        short = 'V',
+        help = "Listening address of the split DNS stub resolver [default: 127.0.0.153:53]"
+    )]
+    pub dns_stub_address: Option<SocketAddr>,
+
     #[clap(
         long = "default-route",
         short = 't',
@@ -159,6 +200,76 @@ pub struct CmdlineParams {
         help = "Custom client mode [default: secure_connect]"
     )]
     pub client_mode: Option<String>,
+
+    #[clap(
+        long = "tcpt-port",
+        short = 'T',
+        help = "SSL tunnel TCP port [default: advertised by the server]"
+    )]
+    pub tcpt_port: Option<u16>,
+
+    #[clap(
+        long = "natt-port",
+        short = 'M',
+        help = "IPSec NAT-T port [default: advertised by the server]"
+    )]
+    pub natt_port: Option<u16>,
+
+    #[clap(
+        long = "server-ip",
+        short = 'G',
+        help = "Tunnel data-plane IPv4 or IPv6 address of the server [default: advertised by the server]"
+    )]
+    pub server_ip: Option<IpAddr>,
+
+    #[clap(
+        long = "mtu",
+        short = 'Q',
+        help = "Tunnel interface MTU [default: calculated from the outgoing interface MTU]"
+    )]
+    pub mtu: Option<u16>,
+
+    #[clap(
+        long = "mss-clamp",
+        short = 'J',
+        help = "Clamp MSS of TCP SYN packets entering the tunnel"
+    )]
+    pub mss_clamp: Option<bool>,
+
+    #[clap(
+        long = "ipsec-datapath",
+        short = 'D',
+        help = "IPSec datapath, one of: kernel, userspace [default: kernel]"
+    )]
+    pub ipsec_datapath: Option<IpsecDatapath>,
+
+    #[clap(
+        long = "ipsec-transport",
+        short = 'W',
+        help = "IPSec transport, one of: auto, udp, tcpt [default: auto]"
+    )]
+    pub ipsec_transport: Option<IpsecTransportType>,
+
+    #[clap(
+        long = "esp-transforms",
+        short = 'F',
+        help = "Allowed ESP algorithms, e.g.: aes-gcm-256, aes-cbc, sha256 [default: all supported]"
+    )]
+    pub esp_transforms: Vec<String>,
+
+    #[clap(
+        long = "proxy-url",
+        short = 'U',
+        help = "Proxy for HTTPS requests and SSL tunnel: http://host:port or socks5://host:port"
+    )]
+    pub proxy_url: Option<String>,
+
+    #[clap(
+        long = "proxy-auth",
+        short = 'A',
+        help = "Proxy credentials in the form of user:password"
+    )]
+    pub proxy_auth: Option<String>,
 }
 
 impl CmdlineParams {
@@ -188,6 +299,26 @@ impl CmdlineParams {
             other.ignore_search_domains = self.ignore_search_domains;
         }
 
+        if !self.routing_domains.is_empty() {
+            other.routing_domains = self.routing_domains;
+        }
+
+        if let Some(dnssec) = self.dnssec {
+            other.dnssec = Some(dnssec);
+        }
+
+        if let Some(dns_over_tls) = self.dns_over_tls {
+            other.dns_over_tls = Some(dns_over_tls);
+        }
+
+        if let Some(dns_backend) = self.dns_backend {
+            other.dns_backend = dns_backend;
+        }
+
+        if let Some(dns_stub_address) = self.dns_stub_address {
+            other.dns_stub_address = dns_stub_address;
+        }
+
         if let Some(default_route) = self.default_route {
             other.default_route = default_route;
         }
@@ -275,5 +406,45 @@ impl CmdlineParams {
         if let Some(client_mode) = self.client_mode {
             other.client_mode = client_mode;
         }
+
+        if let Some(tcpt_port) = self.tcpt_port {
+            other.tcpt_port = Some(tcpt_port);
+        }
+
+        if let Some(natt_port) = self.natt_port {
+            other.natt_port = Some(natt_port);
+        }
+
+        if let Some(server_ip) = self.server_ip {
+            other.server_ip = Some(server_ip);
+        }
+
+        if let Some(mtu) = self.mtu {
+            other.mtu = Some(mtu);
+        }
+
+        if let Some(mss_clamp) = self.mss_clamp {
+            other.mss_clamp = mss_clamp;
+        }
+
+        if let Some(ipsec_datapath) = self.ipsec_datapath {
+            other.ipsec_datapath = ipsec_datapath;
+        }
+
+        if let Some(ipsec_transport) = self.ipsec_transport {
+            other.ipsec_transport = ipsec_transport;
+        }
+
+        if !self.esp_transforms.is_empty() {
+            other.esp_transforms = self.esp_transforms;
+        }
+
+        if let Some(proxy_url) = self.proxy_url {
+            other.proxy_url = Some(proxy_url);
+        }
+
+        if let Some(proxy_auth) = self.proxy_auth {
+            other.proxy_auth = Some(proxy_auth);
+        }
     }
 }
@@ -0,0 +1,289 @@
+use bytes::{Buf, Bytes, BytesMut};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use futures::{channel::mpsc, SinkExt, StreamExt, TryStreamExt};
+use openssl::{
+    asn1::Asn1Time,
+    bn::BigNum,
+    hash::MessageDigest,
+    pkey::PKey,
+    rsa::Rsa,
+    x509::{X509Builder, X509NameBuilder},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_native_tls::{
+    native_tls::{self, Identity},
+    TlsAcceptor, TlsConnector, TlsStream,
+};
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedRead};
+
+use snxcore::tunnel::ssl::{
+    channel::{self, SslChannel},
+    codec::{SslPacketCodec, SslPacketType},
+};
+
+const PACKET_SIZE: usize = 1400;
+const PACKET_COUNT: usize = 10000;
+const CHANNEL_SIZE: usize = 1024;
+
+struct TlsPair {
+    acceptor: TlsAcceptor,
+    connector: TlsConnector,
+}
+
+impl TlsPair {
+    fn new() -> Self {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = builder.build();
+
+        let identity = Identity::from_pkcs8(&cert.to_pem().unwrap(), &key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+
+        Self {
+            acceptor: native_tls::TlsAcceptor::new(identity).unwrap().into(),
+            connector: native_tls::TlsConnector::builder()
+                .danger_accept_invalid_certs(true)
+                .build()
+                .unwrap()
+                .into(),
+        }
+    }
+
+    async fn loopback(&self) -> (TlsStream<TcpStream>, TlsStream<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        let (client, server) = tokio::join!(
+            self.connector.connect("localhost", client),
+            self.acceptor.accept(server)
+        );
+        (client.unwrap(), server.unwrap())
+    }
+}
+
+async fn receive_packets<S>(stream: S) -> usize
+where
+    S: AsyncRead + Unpin,
+{
+    let mut framed = FramedRead::new(stream, SslPacketCodec);
+    let mut count = 0;
+
+    while count < PACKET_COUNT {
+        match framed.next().await {
+            Some(Ok(SslPacketType::Data(_))) => count += 1,
+            _ => break,
+        }
+    }
+    count
+}
+
+// previous decoder: every data frame is copied out of the read buffer
+struct CopyingCodec;
+
+impl Decoder for CopyingCodec {
+    type Item = Vec<u8>;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> anyhow::Result<Option<Self::Item>> {
+        if src.remaining() < 8 {
+            return Ok(None);
+        }
+
+        let len = u32::from_be_bytes(src[0..4].try_into()?) as usize;
+        if src.remaining() < 8 + len {
+            return Ok(None);
+        }
+
+        let data = src[8..8 + len].to_vec();
+        src.advance(8 + len);
+        Ok(Some(data))
+    }
+}
+
+fn encode_frames(payload: &Bytes) -> Bytes {
+    let mut buf = BytesMut::new();
+    for _ in 0..PACKET_COUNT {
+        SslPacketCodec
+            .encode(SslPacketType::Data(payload.clone()), &mut buf)
+            .unwrap();
+    }
+    buf.freeze()
+}
+
+// the gateway side writes all frames and keeps the connection open until the client is done
+async fn send_frames<S>(mut stream: S, frames: Bytes) -> S
+where
+    S: AsyncWrite + Unpin,
+{
+    stream.write_all(&frames).await.unwrap();
+    stream.flush().await.unwrap();
+    stream
+}
+
+// previous datapath: a framed TLS stream with a single task forwarding both directions via the mpsc channels
+fn framed_channel<S>(stream: S) -> (mpsc::Sender<SslPacketType>, mpsc::Receiver<SslPacketType>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let framed = Framed::new(stream, SslPacketCodec);
+
+    let (tx_in, rx_in) = mpsc::channel(CHANNEL_SIZE);
+    let (tx_out, rx_out) = mpsc::channel(CHANNEL_SIZE);
+
+    tokio::spawn(async move {
+        let (mut sink, stream) = framed.split();
+
+        let mut rx = rx_out.map(Ok::<_, anyhow::Error>);
+        let to_wire = sink.send_all(&mut rx);
+
+        let mut tx = tx_in.sink_map_err(anyhow::Error::from);
+        let from_wire = stream.map_err(Into::into).forward(&mut tx);
+
+        futures::future::select(to_wire, from_wire).await;
+    });
+
+    (tx_out, rx_in)
+}
+
+async fn send_framed(tls: &TlsPair, payload: &[u8]) {
+    let (client, server) = tls.loopback().await;
+    let receiver = tokio::spawn(receive_packets(server));
+
+    let (mut sender, _receiver) = framed_channel(client);
+    for _ in 0..PACKET_COUNT {
+        sender
+            .send(SslPacketType::Data(Bytes::from(payload.to_vec())))
+            .await
+            .unwrap();
+    }
+
+    assert_eq!(receiver.await.unwrap(), PACKET_COUNT);
+}
+
+async fn send_batched(tls: &TlsPair, payload: &Bytes) {
+    let (client, server) = tls.loopback().await;
+    let receiver = tokio::spawn(receive_packets(server));
+
+    let (data_sender, _data_receiver) = channel::data_channel();
+    let channel = SslChannel::new(client, data_sender);
+    for _ in 0..PACKET_COUNT {
+        channel.sender.send(payload.clone().into()).await.unwrap();
+    }
+
+    assert_eq!(receiver.await.unwrap(), PACKET_COUNT);
+}
+
+async fn receive_copied(tls: &TlsPair, frames: &Bytes) {
+    let (client, server) = tls.loopback().await;
+    let sender = tokio::spawn(send_frames(server, frames.clone()));
+
+    let (tx, mut rx) = mpsc::channel(CHANNEL_SIZE);
+    let from_wire = tokio::spawn(async move {
+        let mut tx = tx.sink_map_err(anyhow::Error::from);
+        FramedRead::new(client, CopyingCodec).forward(&mut tx).await
+    });
+
+    let mut count = 0;
+    while count < PACKET_COUNT && rx.next().await.is_some() {
+        count += 1;
+    }
+
+    assert_eq!(count, PACKET_COUNT);
+    from_wire.abort();
+    drop(sender.await.unwrap());
+}
+
+async fn receive_split(tls: &TlsPair, frames: &Bytes) {
+    let (client, server) = tls.loopback().await;
+    let sender = tokio::spawn(send_frames(server, frames.clone()));
+
+    let (data_sender, mut data_receiver) = channel::data_channel();
+    let _channel = SslChannel::new(client, data_sender);
+
+    let mut count = 0;
+    while count < PACKET_COUNT && data_receiver.recv().await.is_some() {
+        count += 1;
+    }
+
+    assert_eq!(count, PACKET_COUNT);
+    drop(sender.await.unwrap());
+}
+
+fn decode_copied(mut buf: BytesMut) -> usize {
+    let mut codec = CopyingCodec;
+    let mut count = 0;
+    while let Some(data) = codec.decode(&mut buf).unwrap() {
+        count += data.len();
+    }
+    count
+}
+
+fn decode_split(mut buf: BytesMut) -> usize {
+    let mut codec = SslPacketCodec;
+    let mut count = 0;
+    while let Some(SslPacketType::Data(data)) = codec.decode(&mut buf).unwrap() {
+        count += data.len();
+    }
+    count
+}
+
+fn ssl_datapath(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let payload = Bytes::from(vec![0x45u8; PACKET_SIZE]);
+    let tls = TlsPair::new();
+
+    let mut group = c.benchmark_group("ssl_uplink");
+    group.throughput(Throughput::Bytes((PACKET_SIZE * PACKET_COUNT) as u64));
+
+    group.bench_function("framed", |b| b.to_async(&runtime).iter(|| send_framed(&tls, &payload)));
+    group.bench_function("batched", |b| {
+        b.to_async(&runtime).iter(|| send_batched(&tls, &payload))
+    });
+
+    group.finish();
+
+    let frames = encode_frames(&payload);
+
+    let mut group = c.benchmark_group("ssl_downlink");
+    group.throughput(Throughput::Bytes((PACKET_SIZE * PACKET_COUNT) as u64));
+
+    group.bench_function("copied", |b| {
+        b.to_async(&runtime).iter(|| receive_copied(&tls, &frames))
+    });
+    group.bench_function("split", |b| b.to_async(&runtime).iter(|| receive_split(&tls, &frames)));
+
+    group.finish();
+
+    let mut group = c.benchmark_group("ssl_decode");
+    group.throughput(Throughput::Bytes((PACKET_SIZE * PACKET_COUNT) as u64));
+
+    group.bench_function("copied", |b| {
+        b.iter_batched(|| BytesMut::from(&frames[..]), decode_copied, BatchSize::LargeInput)
+    });
+    group.bench_function("split", |b| {
+        b.iter_batched(|| BytesMut::from(&frames[..]), decode_split, BatchSize::LargeInput)
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, ssl_datapath);
+criterion_main!(benches);
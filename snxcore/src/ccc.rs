@@ -12,10 +12,11 @@ use tracing::{trace, warn};
 
 use crate::{
     model::{
-        params::{CertType, TunnelParams},
+        params::{CertType, TunnelParams, TunnelType},
         proto::*,
         VpnSession,
     },
+    pkcs11, proxy,
     sexpr::SExpression,
 };
 
@@ -156,7 +157,15 @@ impl CccHttpClient {
             builder = builder.danger_accept_invalid_certs(true);
         }
 
-        let path = if let Some(ref client_cert) = self.params.cert_path {
+        if let Some(proxy) = proxy::new_reqwest_proxy(&self.params)? {
+            builder = builder.proxy(proxy);
+        }
+
+        // for IPSec tunnels the token is used by the IKE exchange
+        let path = if self.params.cert_type == CertType::Pkcs11 && self.params.tunnel_type == TunnelType::Ssl {
+            builder = builder.use_preconfigured_tls(pkcs11::new_client_config(&self.params).await?);
+            "/clients/cert/"
+        } else if let Some(ref client_cert) = self.params.cert_path {
             let data = std::fs::read(client_cert)?;
             let identity = match self.params.cert_type {
                 CertType::Pkcs8 => Some(Identity::from_pkcs8_pem(&data, &data)?),
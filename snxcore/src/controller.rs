@@ -91,7 +91,8 @@ where
         let response = self.send_receive(TunnelServiceRequest::GetStatus, RECV_TIMEOUT).await?;
         match response {
             TunnelServiceResponse::ConnectionStatus(status) => {
-                if let (None, Some(mfa)) = (status.connected_since, &status.mfa) {
+                // the challenge is also raised by the running tunnel when the session is re-authenticated
+                if let Some(ref mfa) = status.mfa {
                     match self.get_mfa_input(mfa).await {
                         Ok(input) => {
                             let result = self.do_challenge_code(input.clone()).await;
@@ -0,0 +1,264 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    task::{JoinHandle, JoinSet},
+};
+use tracing::{debug, info, trace, warn};
+
+use crate::{happy_eyeballs, platform::DnsConfig};
+
+const DNS_PORT: u16 = 53;
+const DNS_HEADER_LEN: usize = 12;
+const MAX_UDP_MESSAGE_SIZE: usize = 65535;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Question of the DNS query: lowercase name without the trailing dot and the record type
+#[derive(Debug, Clone, PartialEq)]
+pub struct DnsQuestion {
+    pub name: String,
+    pub qtype: u16,
+}
+
+impl DnsQuestion {
+    // only the first question is used, compression pointers are not expected in queries
+    pub fn parse(message: &[u8]) -> Option<Self> {
+        let mut labels = Vec::new();
+        let mut offset = DNS_HEADER_LEN;
+
+        loop {
+            let len = *message.get(offset)? as usize;
+            offset += 1;
+            if len == 0 {
+                break;
+            }
+            if len > 63 {
+                return None;
+            }
+            let label = message.get(offset..offset + len)?;
+            labels.push(String::from_utf8_lossy(label).to_lowercase());
+            offset += len;
+        }
+
+        let qtype = u16::from_be_bytes(message.get(offset..offset + 2)?.try_into().ok()?);
+
+        Some(Self {
+            name: labels.join("."),
+            qtype,
+        })
+    }
+}
+
+/// Split DNS routing: names under the VPN suffixes are resolved by the tunnel servers, the rest by the upstream ones
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SplitDnsRoutes {
+    pub suffixes: Vec<String>,
+    pub tunnel_servers: Vec<IpAddr>,
+    pub upstream_servers: Vec<IpAddr>,
+}
+
+impl SplitDnsRoutes {
+    pub fn new(config: &DnsConfig, upstream_servers: Vec<IpAddr>) -> Self {
+        Self {
+            suffixes: config.suffixes.iter().map(|s| s.name.to_lowercase()).collect(),
+            tunnel_servers: config.servers.clone(),
+            upstream_servers,
+        }
+    }
+
+    pub fn is_tunneled(&self, name: &str) -> bool {
+        self.suffixes.iter().any(|suffix| {
+            name == suffix || (name.ends_with(suffix.as_str()) && name[..name.len() - suffix.len()].ends_with('.'))
+        })
+    }
+
+    fn servers_for(&self, message: &[u8]) -> anyhow::Result<&[IpAddr]> {
+        let question = DnsQuestion::parse(message).ok_or_else(|| anyhow!("Malformed DNS query"))?;
+
+        if self.is_tunneled(&question.name) {
+            info!("DNS query via tunnel: {} type {}", question.name, question.qtype);
+            Ok(&self.tunnel_servers)
+        } else {
+            trace!("DNS query via upstream: {} type {}", question.name, question.qtype);
+            Ok(&self.upstream_servers)
+        }
+    }
+}
+
+fn same_id(query: &[u8], reply: &[u8]) -> bool {
+    reply.len() >= DNS_HEADER_LEN && query.get(..2) == reply.get(..2)
+}
+
+async fn forward_udp(servers: &[IpAddr], query: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut buf = vec![0u8; MAX_UDP_MESSAGE_SIZE];
+
+    for &server in servers {
+        let result = async {
+            let socket = happy_eyeballs::bind_udp(server).await?;
+            socket.connect((server, DNS_PORT)).await?;
+            socket.send(query).await?;
+
+            loop {
+                let size = tokio::time::timeout(QUERY_TIMEOUT, socket.recv(&mut buf)).await??;
+                if same_id(query, &buf[..size]) {
+                    break Ok::<_, anyhow::Error>(buf[..size].to_vec());
+                }
+            }
+        }
+        .await;
+
+        match result {
+            Ok(reply) => return Ok(reply),
+            Err(e) => debug!("DNS server {} failed: {}", server, e),
+        }
+    }
+
+    Err(anyhow!("No DNS server responded"))
+}
+
+async fn read_tcp_message<S: AsyncRead + Unpin>(stream: &mut S) -> anyhow::Result<Vec<u8>> {
+    let len = stream.read_u16().await? as usize;
+    let mut message = vec![0u8; len];
+    stream.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+async fn write_tcp_message<S: AsyncWrite + Unpin>(stream: &mut S, message: &[u8]) -> anyhow::Result<()> {
+    stream.write_u16(message.len() as u16).await?;
+    stream.write_all(message).await?;
+    Ok(())
+}
+
+async fn forward_tcp(servers: &[IpAddr], query: &[u8]) -> anyhow::Result<Vec<u8>> {
+    for &server in servers {
+        let result = tokio::time::timeout(QUERY_TIMEOUT, async {
+            let mut stream = TcpStream::connect((server, DNS_PORT)).await?;
+            write_tcp_message(&mut stream, query).await?;
+            read_tcp_message(&mut stream).await
+        })
+        .await;
+
+        match result {
+            Ok(Ok(reply)) => return Ok(reply),
+            Ok(Err(e)) => debug!("DNS server {} failed: {}", server, e),
+            Err(e) => debug!("DNS server {} failed: {}", server, e),
+        }
+    }
+
+    Err(anyhow!("No DNS server responded"))
+}
+
+async fn serve_udp(socket: Arc<UdpSocket>, routes: Arc<SplitDnsRoutes>) -> anyhow::Result<()> {
+    let mut buf = vec![0u8; MAX_UDP_MESSAGE_SIZE];
+
+    loop {
+        let (size, client) = socket.recv_from(&mut buf).await?;
+        let query = buf[..size].to_vec();
+        let socket = socket.clone();
+        let routes = routes.clone();
+
+        tokio::spawn(async move {
+            let result = async {
+                let reply = forward_udp(routes.servers_for(&query)?, &query).await?;
+                socket.send_to(&reply, client).await?;
+                Ok::<_, anyhow::Error>(())
+            }
+            .await;
+
+            if let Err(e) = result {
+                debug!("DNS query from {} failed: {}", client, e);
+            }
+        });
+    }
+}
+
+async fn serve_tcp_client(mut stream: TcpStream, routes: Arc<SplitDnsRoutes>) -> anyhow::Result<()> {
+    loop {
+        let query = read_tcp_message(&mut stream).await?;
+        let reply = forward_tcp(routes.servers_for(&query)?, &query).await?;
+        write_tcp_message(&mut stream, &reply).await?;
+    }
+}
+
+async fn serve_tcp(listener: TcpListener, routes: Arc<SplitDnsRoutes>) -> anyhow::Result<()> {
+    // client connections are owned by the set and aborted together with the stub task
+    let mut clients = JoinSet::new();
+
+    loop {
+        tokio::select! {
+            result = listener.accept() => {
+                let (stream, _) = result?;
+                clients.spawn(serve_tcp_client(stream, routes.clone()));
+            }
+            Some(_) = clients.join_next() => {}
+        }
+    }
+}
+
+/// Start the forwarder on the given address, the sockets are bound before returning
+pub async fn start(address: SocketAddr, routes: SplitDnsRoutes) -> anyhow::Result<JoinHandle<()>> {
+    let socket = Arc::new(UdpSocket::bind(address).await?);
+    let listener = TcpListener::bind(address).await?;
+    let routes = Arc::new(routes);
+
+    debug!("Starting DNS stub on {}: {:?}", address, routes);
+
+    Ok(tokio::spawn(async move {
+        let result = tokio::select! {
+            result = serve_udp(socket, routes.clone()) => result,
+            result = serve_tcp(listener, routes) => result,
+        };
+
+        if let Err(e) = result {
+            warn!("DNS stub stopped: {}", e);
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    // query for www.Corp.Example type A
+    const QUERY: &[u8] = &[
+        0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 3, b'w', b'w', b'w', 4, b'C', b'o',
+        b'r', b'p', 7, b'E', b'x', b'a', b'm', b'p', b'l', b'e', 0, 0x00, 0x01, 0x00, 0x01,
+    ];
+
+    #[test]
+    fn test_parse_question() {
+        assert_eq!(
+            DnsQuestion::parse(QUERY),
+            Some(DnsQuestion {
+                name: "www.corp.example".to_owned(),
+                qtype: 1
+            })
+        );
+        assert_eq!(DnsQuestion::parse(&QUERY[..20]), None);
+    }
+
+    #[test]
+    fn test_split_routes() {
+        let routes = SplitDnsRoutes {
+            suffixes: vec!["corp.example".to_owned()],
+            tunnel_servers: vec![Ipv4Addr::new(10, 0, 0, 1).into()],
+            upstream_servers: vec![Ipv4Addr::new(192, 168, 1, 1).into()],
+        };
+
+        assert!(routes.is_tunneled("corp.example"));
+        assert!(routes.is_tunneled("www.corp.example"));
+        assert!(!routes.is_tunneled("notcorp.example"));
+        assert!(!routes.is_tunneled("example"));
+
+        assert_eq!(routes.servers_for(QUERY).unwrap(), &routes.tunnel_servers[..]);
+        assert!(routes.servers_for(&QUERY[..10]).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use futures::{stream::FuturesUnordered, StreamExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tracing::debug;
+
+// delay between the connection attempts recommended by RFC 8305
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Unspecified address of the same family, used for binding the sockets
+pub fn unspecified(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    }
+}
+
+/// Bind UDP socket to the unspecified address of the same family as the given one
+pub async fn bind_udp(address: IpAddr) -> anyhow::Result<UdpSocket> {
+    Ok(UdpSocket::bind((unspecified(address), 0)).await?)
+}
+
+// address families are interleaved, starting with the preferred one
+fn interleave(addresses: Vec<SocketAddr>, prefer_ipv6: bool) -> Vec<SocketAddr> {
+    let (preferred, other): (Vec<_>, Vec<_>) = addresses.into_iter().partition(|a| a.is_ipv6() == prefer_ipv6);
+
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    let mut result = Vec::new();
+
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (first, second) => result.extend(first.into_iter().chain(second)),
+        }
+    }
+    result
+}
+
+async fn resolve(host: &str, port: u16, prefer_ipv6: bool) -> anyhow::Result<Vec<SocketAddr>> {
+    let addresses = tokio::net::lookup_host((host, port)).await?.collect::<Vec<_>>();
+    if addresses.is_empty() {
+        return Err(anyhow!("Cannot resolve {}", host));
+    }
+    Ok(interleave(addresses, prefer_ipv6))
+}
+
+/// Resolve the host into the list of addresses in the order of the connection attempts
+pub async fn resolve_tcp(host: &str, port: u16) -> anyhow::Result<Vec<SocketAddr>> {
+    resolve(host, port, true).await
+}
+
+/// Select the address for the UDP transport: the first one with a route to it.
+/// IPv4 is preferred, as NAT discovery of the IKE exchange does not support IPv6 addresses.
+pub async fn resolve_udp(host: &str, port: u16) -> anyhow::Result<SocketAddr> {
+    for address in resolve(host, port, false).await? {
+        // connecting UDP socket does not send anything, it fails when there is no route
+        match bind_udp(address.ip()).await {
+            Ok(socket) if socket.connect(address).await.is_ok() => return Ok(address),
+            _ => debug!("No route to {}", address),
+        }
+    }
+    Err(anyhow!("No reachable address for {}", host))
+}
+
+/// Connect to the host trying its addresses concurrently, each next attempt is started
+/// after a short delay or when the previous one fails. The first established connection wins.
+pub async fn connect_tcp(host: &str, port: u16) -> anyhow::Result<TcpStream> {
+    let mut addresses = resolve_tcp(host, port).await?.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = anyhow!("Cannot connect to {}:{}", host, port);
+
+    loop {
+        if let Some(address) = addresses.next() {
+            debug!("Connecting to {}", address);
+            attempts.push(async move { (address, TcpStream::connect(address).await) });
+        } else if attempts.is_empty() {
+            return Err(last_error);
+        }
+
+        let delay = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY);
+        tokio::pin!(delay);
+
+        tokio::select! {
+            Some((address, result)) = attempts.next() => match result {
+                Ok(stream) => {
+                    debug!("Connected to {}", address);
+                    return Ok(stream);
+                }
+                Err(e) => {
+                    debug!("Connection to {} failed: {}", address, e);
+                    last_error = e.into();
+                }
+            },
+            () = &mut delay, if addresses.len() > 0 => {}
+            else => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interleave() {
+        let v4 = |n: u8| SocketAddr::from((Ipv4Addr::new(192, 0, 2, n), 443));
+        let v6 = |n: u16| SocketAddr::from((Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, n), 443));
+
+        let addresses = vec![v4(1), v4(2), v4(3), v6(1)];
+
+        assert_eq!(interleave(addresses.clone(), true), vec![v6(1), v4(1), v4(2), v4(3)]);
+        assert_eq!(interleave(addresses, false), vec![v4(1), v6(1), v4(2), v4(3)]);
+        assert!(interleave(Vec::new(), true).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connect_tcp() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        assert!(connect_tcp("127.0.0.1", port).await.is_ok());
+
+        drop(listener);
+        assert!(connect_tcp("127.0.0.1", port).await.is_err());
+    }
+}
@@ -1,11 +1,17 @@
 pub mod browser;
 pub mod ccc;
 pub mod controller;
+pub mod dns_stub;
+pub mod happy_eyeballs;
 pub mod model;
+pub mod mtu;
+pub mod pkcs11;
 pub mod platform;
 pub mod prompt;
+pub mod proxy;
 pub mod server;
 pub mod server_info;
 pub mod sexpr;
+pub mod transform;
 pub mod tunnel;
 pub mod util;
@@ -1,11 +1,11 @@
 use std::sync::Arc;
-use std::{net::Ipv4Addr, time::Duration};
+use std::{fmt, net::Ipv4Addr, time::Duration};
 
 use chrono::{DateTime, Local};
 use isakmp::model::EspCryptMaterial;
 use serde::{Deserialize, Serialize};
 
-use crate::model::params::TunnelParams;
+use crate::{model::params::TunnelParams, util};
 
 pub mod params;
 pub mod proto;
@@ -92,10 +92,45 @@ pub struct MfaChallenge {
     pub prompt: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, PartialOrd)]
+pub struct TunnelStatistics {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub send_rate: u64,
+    pub receive_rate: u64,
+    pub errors: u64,
+    pub keepalive_rtt: Option<Duration>,
+    pub keepalive_failures: u64,
+}
+
+impl fmt::Display for TunnelStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sent: {} ({} packets, {}/s), received: {} ({} packets, {}/s), errors: {}",
+            util::format_bytes(self.bytes_sent),
+            self.packets_sent,
+            util::format_bytes(self.send_rate),
+            util::format_bytes(self.bytes_received),
+            self.packets_received,
+            util::format_bytes(self.receive_rate),
+            self.errors
+        )?;
+        if let Some(rtt) = self.keepalive_rtt {
+            write!(f, ", keepalive RTT: {} ms", rtt.as_millis())?;
+        }
+        write!(f, ", keepalive failures: {}", self.keepalive_failures)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, PartialOrd)]
 pub struct ConnectionStatus {
     pub connected_since: Option<DateTime<Local>>,
     pub mfa: Option<MfaChallenge>,
+    pub statistics: Option<TunnelStatistics>,
+    pub last_disconnect_reason: Option<String>,
 }
 
 impl ConnectionStatus {
@@ -1,6 +1,7 @@
 use std::{
     fmt,
     io::{Cursor, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
     path::{Path, PathBuf},
     str::FromStr,
     time::Duration,
@@ -16,6 +17,7 @@ use tracing::warn;
 const DEFAULT_ESP_LIFETIME: Duration = Duration::from_secs(3600);
 const DEFAULT_IKE_LIFETIME: Duration = Duration::from_secs(28800);
 const DEFAULT_IKE_PORT: u16 = 500;
+const DEFAULT_DNS_STUB_ADDRESS: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 153), 53));
 
 #[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub enum OperationMode {
@@ -74,6 +76,105 @@ impl FromStr for TunnelType {
     }
 }
 
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum IpsecDatapath {
+    #[default]
+    Kernel,
+    Userspace,
+}
+
+impl IpsecDatapath {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IpsecDatapath::Kernel => "kernel",
+            IpsecDatapath::Userspace => "userspace",
+        }
+    }
+}
+
+impl FromStr for IpsecDatapath {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "kernel" => Ok(IpsecDatapath::Kernel),
+            "userspace" => Ok(IpsecDatapath::Userspace),
+            _ => Err(anyhow!("Invalid IPSec datapath!")),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum IpsecTransportType {
+    #[default]
+    Auto,
+    Udp,
+    Tcpt,
+}
+
+impl IpsecTransportType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IpsecTransportType::Auto => "auto",
+            IpsecTransportType::Udp => "udp",
+            IpsecTransportType::Tcpt => "tcpt",
+        }
+    }
+}
+
+impl FromStr for IpsecTransportType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(IpsecTransportType::Auto),
+            "udp" => Ok(IpsecTransportType::Udp),
+            "tcpt" => Ok(IpsecTransportType::Tcpt),
+            _ => Err(anyhow!("Invalid IPSec transport type!")),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum DnsBackend {
+    #[default]
+    Auto,
+    Resolved,
+    NetworkManager,
+    Dnsmasq,
+    ResolvConf,
+    Stub,
+}
+
+impl DnsBackend {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DnsBackend::Auto => "auto",
+            DnsBackend::Resolved => "resolved",
+            DnsBackend::NetworkManager => "network-manager",
+            DnsBackend::Dnsmasq => "dnsmasq",
+            DnsBackend::ResolvConf => "resolvconf",
+            DnsBackend::Stub => "stub",
+        }
+    }
+}
+
+impl FromStr for DnsBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(DnsBackend::Auto),
+            "resolved" => Ok(DnsBackend::Resolved),
+            "network-manager" => Ok(DnsBackend::NetworkManager),
+            "dnsmasq" => Ok(DnsBackend::Dnsmasq),
+            "resolvconf" => Ok(DnsBackend::ResolvConf),
+            "stub" => Ok(DnsBackend::Stub),
+            _ => Err(anyhow!("Invalid DNS backend!")),
+        }
+    }
+}
+
 #[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
 pub enum CertType {
     #[default]
@@ -139,6 +240,11 @@ pub struct TunnelParams {
     pub log_level: String,
     pub search_domains: Vec<String>,
     pub ignore_search_domains: Vec<String>,
+    pub routing_domains: Vec<String>,
+    pub dnssec: Option<String>,
+    pub dns_over_tls: Option<String>,
+    pub dns_backend: DnsBackend,
+    pub dns_stub_address: SocketAddr,
     pub default_route: bool,
     pub no_routing: bool,
     pub add_routes: Vec<Ipv4Net>,
@@ -155,12 +261,24 @@ pub struct TunnelParams {
     pub cert_password: Option<String>,
     pub cert_id: Option<String>,
     pub if_name: Option<String>,
+    /// Leave the tunnel device managed by NetworkManager, set when it is NetworkManager which runs the tunnel
+    pub keep_device_managed: bool,
     pub no_keychain: bool,
     pub server_prompt: bool,
     pub esp_lifetime: Duration,
     pub ike_lifetime: Duration,
     pub ike_port: u16,
+    pub tcpt_port: Option<u16>,
+    pub natt_port: Option<u16>,
+    pub server_ip: Option<IpAddr>,
+    pub mtu: Option<u16>,
+    pub mss_clamp: bool,
+    pub ipsec_datapath: IpsecDatapath,
+    pub ipsec_transport: IpsecTransportType,
+    pub esp_transforms: Vec<String>,
     pub client_mode: String,
+    pub proxy_url: Option<String>,
+    pub proxy_auth: Option<String>,
     pub config_file: PathBuf,
 }
 
@@ -173,6 +291,11 @@ impl Default for TunnelParams {
             log_level: "off".to_owned(),
             search_domains: Vec::new(),
             ignore_search_domains: Vec::new(),
+            routing_domains: Vec::new(),
+            dnssec: None,
+            dns_over_tls: None,
+            dns_backend: DnsBackend::default(),
+            dns_stub_address: DEFAULT_DNS_STUB_ADDRESS,
             default_route: false,
             no_routing: false,
             add_routes: Vec::new(),
@@ -189,12 +312,23 @@ impl Default for TunnelParams {
             cert_password: None,
             cert_id: None,
             if_name: None,
+            keep_device_managed: false,
             no_keychain: false,
             server_prompt: true,
             esp_lifetime: DEFAULT_ESP_LIFETIME,
             ike_lifetime: DEFAULT_IKE_LIFETIME,
             ike_port: DEFAULT_IKE_PORT,
+            tcpt_port: None,
+            natt_port: None,
+            server_ip: None,
+            mtu: None,
+            mss_clamp: false,
+            ipsec_datapath: IpsecDatapath::default(),
+            ipsec_transport: IpsecTransportType::default(),
+            esp_transforms: Vec::new(),
             client_mode: TunnelType::Ipsec.as_client_mode().to_owned(),
+            proxy_url: None,
+            proxy_auth: None,
             config_file: Self::default_config_path(),
         }
     }
@@ -216,49 +350,7 @@ impl TunnelParams {
                     .and_then(|(k, v)| if v.is_empty() { None } else { Some((k, v)) });
 
                 if let Some((k, v)) = parts {
-                    let v = v.to_owned();
-                    match k {
-                        "server-name" => params.server_name = v,
-                        "user-name" => params.user_name = v,
-                        "password" => params.password = v,
-                        "log-level" => params.log_level = v,
-                        "search-domains" => params.search_domains = v.split(',').map(|s| s.trim().to_owned()).collect(),
-                        "ignore-search-domains" => {
-                            params.ignore_search_domains = v.split(',').map(|s| s.trim().to_owned()).collect();
-                        }
-                        "default-route" => params.default_route = v.parse().unwrap_or_default(),
-                        "no-routing" => params.no_routing = v.parse().unwrap_or_default(),
-                        "add-routes" => params.add_routes = v.split(',').flat_map(|s| s.trim().parse().ok()).collect(),
-                        "ignore-routes" => {
-                            params.ignore_routes = v.split(',').flat_map(|s| s.trim().parse().ok()).collect();
-                        }
-                        "no-dns" => params.no_dns = v.parse().unwrap_or_default(),
-                        "no-cert-check" => params.no_cert_check = v.parse().unwrap_or_default(),
-                        "ipsec-cert-check" => params.ipsec_cert_check = v.parse().unwrap_or_default(),
-                        "ignore-server-cert" => params.ignore_server_cert = v.parse().unwrap_or_default(),
-                        "tunnel-type" => params.tunnel_type = v.parse().unwrap_or_default(),
-                        "ca-cert" => params.ca_cert = v.split(',').map(|s| s.trim().into()).collect(),
-                        "login-type" => params.login_type = v,
-                        "cert-type" => params.cert_type = v.parse().unwrap_or_default(),
-                        "cert-path" => params.cert_path = Some(v.into()),
-                        "cert-password" => params.cert_password = Some(v),
-                        "cert-id" => params.cert_id = Some(v),
-                        "if-name" => params.if_name = Some(v),
-                        "no-keychain" => params.no_keychain = v.parse().unwrap_or_default(),
-                        "server-prompt" => params.server_prompt = v.parse().unwrap_or_default(),
-                        "esp-lifetime" => {
-                            params.esp_lifetime =
-                                v.parse::<u64>().ok().map_or(DEFAULT_ESP_LIFETIME, Duration::from_secs);
-                        }
-                        "ike-lifetime" => {
-                            params.ike_lifetime =
-                                v.parse::<u64>().ok().map_or(DEFAULT_IKE_LIFETIME, Duration::from_secs);
-                        }
-                        "ike-port" => params.ike_port = v.parse().ok().unwrap_or(DEFAULT_IKE_PORT),
-                        other => {
-                            warn!("Ignoring unknown option: {}", other);
-                        }
-                    }
+                    params.set_option(k, v.to_owned());
                 }
             }
         }
@@ -268,6 +360,85 @@ impl TunnelParams {
         Ok(params)
     }
 
+    /// Apply a single option in the configuration file format, the unknown options are ignored
+    pub fn set_option(&mut self, key: &str, value: String) {
+        match key {
+            "server-name" => self.server_name = value,
+            "user-name" => self.user_name = value,
+            "password" => self.password = value,
+            "log-level" => self.log_level = value,
+            "search-domains" => self.search_domains = value.split(',').map(|s| s.trim().to_owned()).collect(),
+            "ignore-search-domains" => {
+                self.ignore_search_domains = value.split(',').map(|s| s.trim().to_owned()).collect();
+            }
+            "routing-domains" => {
+                self.routing_domains = value.split(',').map(|s| s.trim().to_owned()).collect();
+            }
+            "dnssec" => self.dnssec = Some(value),
+            "dns-over-tls" => self.dns_over_tls = Some(value),
+            "dns-backend" => self.dns_backend = value.parse().unwrap_or_default(),
+            "dns-stub-address" => {
+                self.dns_stub_address = value.parse().unwrap_or(DEFAULT_DNS_STUB_ADDRESS);
+            }
+            "default-route" => self.default_route = value.parse().unwrap_or_default(),
+            "no-routing" => self.no_routing = value.parse().unwrap_or_default(),
+            "add-routes" => self.add_routes = value.split(',').flat_map(|s| s.trim().parse().ok()).collect(),
+            "ignore-routes" => {
+                self.ignore_routes = value.split(',').flat_map(|s| s.trim().parse().ok()).collect();
+            }
+            "no-dns" => self.no_dns = value.parse().unwrap_or_default(),
+            "no-cert-check" => self.no_cert_check = value.parse().unwrap_or_default(),
+            "ipsec-cert-check" => self.ipsec_cert_check = value.parse().unwrap_or_default(),
+            "ignore-server-cert" => self.ignore_server_cert = value.parse().unwrap_or_default(),
+            "tunnel-type" => self.tunnel_type = value.parse().unwrap_or_default(),
+            "ca-cert" => self.ca_cert = value.split(',').map(|s| s.trim().into()).collect(),
+            "login-type" => self.login_type = value,
+            "cert-type" => self.cert_type = value.parse().unwrap_or_default(),
+            "cert-path" => self.cert_path = Some(value.into()),
+            "cert-password" => self.cert_password = Some(value),
+            "cert-id" => self.cert_id = Some(value),
+            "if-name" => self.if_name = Some(value),
+            "no-keychain" => self.no_keychain = value.parse().unwrap_or_default(),
+            "server-prompt" => self.server_prompt = value.parse().unwrap_or_default(),
+            "esp-lifetime" => {
+                self.esp_lifetime = value
+                    .parse::<u64>()
+                    .ok()
+                    .map_or(DEFAULT_ESP_LIFETIME, Duration::from_secs);
+            }
+            "ike-lifetime" => {
+                self.ike_lifetime = value
+                    .parse::<u64>()
+                    .ok()
+                    .map_or(DEFAULT_IKE_LIFETIME, Duration::from_secs);
+            }
+            "ike-port" => self.ike_port = value.parse().ok().unwrap_or(DEFAULT_IKE_PORT),
+            "tcpt-port" => self.tcpt_port = value.parse().ok(),
+            "natt-port" => self.natt_port = value.parse().ok(),
+            "server-ip" => self.server_ip = value.parse().ok(),
+            "mtu" => self.mtu = value.parse().ok(),
+            "mss-clamp" => self.mss_clamp = value.parse().unwrap_or_default(),
+            "ipsec-datapath" => self.ipsec_datapath = value.parse().unwrap_or_default(),
+            "ipsec-transport" => self.ipsec_transport = value.parse().unwrap_or_default(),
+            "esp-transforms" => {
+                self.esp_transforms = value
+                    .split(',')
+                    .map(|s| s.trim().to_owned())
+                    .filter(|s| !s.is_empty())
+                    .collect();
+            }
+            "proxy-url" => self.proxy_url = Some(value),
+            "proxy-auth" => self.proxy_auth = Some(value),
+            "proxy-auth-b64" => match base64::engine::general_purpose::STANDARD.decode(&value) {
+                Ok(decoded) => self.proxy_auth = Some(String::from_utf8_lossy(&decoded).into_owned()),
+                Err(e) => warn!("Ignoring invalid proxy-auth-b64 option: {}", e),
+            },
+            other => {
+                warn!("Ignoring unknown option: {}", other);
+            }
+        }
+    }
+
     pub fn save(&self) -> anyhow::Result<()> {
         let mut buf = Cursor::new(Vec::new());
         writeln!(buf, "server-name={}", self.server_name)?;
@@ -279,6 +450,15 @@ impl TunnelParams {
         )?;
         writeln!(buf, "search-domains={}", self.search_domains.join(","))?;
         writeln!(buf, "ignore-search-domains={}", self.ignore_search_domains.join(","))?;
+        writeln!(buf, "routing-domains={}", self.routing_domains.join(","))?;
+        if let Some(ref dnssec) = self.dnssec {
+            writeln!(buf, "dnssec={dnssec}")?;
+        }
+        if let Some(ref dns_over_tls) = self.dns_over_tls {
+            writeln!(buf, "dns-over-tls={dns_over_tls}")?;
+        }
+        writeln!(buf, "dns-backend={}", self.dns_backend.as_str())?;
+        writeln!(buf, "dns-stub-address={}", self.dns_stub_address)?;
         writeln!(buf, "default-route={}", self.default_route)?;
         writeln!(buf, "no-routing={}", self.no_routing)?;
         writeln!(
@@ -332,6 +512,32 @@ impl TunnelParams {
         writeln!(buf, "esp-lifetime={}", self.esp_lifetime.as_secs())?;
         writeln!(buf, "ike-lifetime={}", self.ike_lifetime.as_secs())?;
         writeln!(buf, "ike-port={}", self.ike_port)?;
+        if let Some(tcpt_port) = self.tcpt_port {
+            writeln!(buf, "tcpt-port={tcpt_port}")?;
+        }
+        if let Some(natt_port) = self.natt_port {
+            writeln!(buf, "natt-port={natt_port}")?;
+        }
+        if let Some(server_ip) = self.server_ip {
+            writeln!(buf, "server-ip={server_ip}")?;
+        }
+        if let Some(mtu) = self.mtu {
+            writeln!(buf, "mtu={mtu}")?;
+        }
+        writeln!(buf, "mss-clamp={}", self.mss_clamp)?;
+        writeln!(buf, "ipsec-datapath={}", self.ipsec_datapath.as_str())?;
+        writeln!(buf, "ipsec-transport={}", self.ipsec_transport.as_str())?;
+        writeln!(buf, "esp-transforms={}", self.esp_transforms.join(","))?;
+        if let Some(ref proxy_url) = self.proxy_url {
+            writeln!(buf, "proxy-url={proxy_url}")?;
+        }
+        if let Some(ref proxy_auth) = self.proxy_auth {
+            writeln!(
+                buf,
+                "proxy-auth-b64={}",
+                base64::engine::general_purpose::STANDARD.encode(proxy_auth)
+            )?;
+        }
 
         PathBuf::from(&self.config_file).parent().iter().for_each(|dir| {
             let _ = std::fs::create_dir_all(dir);
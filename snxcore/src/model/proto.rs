@@ -379,7 +379,7 @@ pub struct DisconnectRequest {
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct DisconnectRequestData {
-    pub code: String,
+    pub code: u32,
     pub message: Option<QuotedString>,
 }
 
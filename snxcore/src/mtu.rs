@@ -0,0 +1,229 @@
+use std::net::IpAddr;
+
+use isakmp::model::EspCryptMaterial;
+
+use crate::transform::EspTransform;
+
+pub const DEFAULT_LINK_MTU: u16 = 1500;
+pub const MIN_TUNNEL_MTU: u16 = 576;
+
+const IPV4_HEADER_LEN: u16 = 20;
+const IPV6_HEADER_LEN: u16 = 40;
+const UDP_HEADER_LEN: u16 = 8;
+const TCP_HEADER_LEN: u16 = 20;
+const TCP_OPTIONS_LEN: u16 = 12;
+// record header, explicit nonce and AEAD tag
+const TLS_RECORD_OVERHEAD: u16 = 29;
+const SSL_FRAME_HEADER_LEN: u16 = 8;
+const TCPT_FRAME_HEADER_LEN: u16 = 8;
+const ESP_HEADER_LEN: u16 = 8;
+const ESP_TRAILER_LEN: u16 = 2;
+
+const IPPROTO_TCP: u8 = 6;
+const TCP_FLAG_SYN: u8 = 0x02;
+const TCP_OPTION_END: u8 = 0;
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_MSS: u8 = 2;
+
+/// Link MTU for the calculations below, which assume the IPv4 header of the outer packets
+pub fn outer_link_mtu(link_mtu: u16, address: IpAddr) -> u16 {
+    match address {
+        IpAddr::V4(_) => link_mtu,
+        IpAddr::V6(_) => link_mtu.saturating_sub(IPV6_HEADER_LEN - IPV4_HEADER_LEN),
+    }
+}
+
+/// Tunnel MTU for the SSL transport, so that every tunneled packet fits into a single TCP segment
+pub fn ssl_mtu(link_mtu: u16) -> u16 {
+    let overhead = IPV4_HEADER_LEN + TCP_HEADER_LEN + TCP_OPTIONS_LEN + TLS_RECORD_OVERHEAD + SSL_FRAME_HEADER_LEN;
+    link_mtu.saturating_sub(overhead).max(MIN_TUNNEL_MTU)
+}
+
+/// Tunnel MTU for the ESP-in-UDP transport with the given outbound SA parameters
+pub fn esp_mtu(link_mtu: u16, material: &EspCryptMaterial) -> u16 {
+    let (block_size, iv_len, icv_len) = match EspTransform::new(material) {
+        Ok(transform) => (
+            transform.encryption.block_size(),
+            transform.encryption.iv_len(),
+            transform.icv_len,
+        ),
+        Err(_) => (16, 16, material.auth_algorithm.hash_len()),
+    };
+    esp_mtu_with(link_mtu, block_size as u16, iv_len as u16, icv_len as u16)
+}
+
+/// Tunnel MTU for the ESP-in-TCPT transport with the given outbound SA parameters
+pub fn tcpt_mtu(link_mtu: u16, material: &EspCryptMaterial) -> u16 {
+    // TCP header with options and the TCPT frame header are used instead of the UDP header
+    let overhead = TCP_HEADER_LEN + TCP_OPTIONS_LEN + TCPT_FRAME_HEADER_LEN - UDP_HEADER_LEN;
+    esp_mtu(link_mtu.saturating_sub(overhead), material)
+}
+
+fn esp_mtu_with(link_mtu: u16, block_size: u16, iv_len: u16, icv_len: u16) -> u16 {
+    let payload = link_mtu.saturating_sub(IPV4_HEADER_LEN + UDP_HEADER_LEN + ESP_HEADER_LEN + iv_len + icv_len);
+
+    // the encrypted part includes the padding and the trailer and is a multiple of the cipher block size
+    (payload - payload % block_size)
+        .saturating_sub(ESP_TRAILER_LEN)
+        .max(MIN_TUNNEL_MTU)
+}
+
+/// Maximum TCP segment size for the given tunnel MTU
+pub fn mss_for_mtu(mtu: u16) -> u16 {
+    mtu.saturating_sub(IPV4_HEADER_LEN + TCP_HEADER_LEN)
+}
+
+/// Lower the MSS option of the IPv4 TCP SYN packet to the given value.
+/// Returns true if the packet was modified.
+pub fn clamp_tcp_mss(packet: &mut [u8], mss: u16) -> bool {
+    if packet.len() < IPV4_HEADER_LEN as usize || packet[0] >> 4 != 4 || packet[9] != IPPROTO_TCP {
+        return false;
+    }
+
+    // only the first fragment carries the TCP header
+    if u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff != 0 {
+        return false;
+    }
+
+    let ihl = ((packet[0] & 0x0f) as usize) * 4;
+    let total_len = (u16::from_be_bytes([packet[2], packet[3]]) as usize).min(packet.len());
+    if ihl < IPV4_HEADER_LEN as usize || total_len < ihl + TCP_HEADER_LEN as usize {
+        return false;
+    }
+
+    let (header, segment) = packet[..total_len].split_at_mut(ihl);
+    if segment[13] & TCP_FLAG_SYN == 0 {
+        return false;
+    }
+
+    let data_offset = ((segment[12] >> 4) as usize * 4).min(segment.len());
+    let mut offset = TCP_HEADER_LEN as usize;
+    let mut modified = false;
+
+    while offset < data_offset {
+        match segment[offset] {
+            TCP_OPTION_END => break,
+            TCP_OPTION_NOP => offset += 1,
+            kind => {
+                let len = segment.get(offset + 1).copied().unwrap_or_default() as usize;
+                if len < 2 || offset + len > data_offset {
+                    break;
+                }
+                if kind == TCP_OPTION_MSS && len == 4 {
+                    let value = u16::from_be_bytes([segment[offset + 2], segment[offset + 3]]);
+                    if value > mss {
+                        segment[offset + 2..offset + 4].copy_from_slice(&mss.to_be_bytes());
+                        modified = true;
+                    }
+                }
+                offset += len;
+            }
+        }
+    }
+
+    if modified {
+        segment[16..18].fill(0);
+        let checksum = tcp_checksum(&header[12..16], &header[16..20], segment);
+        segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    modified
+}
+
+fn ones_complement_sum(data: &[u8], mut sum: u32) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+fn tcp_checksum(src: &[u8], dst: &[u8], segment: &[u8]) -> u16 {
+    let mut sum = ones_complement_sum(src, 0);
+    sum = ones_complement_sum(dst, sum);
+    sum += IPPROTO_TCP as u32 + segment.len() as u32;
+    sum = ones_complement_sum(segment, sum);
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn syn_packet(mss: u16) -> Vec<u8> {
+        let mut packet = vec![
+            0x45, 0x00, 0x00, 0x2c, 0x12, 0x34, 0x40, 0x00, 0x40, 0x06, 0x00, 0x00, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        packet.extend_from_slice(&[
+            0xd4,
+            0x31,
+            0x00,
+            0x16,
+            0x00,
+            0x00,
+            0x00,
+            0x01,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0x60,
+            TCP_FLAG_SYN,
+            0xfa,
+            0xf0,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            TCP_OPTION_MSS,
+            4,
+        ]);
+        packet.extend_from_slice(&mss.to_be_bytes());
+
+        let checksum = tcp_checksum(&packet[12..16], &packet[16..20], &packet[20..]);
+        packet[36..38].copy_from_slice(&checksum.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_tunnel_mtu() {
+        assert_eq!(ssl_mtu(1500), 1411);
+        assert_eq!(ssl_mtu(100), MIN_TUNNEL_MTU);
+
+        // AES-CBC with HMAC-SHA256-128
+        assert_eq!(esp_mtu_with(1500, 16, 16, 16), 1422);
+        // 3DES with HMAC-SHA1-96
+        assert_eq!(esp_mtu_with(1500, 8, 8, 12), 1438);
+        assert_eq!(esp_mtu_with(1400, 16, 16, 16), 1326);
+        // AES-GCM with 16 bytes ICV
+        assert_eq!(esp_mtu_with(1500, 4, 8, 16), 1438);
+
+        assert_eq!(outer_link_mtu(1500, "203.0.113.1".parse().unwrap()), 1500);
+        assert_eq!(outer_link_mtu(1500, "2001:db8::1".parse().unwrap()), 1480);
+    }
+
+    #[test]
+    fn test_clamp_tcp_mss() {
+        let mut packet = syn_packet(1460);
+        assert!(clamp_tcp_mss(&mut packet, 1382));
+        assert_eq!(&packet[42..44], &1382u16.to_be_bytes());
+        assert_eq!(tcp_checksum(&packet[12..16], &packet[16..20], &packet[20..]), 0);
+        assert_eq!(packet, syn_packet(1382));
+
+        let mut packet = syn_packet(1200);
+        assert!(!clamp_tcp_mss(&mut packet, 1382));
+        assert_eq!(packet, syn_packet(1200));
+
+        let mut packet = syn_packet(1460);
+        packet[33] = 0x10;
+        assert!(!clamp_tcp_mss(&mut packet, 1382));
+    }
+}
@@ -0,0 +1,451 @@
+use std::{
+    fmt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+use cryptoki::{
+    context::{CInitializeArgs, Pkcs11},
+    mechanism::{
+        rsa::{PkcsMgfType, PkcsPssParams},
+        Mechanism, MechanismType,
+    },
+    object::{Attribute, AttributeType, KeyType, ObjectClass, ObjectHandle},
+    session::{Session, UserType},
+    types::AuthPin,
+};
+use once_cell::sync::Lazy;
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        ResolvesClientCert, WebPkiServerVerifier,
+    },
+    crypto::ring,
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    sign::{CertifiedKey, Signer, SigningKey},
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureAlgorithm, SignatureScheme,
+};
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tracing::{debug, warn};
+
+use crate::model::params::TunnelParams;
+
+const DEFAULT_DRIVER_PATH: &str = "opensc-pkcs11.so";
+
+// DER-encoded OIDs of the supported EC named curves
+const OID_SECP256R1: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_SECP384R1: &[u8] = &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x22];
+
+type CachedKey = (String, Arc<CertifiedKey>);
+
+// the token is opened once per process: repeated C_Initialize/C_Finalize calls
+// would invalidate the session which is still used by the established connections
+static CERTIFIED_KEY: Lazy<tokio::sync::Mutex<Option<CachedKey>>> = Lazy::new(|| tokio::sync::Mutex::new(None));
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum KeyKind {
+    Rsa,
+    EcP256,
+    EcP384,
+}
+
+impl KeyKind {
+    fn schemes(self) -> &'static [SignatureScheme] {
+        match self {
+            KeyKind::Rsa => &[
+                SignatureScheme::RSA_PSS_SHA256,
+                SignatureScheme::RSA_PSS_SHA384,
+                SignatureScheme::RSA_PSS_SHA512,
+                SignatureScheme::RSA_PKCS1_SHA256,
+                SignatureScheme::RSA_PKCS1_SHA384,
+                SignatureScheme::RSA_PKCS1_SHA512,
+            ],
+            KeyKind::EcP256 => &[SignatureScheme::ECDSA_NISTP256_SHA256],
+            KeyKind::EcP384 => &[SignatureScheme::ECDSA_NISTP384_SHA384],
+        }
+    }
+}
+
+fn pss_params(hash_alg: MechanismType, mgf: PkcsMgfType, s_len: u64) -> PkcsPssParams {
+    PkcsPssParams {
+        hash_alg,
+        mgf,
+        s_len: s_len.into(),
+    }
+}
+
+fn scheme_to_mechanism(scheme: SignatureScheme) -> Option<Mechanism<'static>> {
+    match scheme {
+        SignatureScheme::RSA_PKCS1_SHA256 => Some(Mechanism::Sha256RsaPkcs),
+        SignatureScheme::RSA_PKCS1_SHA384 => Some(Mechanism::Sha384RsaPkcs),
+        SignatureScheme::RSA_PKCS1_SHA512 => Some(Mechanism::Sha512RsaPkcs),
+        SignatureScheme::RSA_PSS_SHA256 => Some(Mechanism::Sha256RsaPkcsPss(pss_params(
+            MechanismType::SHA256,
+            PkcsMgfType::MGF1_SHA256,
+            32,
+        ))),
+        SignatureScheme::RSA_PSS_SHA384 => Some(Mechanism::Sha384RsaPkcsPss(pss_params(
+            MechanismType::SHA384,
+            PkcsMgfType::MGF1_SHA384,
+            48,
+        ))),
+        SignatureScheme::RSA_PSS_SHA512 => Some(Mechanism::Sha512RsaPkcsPss(pss_params(
+            MechanismType::SHA512,
+            PkcsMgfType::MGF1_SHA512,
+            64,
+        ))),
+        SignatureScheme::ECDSA_NISTP256_SHA256 => Some(Mechanism::EcdsaSha256),
+        SignatureScheme::ECDSA_NISTP384_SHA384 => Some(Mechanism::EcdsaSha384),
+        _ => None,
+    }
+}
+
+// token operations may take seconds (PIN pads, slow smart cards) and must not stall the async worker threads
+fn run_blocking<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => tokio::task::block_in_place(f),
+        _ => f(),
+    }
+}
+
+fn encode_der_integer(value: &[u8]) -> Vec<u8> {
+    let start = value
+        .iter()
+        .position(|&b| b != 0)
+        .unwrap_or(value.len().saturating_sub(1));
+    let value = &value[start..];
+    let pad = value.first().is_some_and(|&b| b & 0x80 != 0);
+
+    let mut result = vec![0x02, (value.len() + pad as usize) as u8];
+    if pad {
+        result.push(0);
+    }
+    result.extend_from_slice(value);
+    result
+}
+
+// PKCS11 returns the raw r || s pair, TLS expects a DER-encoded ECDSA-Sig-Value
+fn encode_ecdsa_signature(raw: &[u8]) -> Vec<u8> {
+    let (r, s) = raw.split_at(raw.len() / 2);
+    let r = encode_der_integer(r);
+    let s = encode_der_integer(s);
+
+    let mut result = vec![0x30, (r.len() + s.len()) as u8];
+    result.extend(r);
+    result.extend(s);
+    result
+}
+
+#[derive(Clone)]
+struct Pkcs11Key {
+    session: Arc<Mutex<Session>>,
+    handle: ObjectHandle,
+    kind: KeyKind,
+}
+
+impl fmt::Debug for Pkcs11Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Pkcs11Key({:?})", self.kind)
+    }
+}
+
+impl SigningKey for Pkcs11Key {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
+        self.kind
+            .schemes()
+            .iter()
+            .find(|scheme| offered.contains(scheme))
+            .map(|&scheme| {
+                Box::new(Pkcs11Signer {
+                    key: self.clone(),
+                    scheme,
+                }) as Box<dyn Signer>
+            })
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        match self.kind {
+            KeyKind::Rsa => SignatureAlgorithm::RSA,
+            KeyKind::EcP256 | KeyKind::EcP384 => SignatureAlgorithm::ECDSA,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Pkcs11Signer {
+    key: Pkcs11Key,
+    scheme: SignatureScheme,
+}
+
+impl Signer for Pkcs11Signer {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, rustls::Error> {
+        let mechanism = scheme_to_mechanism(self.scheme)
+            .ok_or_else(|| rustls::Error::General(format!("Unsupported signature scheme: {:?}", self.scheme)))?;
+
+        let signature = run_blocking(|| {
+            let session = self
+                .key
+                .session
+                .lock()
+                .map_err(|_| rustls::Error::General("PKCS11 session is poisoned".to_owned()))?;
+
+            session
+                .sign(&mechanism, self.key.handle, message)
+                .map_err(|e| rustls::Error::General(format!("PKCS11 signing failed: {e}")))
+        })?;
+
+        match self.key.kind {
+            KeyKind::Rsa => Ok(signature),
+            KeyKind::EcP256 | KeyKind::EcP384 => Ok(encode_ecdsa_signature(&signature)),
+        }
+    }
+
+    fn scheme(&self) -> SignatureScheme {
+        self.scheme
+    }
+}
+
+#[derive(Debug)]
+struct Pkcs11CertResolver(Arc<CertifiedKey>);
+
+impl ResolvesClientCert for Pkcs11CertResolver {
+    fn resolve(&self, _root_hint_subjects: &[&[u8]], _sigschemes: &[SignatureScheme]) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.clone())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug)]
+struct ServerVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    no_cert_check: bool,
+    ignore_server_cert: bool,
+}
+
+impl ServerCertVerifier for ServerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.ignore_server_cert {
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        match self
+            .inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+        {
+            Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForName)) if self.no_cert_check => {
+                Ok(ServerCertVerified::assertion())
+            }
+            other => other,
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+fn load_certified_key(driver_path: PathBuf, pin: String, key_id: Option<Vec<u8>>) -> anyhow::Result<CertifiedKey> {
+    debug!("Loading PKCS11 driver: {}", driver_path.display());
+
+    let pkcs11 = Pkcs11::new(driver_path)?;
+    if let Err(e) = pkcs11.initialize(CInitializeArgs::OsThreads) {
+        warn!("PKCS11 initialization error: {}", e);
+    }
+
+    let slot = *pkcs11
+        .get_slots_with_token()?
+        .first()
+        .ok_or_else(|| anyhow!("No PKCS11 token found!"))?;
+
+    let session = pkcs11.open_ro_session(slot)?;
+    session.login(UserType::User, Some(&AuthPin::new(pin)))?;
+
+    let mut template = vec![Attribute::Class(ObjectClass::CERTIFICATE)];
+    if let Some(id) = key_id {
+        template.push(Attribute::Id(id));
+    }
+
+    let cert = *session
+        .find_objects(&template)?
+        .first()
+        .ok_or_else(|| anyhow!("No certificate found on the PKCS11 token!"))?;
+
+    let mut cert_data = None;
+    let mut cert_id = None;
+
+    for attr in session.get_attributes(cert, &[AttributeType::Value, AttributeType::Id])? {
+        match attr {
+            Attribute::Value(value) => cert_data = Some(value),
+            Attribute::Id(id) => cert_id = Some(id),
+            _ => {}
+        }
+    }
+
+    let cert_data = cert_data.ok_or_else(|| anyhow!("Cannot read certificate from the PKCS11 token!"))?;
+    let cert_id = cert_id.ok_or_else(|| anyhow!("PKCS11 certificate has no ID!"))?;
+
+    debug!("Using PKCS11 certificate with ID: {}", hex::encode(&cert_id));
+
+    let handle = *session
+        .find_objects(&[Attribute::Class(ObjectClass::PRIVATE_KEY), Attribute::Id(cert_id)])?
+        .first()
+        .ok_or_else(|| anyhow!("No private key found for the PKCS11 certificate!"))?;
+
+    let mut key_type = None;
+    let mut ec_params = None;
+
+    for attr in session.get_attributes(handle, &[AttributeType::KeyType, AttributeType::EcParams])? {
+        match attr {
+            Attribute::KeyType(value) => key_type = Some(value),
+            Attribute::EcParams(value) => ec_params = Some(value),
+            _ => {}
+        }
+    }
+
+    let kind = match (key_type, ec_params.as_deref()) {
+        (Some(key_type), _) if key_type == KeyType::RSA => KeyKind::Rsa,
+        (Some(key_type), Some(OID_SECP256R1)) if key_type == KeyType::EC => KeyKind::EcP256,
+        (Some(key_type), Some(OID_SECP384R1)) if key_type == KeyType::EC => KeyKind::EcP384,
+        _ => return Err(anyhow!("Unsupported PKCS11 key type!")),
+    };
+
+    let key = Pkcs11Key {
+        session: Arc::new(Mutex::new(session)),
+        handle,
+        kind,
+    };
+
+    Ok(CertifiedKey::new(vec![CertificateDer::from(cert_data)], Arc::new(key)))
+}
+
+async fn certified_key(params: &TunnelParams) -> anyhow::Result<Arc<CertifiedKey>> {
+    let driver_path = params.cert_path.clone().unwrap_or_else(|| DEFAULT_DRIVER_PATH.into());
+    let pin = params
+        .cert_password
+        .clone()
+        .ok_or_else(|| anyhow!("No PKCS11 pin provided!"))?;
+    let key_id = params
+        .cert_id
+        .as_ref()
+        .map(|s| hex::decode(s.replace(':', "")))
+        .transpose()?;
+
+    let cache_key = format!(
+        "{}:{}",
+        driver_path.display(),
+        params.cert_id.as_deref().unwrap_or_default()
+    );
+
+    let mut cached = CERTIFIED_KEY.lock().await;
+
+    match cached.as_ref() {
+        Some((key, certified_key)) if *key == cache_key => Ok(certified_key.clone()),
+        _ => {
+            let certified_key =
+                Arc::new(tokio::task::spawn_blocking(move || load_certified_key(driver_path, pin, key_id)).await??);
+            *cached = Some((cache_key, certified_key.clone()));
+            Ok(certified_key)
+        }
+    }
+}
+
+fn root_cert_store(params: &TunnelParams) -> anyhow::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+
+    let native_certs = rustls_native_certs::load_native_certs();
+    for e in native_certs.errors {
+        warn!("Cannot load system certificate: {}", e);
+    }
+    roots.add_parsable_certificates(native_certs.certs);
+
+    for ca_cert in &params.ca_cert {
+        let data = std::fs::read(ca_cert)?;
+        let certs = rustls_pemfile::certs(&mut data.as_slice()).collect::<Result<Vec<_>, _>>()?;
+        if certs.is_empty() {
+            roots.add(CertificateDer::from(data))?;
+        } else {
+            roots.add_parsable_certificates(certs);
+        }
+    }
+
+    Ok(roots)
+}
+
+/// Create TLS client configuration which signs the handshake with the key stored on the PKCS11 token
+pub async fn new_client_config(params: &TunnelParams) -> anyhow::Result<ClientConfig> {
+    let provider = Arc::new(ring::default_provider());
+
+    let inner =
+        WebPkiServerVerifier::builder_with_provider(Arc::new(root_cert_store(params)?), provider.clone()).build()?;
+
+    if params.ignore_server_cert {
+        warn!("Disabling all certificate checks!!!");
+    }
+
+    let verifier = ServerVerifier {
+        inner,
+        no_cert_check: params.no_cert_check,
+        ignore_server_cert: params.ignore_server_cert,
+    };
+
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_client_cert_resolver(Arc::new(Pkcs11CertResolver(certified_key(params).await?)));
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_ecdsa_signature() {
+        let mut raw = vec![0u8; 64];
+        raw[0] = 0x80;
+        raw[31] = 0x01;
+        raw[63] = 0x7f;
+
+        let encoded = encode_ecdsa_signature(&raw);
+
+        let mut expected = vec![0x30, 0x26, 0x02, 0x21, 0x00, 0x80];
+        expected.extend_from_slice(&raw[1..32]);
+        expected.extend_from_slice(&[0x02, 0x01, 0x7f]);
+
+        assert_eq!(encoded, expected);
+    }
+}
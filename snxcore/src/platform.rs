@@ -1,4 +1,8 @@
-use std::{net::Ipv4Addr, sync::Arc, time::Duration};
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::anyhow;
 use ipnet::Ipv4Net;
@@ -7,15 +11,18 @@ use tokio::net::UdpSocket;
 #[cfg(target_os = "linux")]
 use linux as platform_impl;
 pub use platform_impl::{
-    acquire_password, delete_device, get_machine_uuid,
+    acquire_password, delete_device,
+    dns::new_dns_configurator,
+    get_machine_uuid,
     net::{
-        add_default_route, add_dns_servers, add_dns_suffixes, add_route, add_routes, get_default_ip, is_online,
-        poll_online, start_network_state_monitoring,
+        add_default_route, add_keepalive_route, add_route, add_routes, delete_keepalive_route, get_default_ip,
+        get_link_mtu, get_source_ip, is_online, poll_online, set_link_mtu, start_network_state_monitoring,
+        watch_source_ip,
     },
     new_tun_config, store_password, unmanage_device, IpsecImpl, SingleInstance,
 };
 
-use crate::model::{params::TunnelParams, IpsecSession};
+use crate::model::{params::TunnelParams, IpsecSession, TunnelStatistics};
 
 #[cfg(target_os = "linux")]
 mod linux;
@@ -25,16 +32,106 @@ pub trait IpsecConfigurator {
     async fn configure(&mut self) -> anyhow::Result<()>;
     async fn rekey(&mut self, session: &IpsecSession) -> anyhow::Result<()>;
     async fn cleanup(&mut self);
+    async fn statistics(&self) -> anyhow::Result<TunnelStatistics>;
+    fn device_name(&self) -> &str;
+    fn mtu(&self) -> u16;
+    async fn set_mtu(&mut self, mtu: u16) -> anyhow::Result<()>;
+    async fn reanchor(&mut self, source_ip: IpAddr) -> anyhow::Result<()>;
 }
 
 pub fn new_ipsec_configurator(
     tunnel_params: Arc<TunnelParams>,
     ipsec_session: IpsecSession,
     src_port: u16,
-    dest_ip: Ipv4Addr,
+    dest_ip: IpAddr,
+    dest_port: u16,
+    keepalive_ip: Ipv4Addr,
     subnets: Vec<Ipv4Net>,
 ) -> anyhow::Result<impl IpsecConfigurator> {
-    IpsecImpl::new(tunnel_params, ipsec_session, src_port, dest_ip, subnets)
+    IpsecImpl::new(
+        tunnel_params,
+        ipsec_session,
+        src_port,
+        dest_ip,
+        dest_port,
+        keepalive_ip,
+        subnets,
+    )
+}
+
+/// DNS suffix of the tunnel link
+#[derive(Debug, Clone, PartialEq)]
+pub struct DnsSuffix {
+    pub name: String,
+    /// Only the queries for this domain are sent to the tunnel DNS servers, the domain is not searched
+    pub routing_only: bool,
+}
+
+/// Resolver configuration of the tunnel link
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DnsConfig {
+    pub servers: Vec<IpAddr>,
+    pub suffixes: Vec<DnsSuffix>,
+    pub dnssec: Option<String>,
+    pub dns_over_tls: Option<String>,
+}
+
+impl DnsConfig {
+    // acquired suffixes come first, followed by the configured and the routing ones; ignored and duplicate suffixes
+    // are dropped. Suffixes with "~" prefix or listed in the routing domains are routing-only.
+    pub fn new<I, T>(params: &TunnelParams, acquired_suffixes: I, servers: Vec<IpAddr>) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<str>,
+    {
+        let mut suffixes: Vec<DnsSuffix> = Vec::new();
+
+        let acquired_suffixes = acquired_suffixes
+            .into_iter()
+            .map(|s| s.as_ref().trim().to_owned())
+            .collect::<Vec<_>>();
+
+        for suffix in acquired_suffixes
+            .iter()
+            .chain(params.search_domains.iter())
+            .chain(params.routing_domains.iter())
+        {
+            let suffix = suffix.trim();
+            let (name, tilde) = match suffix.strip_prefix('~') {
+                Some(name) => (name, true),
+                None => (suffix, false),
+            };
+
+            let is_ignored = params
+                .ignore_search_domains
+                .iter()
+                .any(|d| d.trim_start_matches('~').eq_ignore_ascii_case(name));
+
+            if name.is_empty() || is_ignored || suffixes.iter().any(|s| s.name.eq_ignore_ascii_case(name)) {
+                continue;
+            }
+
+            let routing_only = tilde || params.routing_domains.iter().any(|d| d.eq_ignore_ascii_case(name));
+
+            suffixes.push(DnsSuffix {
+                name: name.to_owned(),
+                routing_only,
+            });
+        }
+
+        Self {
+            servers,
+            suffixes,
+            dnssec: params.dnssec.clone(),
+            dns_over_tls: params.dns_over_tls.clone(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+pub trait DnsConfigurator: Send + Sync {
+    async fn configure(&self, device: &str, config: &DnsConfig) -> anyhow::Result<()>;
+    async fn revert(&self, device: &str);
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
@@ -46,6 +143,8 @@ pub enum UdpEncap {
 pub trait UdpSocketExt {
     fn set_encap(&self, encap: UdpEncap) -> anyhow::Result<()>;
     fn set_no_check(&self, flag: bool) -> anyhow::Result<()>;
+    fn set_dont_fragment(&self, flag: bool) -> anyhow::Result<()>;
+    fn disconnect(&self) -> anyhow::Result<()>;
     async fn send_receive(&self, data: &[u8], timeout: Duration) -> anyhow::Result<Vec<u8>>;
 }
 
@@ -63,3 +162,44 @@ async fn udp_send_receive(socket: &UdpSocket, data: &[u8], timeout: Duration) ->
         Err(anyhow!("Error sending UDP request!"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dns_config() {
+        let params = TunnelParams {
+            search_domains: vec!["~corp.example".to_owned(), "lab.example".to_owned()],
+            ignore_search_domains: vec!["ignored.example".to_owned()],
+            routing_domains: vec!["VPN.example".to_owned(), "extra.example".to_owned()],
+            dnssec: Some("allow-downgrade".to_owned()),
+            ..Default::default()
+        };
+
+        let config = DnsConfig::new(
+            &params,
+            ["vpn.example", " ignored.example", "", "Lab.Example"],
+            vec![Ipv4Addr::new(10, 0, 0, 1).into()],
+        );
+
+        let suffixes = config
+            .suffixes
+            .iter()
+            .map(|s| (s.name.as_str(), s.routing_only))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            suffixes,
+            vec![
+                ("vpn.example", true),
+                ("Lab.Example", false),
+                ("corp.example", true),
+                ("extra.example", true),
+            ]
+        );
+        assert_eq!(config.servers, vec![IpAddr::from(Ipv4Addr::new(10, 0, 0, 1))]);
+        assert_eq!(config.dnssec.as_deref(), Some("allow-downgrade"));
+        assert_eq!(config.dns_over_tls, None);
+    }
+}
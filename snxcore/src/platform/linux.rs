@@ -17,7 +17,9 @@ pub use xfrm::XfrmConfigurator as IpsecImpl;
 
 use crate::platform::{UdpEncap, UdpSocketExt};
 
+pub mod dns;
 pub mod net;
+pub mod netlink;
 pub mod xfrm;
 
 const UDP_ENCAP_ESPINUDP: libc::c_int = 2; // from /usr/include/linux/udp.h
@@ -63,6 +65,45 @@ impl UdpSocketExt for UdpSocket {
         }
     }
 
+    fn set_dont_fragment(&self, flag: bool) -> anyhow::Result<()> {
+        // probe mode sets DF bit and ignores the cached path MTU
+        let mode: libc::c_int = if flag {
+            libc::IP_PMTUDISC_PROBE
+        } else {
+            libc::IP_PMTUDISC_DONT
+        };
+        unsafe {
+            let rc = libc::setsockopt(
+                self.as_raw_fd(),
+                libc::IPPROTO_IP,
+                libc::IP_MTU_DISCOVER,
+                &mode as *const libc::c_int as _,
+                std::mem::size_of::<libc::c_int>() as _,
+            );
+            if rc != 0 {
+                Err(anyhow!("Cannot set IP_MTU_DISCOVER socket option, error code: {}", rc))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn disconnect(&self) -> anyhow::Result<()> {
+        // connecting to AF_UNSPEC address dissolves the association and resets the cached source address
+        let addr = libc::sockaddr {
+            sa_family: libc::AF_UNSPEC as _,
+            sa_data: [0; 14],
+        };
+        unsafe {
+            let rc = libc::connect(self.as_raw_fd(), &addr, std::mem::size_of::<libc::sockaddr>() as _);
+            if rc != 0 {
+                Err(anyhow!("Cannot disconnect UDP socket, error code: {}", rc))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
     async fn send_receive(&self, data: &[u8], timeout: Duration) -> anyhow::Result<Vec<u8>> {
         super::udp_send_receive(self, data, timeout).await
     }
@@ -72,7 +113,7 @@ pub fn new_tun_config() -> tun::Configuration {
     let mut config = tun::Configuration::default();
 
     config.platform(|config| {
-        config.packet_information(true);
+        config.packet_information(false);
     });
 
     config
@@ -188,7 +229,7 @@ pub async fn unmanage_device(device_name: &str) {
 }
 
 pub async fn delete_device(device_name: &str) {
-    let _ = crate::util::run_command("ip", ["link", "del", "name", device_name]).await;
+    let _ = netlink::delete_link(device_name).await;
 }
 
 pub fn get_machine_uuid() -> anyhow::Result<Uuid> {
@@ -0,0 +1,685 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
+    sync::Mutex,
+};
+
+use ipnet::Ipv4Net;
+use once_cell::sync::Lazy;
+use tokio::{sync::OnceCell, task::JoinHandle};
+use tracing::{debug, warn};
+use zbus::{
+    fdo::DBusProxy,
+    names::BusName,
+    zvariant::{ObjectPath, OwnedObjectPath, Value},
+    Connection,
+};
+
+use crate::{
+    dns_stub::{self, SplitDnsRoutes},
+    model::params::{DnsBackend, TunnelParams},
+    platform::{DnsConfig, DnsConfigurator, DnsSuffix},
+    util,
+};
+
+use super::{
+    net::{NetworkManagerDeviceProxy, NetworkManagerProxy},
+    netlink::{self, RouteInfo},
+};
+
+const RESOLVED_SERVICE: &str = "org.freedesktop.resolve1";
+const NETWORK_MANAGER_SERVICE: &str = "org.freedesktop.NetworkManager";
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const RESOLV_CONF_BACKUP: &str = "/etc/resolv.conf.snx-rs";
+const DNSMASQ_CONF_DIR: &str = "/etc/dnsmasq.d";
+const RESOLVED_STUB_ADDRESS: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 53);
+const RT_TABLE_MAIN: u32 = 254;
+
+// detection is done once: the resolv.conf backend replaces the file it is detected from
+static DETECTED_BACKEND: OnceCell<DnsBackend> = OnceCell::const_new();
+
+static STUB_TASKS: Lazy<Mutex<HashMap<String, JoinHandle<()>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[zbus::proxy(
+    interface = "org.freedesktop.resolve1.Manager",
+    default_service = "org.freedesktop.resolve1",
+    default_path = "/org/freedesktop/resolve1"
+)]
+pub trait ResolvedManager {
+    #[zbus(name = "SetLinkDNS")]
+    fn set_link_dns(&self, ifindex: i32, addresses: &[(i32, Vec<u8>)]) -> zbus::Result<()>;
+
+    fn set_link_domains(&self, ifindex: i32, domains: &[(&str, bool)]) -> zbus::Result<()>;
+
+    fn set_link_default_route(&self, ifindex: i32, enable: bool) -> zbus::Result<()>;
+
+    #[zbus(name = "SetLinkDNSSEC")]
+    fn set_link_dnssec(&self, ifindex: i32, mode: &str) -> zbus::Result<()>;
+
+    #[zbus(name = "SetLinkDNSOverTLS")]
+    fn set_link_dns_over_tls(&self, ifindex: i32, mode: &str) -> zbus::Result<()>;
+
+    fn revert_link(&self, ifindex: i32) -> zbus::Result<()>;
+
+    fn get_link(&self, ifindex: i32) -> zbus::Result<OwnedObjectPath>;
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.resolve1.Link",
+    default_service = "org.freedesktop.resolve1"
+)]
+pub trait ResolvedLink {
+    #[zbus(property, name = "DNS")]
+    fn dns(&self) -> zbus::Result<Vec<(i32, Vec<u8>)>>;
+
+    #[zbus(property)]
+    fn domains(&self) -> zbus::Result<Vec<(String, bool)>>;
+
+    #[zbus(property)]
+    fn default_route(&self) -> zbus::Result<bool>;
+
+    #[zbus(property, name = "DNSSEC")]
+    fn dnssec(&self) -> zbus::Result<String>;
+
+    #[zbus(property, name = "DNSOverTLS")]
+    fn dns_over_tls(&self) -> zbus::Result<String>;
+}
+
+fn resolved_address(address: IpAddr) -> (i32, Vec<u8>) {
+    match address {
+        IpAddr::V4(v4) => (libc::AF_INET, v4.octets().to_vec()),
+        IpAddr::V6(v6) => (libc::AF_INET6, v6.octets().to_vec()),
+    }
+}
+
+fn parse_resolved_address((family, data): &(i32, Vec<u8>)) -> Option<IpAddr> {
+    match *family {
+        libc::AF_INET => Some(IpAddr::from(<[u8; 4]>::try_from(data.as_slice()).ok()?)),
+        libc::AF_INET6 => Some(IpAddr::from(<[u8; 16]>::try_from(data.as_slice()).ok()?)),
+        _ => None,
+    }
+}
+
+/// Link configuration as reported back by systemd-resolved
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ResolvedLinkState {
+    pub servers: Vec<IpAddr>,
+    pub suffixes: Vec<DnsSuffix>,
+    pub default_route: bool,
+    pub dnssec: String,
+    pub dns_over_tls: String,
+}
+
+fn link_ifindex(device: &str) -> anyhow::Result<i32> {
+    Ok(netlink::link_index(device)? as i32)
+}
+
+pub async fn get_link_dns_state(device: &str) -> anyhow::Result<ResolvedLinkState> {
+    let connection = Connection::system().await?;
+    let manager = ResolvedManagerProxy::new(&connection).await?;
+    let path = manager.get_link(link_ifindex(device)?).await?;
+    let link = ResolvedLinkProxy::builder(&connection).path(path)?.build().await?;
+
+    Ok(ResolvedLinkState {
+        servers: link.dns().await?.iter().filter_map(parse_resolved_address).collect(),
+        suffixes: link
+            .domains()
+            .await?
+            .into_iter()
+            .map(|(name, routing_only)| DnsSuffix { name, routing_only })
+            .collect(),
+        default_route: link.default_route().await?,
+        dnssec: link.dnssec().await?,
+        dns_over_tls: link.dns_over_tls().await?,
+    })
+}
+
+pub struct ResolvedConfigurator;
+
+#[async_trait::async_trait]
+impl DnsConfigurator for ResolvedConfigurator {
+    async fn configure(&self, device: &str, config: &DnsConfig) -> anyhow::Result<()> {
+        let ifindex = link_ifindex(device)?;
+        let connection = Connection::system().await?;
+        let manager = ResolvedManagerProxy::new(&connection).await?;
+
+        let servers = config.servers.iter().copied().map(resolved_address).collect::<Vec<_>>();
+        manager.set_link_dns(ifindex, &servers).await?;
+
+        let domains = config
+            .suffixes
+            .iter()
+            .map(|s| (s.name.as_str(), s.routing_only))
+            .collect::<Vec<_>>();
+        manager.set_link_domains(ifindex, &domains).await?;
+
+        // only the queries matching the link domains are sent through the tunnel
+        manager.set_link_default_route(ifindex, false).await?;
+
+        if let Some(ref dnssec) = config.dnssec {
+            manager.set_link_dnssec(ifindex, dnssec).await?;
+        }
+
+        if let Some(ref dns_over_tls) = config.dns_over_tls {
+            manager.set_link_dns_over_tls(ifindex, dns_over_tls).await?;
+        }
+
+        match get_link_dns_state(device).await {
+            Ok(state) => debug!("Resolver state of {}: {:?}", device, state),
+            Err(e) => warn!("Cannot read back resolver state of {}: {}", device, e),
+        }
+
+        Ok(())
+    }
+
+    async fn revert(&self, device: &str) {
+        let result = async {
+            let ifindex = link_ifindex(device)?;
+            let connection = Connection::system().await?;
+            let manager = ResolvedManagerProxy::new(&connection).await?;
+            manager.revert_link(ifindex).await?;
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+
+        if let Err(e) = result {
+            debug!("Cannot revert resolved configuration of {}: {}", device, e);
+        }
+    }
+}
+
+// from /usr/include/libnm/nm-dbus-interface.h
+const NM_DEVICE_TYPE_TUN: u32 = 16;
+const NM_DNS_PRIORITY_VPN: i32 = 50;
+
+// devices which were unmanaged before the connection was activated on them
+static NM_TAKEN_OVER: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Activates a volatile connection on the tunnel device which carries the DNS settings,
+/// NetworkManager removes it when the connection is deactivated
+pub struct NetworkManagerConfigurator;
+
+impl NetworkManagerConfigurator {
+    // the subnet route of the address is added by NetworkManager itself
+    fn connection_routes(address: Ipv4Net, routes: &[RouteInfo]) -> Vec<(Ipv4Net, u32)> {
+        routes
+            .iter()
+            .filter_map(|r| match r.destination {
+                Some(IpAddr::V4(dest)) => Ipv4Net::new(dest, r.dst_len).ok().map(|dest| (dest, r.table)),
+                None if r.dst_len == 0 => Ipv4Net::new(Ipv4Addr::UNSPECIFIED, 0).ok().map(|dest| (dest, r.table)),
+                _ => None,
+            })
+            .filter(|(dest, table)| *table != RT_TABLE_MAIN || *dest != address.trunc())
+            .collect()
+    }
+
+    // the connection replaces the device configuration, so the address and routes are carried over as they are
+    fn connection_settings<'a>(
+        device: &'a str,
+        device_type: u32,
+        address: Ipv4Net,
+        routes: &[RouteInfo],
+        config: &'a DnsConfig,
+    ) -> HashMap<&'static str, HashMap<&'static str, Value<'a>>> {
+        let mut connection = HashMap::new();
+        connection.insert("id", Value::from(format!("snx-rs {device}")));
+        connection.insert("interface-name", Value::from(device));
+        connection.insert("autoconnect", Value::from(false));
+
+        let mut settings = HashMap::new();
+
+        if device_type == NM_DEVICE_TYPE_TUN {
+            connection.insert("type", Value::from("tun"));
+            settings.insert("tun", HashMap::from([("mode", Value::from(1u32))]));
+        } else {
+            connection.insert("type", Value::from("generic"));
+        }
+
+        // IPv4 addresses are passed in network byte order, routing-only domains have the "~" prefix
+        let servers = config
+            .servers
+            .iter()
+            .filter_map(|s| match s {
+                IpAddr::V4(v4) => Some(u32::from_ne_bytes(v4.octets())),
+                IpAddr::V6(_) => None,
+            })
+            .collect::<Vec<_>>();
+
+        let search = config
+            .suffixes
+            .iter()
+            .map(|s| {
+                if s.routing_only {
+                    format!("~{}", s.name)
+                } else {
+                    s.name.clone()
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let address_data = vec![HashMap::from([
+            ("address", Value::from(address.addr().to_string())),
+            ("prefix", Value::from(address.prefix_len() as u32)),
+        ])];
+
+        let route_data = Self::connection_routes(address, routes)
+            .into_iter()
+            .map(|(dest, table)| {
+                let mut route = HashMap::from([
+                    ("dest", Value::from(dest.network().to_string())),
+                    ("prefix", Value::from(dest.prefix_len() as u32)),
+                ]);
+                if table != RT_TABLE_MAIN {
+                    route.insert("table", Value::from(table));
+                }
+                route
+            })
+            .collect::<Vec<_>>();
+
+        let ipv4 = HashMap::from([
+            ("method", Value::from("manual")),
+            ("address-data", Value::from(address_data)),
+            ("route-data", Value::from(route_data)),
+            ("dns", Value::from(servers)),
+            ("dns-search", Value::from(search)),
+            ("dns-priority", Value::from(NM_DNS_PRIORITY_VPN)),
+            ("never-default", Value::from(true)),
+        ]);
+
+        settings.insert("connection", connection);
+        settings.insert("ipv4", ipv4);
+        settings.insert("ipv6", HashMap::from([("method", Value::from("ignore"))]));
+        settings
+    }
+}
+
+#[async_trait::async_trait]
+impl DnsConfigurator for NetworkManagerConfigurator {
+    async fn configure(&self, device: &str, config: &DnsConfig) -> anyhow::Result<()> {
+        let index = netlink::link_index(device)?;
+        let address = netlink::get_link_ipv4_net(index).await?;
+        let routes = netlink::get_link_routes(index).await?;
+
+        let connection = Connection::system().await?;
+        let manager = NetworkManagerProxy::new(&connection).await?;
+        let device_path = manager.get_device_by_ip_iface(device).await?;
+        let nm_device = NetworkManagerDeviceProxy::builder(&connection)
+            .path(&device_path)?
+            .build()
+            .await?;
+
+        // the tunnel devices are unmanaged after creation
+        if !nm_device.managed().await? {
+            nm_device.set_managed(true).await?;
+            NM_TAKEN_OVER.lock().unwrap().insert(device.to_owned());
+        }
+
+        let settings = Self::connection_settings(device, nm_device.device_type().await?, address, &routes, config);
+        let options = HashMap::from([("persist", Value::from("volatile"))]);
+
+        let (_, active, _) = manager
+            .add_and_activate_connection2(settings, &device_path, &ObjectPath::try_from("/")?, options)
+            .await?;
+
+        debug!("Activated NetworkManager connection {} for {}", active.as_str(), device);
+
+        Ok(())
+    }
+
+    async fn revert(&self, device: &str) {
+        let result = async {
+            let connection = Connection::system().await?;
+            let manager = NetworkManagerProxy::new(&connection).await?;
+            let device_path = manager.get_device_by_ip_iface(device).await?;
+            let nm_device = NetworkManagerDeviceProxy::builder(&connection)
+                .path(&device_path)?
+                .build()
+                .await?;
+
+            let active = nm_device.active_connection().await?;
+            if active.as_str() != "/" {
+                manager.deactivate_connection(&active).await?;
+            }
+
+            if NM_TAKEN_OVER.lock().unwrap().remove(device) {
+                nm_device.set_managed(false).await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+
+        if let Err(e) = result {
+            debug!("Cannot revert NetworkManager configuration of {}: {}", device, e);
+        }
+    }
+}
+
+/// Drop-in file with the per-domain upstream servers, dnsmasq has no search list so all suffixes are routed
+pub struct DnsmasqConfigurator;
+
+impl DnsmasqConfigurator {
+    fn config_path(device: &str) -> String {
+        format!("{DNSMASQ_CONF_DIR}/snx-rs-{device}.conf")
+    }
+
+    fn config_contents(config: &DnsConfig) -> String {
+        let mut contents = String::from("# Generated by snx-rs\n");
+        for suffix in &config.suffixes {
+            for server in &config.servers {
+                contents.push_str(&format!("server=/{}/{}\n", suffix.name, server));
+            }
+        }
+        contents
+    }
+
+    // the server lines are read only at startup, SIGHUP is not enough
+    async fn restart() -> anyhow::Result<()> {
+        util::run_command("systemctl", ["restart", "dnsmasq"]).await?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl DnsConfigurator for DnsmasqConfigurator {
+    async fn configure(&self, device: &str, config: &DnsConfig) -> anyhow::Result<()> {
+        tokio::fs::write(Self::config_path(device), Self::config_contents(config)).await?;
+        Self::restart().await
+    }
+
+    async fn revert(&self, device: &str) {
+        match tokio::fs::remove_file(Self::config_path(device)).await {
+            Ok(()) => {
+                if let Err(e) = Self::restart().await {
+                    warn!("Cannot restart dnsmasq: {}", e);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Cannot remove dnsmasq configuration: {}", e),
+        }
+    }
+}
+
+/// Rewrites /etc/resolv.conf with the tunnel servers first, the original file is moved to the backup
+pub struct ResolvConfConfigurator;
+
+impl ResolvConfConfigurator {
+    // routing-only domains cannot be expressed and are resolved via the tunnel servers which come first
+    fn contents(original: &str, config: &DnsConfig) -> String {
+        let mut servers = config.servers.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let mut search = config
+            .suffixes
+            .iter()
+            .filter(|s| !s.routing_only)
+            .map(|s| s.name.clone())
+            .collect::<Vec<_>>();
+        let mut other = Vec::new();
+
+        for line in original.lines().map(str::trim) {
+            let mut parts = line.split_whitespace();
+            match parts.next() {
+                Some("nameserver") => servers.extend(parts.map(ToOwned::to_owned)),
+                Some("search" | "domain") => search.extend(parts.map(ToOwned::to_owned)),
+                Some(option) if !option.starts_with(['#', ';']) => other.push(line.to_owned()),
+                _ => {}
+            }
+        }
+
+        let mut contents = String::from("# Generated by snx-rs, the original file is saved as ");
+        contents.push_str(RESOLV_CONF_BACKUP);
+        contents.push('\n');
+
+        for server in servers {
+            contents.push_str(&format!("nameserver {server}\n"));
+        }
+        if !search.is_empty() {
+            contents.push_str(&format!("search {}\n", search.join(" ")));
+        }
+        for line in other {
+            contents.push_str(&line);
+            contents.push('\n');
+        }
+        contents
+    }
+}
+
+#[async_trait::async_trait]
+impl DnsConfigurator for ResolvConfConfigurator {
+    async fn configure(&self, _device: &str, config: &DnsConfig) -> anyhow::Result<()> {
+        // the backup left over from the previous run is the original file
+        let original = if Path::new(RESOLV_CONF_BACKUP).exists() {
+            tokio::fs::read_to_string(RESOLV_CONF_BACKUP).await?
+        } else {
+            let original = tokio::fs::read_to_string(RESOLV_CONF).await.unwrap_or_default();
+            // renaming keeps the symlink if resolv.conf is managed by another tool,
+            // a missing file is backed up as the empty one which the resolver treats the same way
+            match tokio::fs::rename(RESOLV_CONF, RESOLV_CONF_BACKUP).await {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => tokio::fs::write(RESOLV_CONF_BACKUP, "").await?,
+                result => result?,
+            }
+            original
+        };
+
+        tokio::fs::write(RESOLV_CONF, Self::contents(&original, config)).await?;
+
+        Ok(())
+    }
+
+    async fn revert(&self, _device: &str) {
+        if Path::new(RESOLV_CONF_BACKUP).exists() {
+            if let Err(e) = tokio::fs::rename(RESOLV_CONF_BACKUP, RESOLV_CONF).await {
+                warn!("Cannot restore {}: {}", RESOLV_CONF, e);
+            }
+        }
+    }
+}
+
+/// Runs the split DNS forwarder on the loopback address without touching the system resolver
+pub struct StubConfigurator {
+    address: SocketAddr,
+}
+
+#[async_trait::async_trait]
+impl DnsConfigurator for StubConfigurator {
+    async fn configure(&self, device: &str, config: &DnsConfig) -> anyhow::Result<()> {
+        // the backup is the original file if resolv.conf is rewritten
+        let resolv_conf = match tokio::fs::read_to_string(RESOLV_CONF_BACKUP).await {
+            Ok(backup) => backup,
+            Err(_) => tokio::fs::read_to_string(RESOLV_CONF).await?,
+        };
+
+        let upstream_servers = resolv_conf_servers(&resolv_conf)
+            .into_iter()
+            .filter(|&s| s != self.address.ip())
+            .collect();
+
+        let routes = SplitDnsRoutes::new(config, upstream_servers);
+        let task = dns_stub::start(self.address, routes).await?;
+
+        if let Some(previous) = STUB_TASKS.lock().unwrap().insert(device.to_owned(), task) {
+            previous.abort();
+        }
+
+        Ok(())
+    }
+
+    async fn revert(&self, device: &str) {
+        if let Some(task) = STUB_TASKS.lock().unwrap().remove(device) {
+            task.abort();
+        }
+    }
+}
+
+fn resolv_conf_servers(resolv_conf: &str) -> Vec<IpAddr> {
+    resolv_conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|server| server.trim().parse().ok())
+        .collect()
+}
+
+// resolved or NetworkManager are used only when they manage resolv.conf, dnsmasq when it listens on loopback
+fn detect_backend(resolv_conf: &str, resolved: bool, network_manager: bool, dnsmasq: bool) -> DnsBackend {
+    let servers = resolv_conf_servers(resolv_conf);
+
+    if resolved && servers.contains(&RESOLVED_STUB_ADDRESS.into()) {
+        DnsBackend::Resolved
+    } else if network_manager && resolv_conf.contains("Generated by NetworkManager") {
+        DnsBackend::NetworkManager
+    } else if dnsmasq && !servers.is_empty() && servers.iter().all(IpAddr::is_loopback) {
+        DnsBackend::Dnsmasq
+    } else {
+        DnsBackend::ResolvConf
+    }
+}
+
+async fn has_dbus_owner(connection: &Connection, name: &str) -> bool {
+    let Ok(proxy) = DBusProxy::new(connection).await else {
+        return false;
+    };
+    match BusName::try_from(name) {
+        Ok(name) => proxy.name_has_owner(name).await.unwrap_or_default(),
+        Err(_) => false,
+    }
+}
+
+async fn detected_backend() -> DnsBackend {
+    *DETECTED_BACKEND
+        .get_or_init(|| async {
+            // a leftover backup means resolv.conf is still rewritten by the previous run
+            let resolv_conf = match tokio::fs::read_to_string(RESOLV_CONF_BACKUP).await {
+                Ok(backup) => backup,
+                Err(_) => tokio::fs::read_to_string(RESOLV_CONF).await.unwrap_or_default(),
+            };
+
+            let (resolved, network_manager) = match Connection::system().await {
+                Ok(connection) => (
+                    has_dbus_owner(&connection, RESOLVED_SERVICE).await,
+                    has_dbus_owner(&connection, NETWORK_MANAGER_SERVICE).await,
+                ),
+                Err(_) => (false, false),
+            };
+
+            let dnsmasq = Path::new(DNSMASQ_CONF_DIR).is_dir();
+
+            let backend = detect_backend(&resolv_conf, resolved, network_manager, dnsmasq);
+            debug!("Detected DNS backend: {}", backend.as_str());
+            backend
+        })
+        .await
+}
+
+pub async fn new_dns_configurator(params: &TunnelParams) -> Box<dyn DnsConfigurator> {
+    let backend = match params.dns_backend {
+        DnsBackend::Auto => detected_backend().await,
+        other => other,
+    };
+
+    match backend {
+        DnsBackend::Auto | DnsBackend::Resolved => Box::new(ResolvedConfigurator),
+        DnsBackend::NetworkManager => Box::new(NetworkManagerConfigurator),
+        DnsBackend::Dnsmasq => Box::new(DnsmasqConfigurator),
+        DnsBackend::ResolvConf => Box::new(ResolvConfConfigurator),
+        DnsBackend::Stub => Box::new(StubConfigurator {
+            address: params.dns_stub_address,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> DnsConfig {
+        DnsConfig {
+            servers: vec![Ipv4Addr::new(10, 0, 0, 1).into()],
+            suffixes: vec![
+                DnsSuffix {
+                    name: "corp.example".to_owned(),
+                    routing_only: false,
+                },
+                DnsSuffix {
+                    name: "vpn.example".to_owned(),
+                    routing_only: true,
+                },
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_resolved_address() {
+        let v4 = IpAddr::from(Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(resolved_address(v4), (libc::AF_INET, vec![10, 0, 0, 1]));
+        assert_eq!(parse_resolved_address(&resolved_address(v4)), Some(v4));
+
+        let v6: IpAddr = "2001:db8::53".parse().unwrap();
+        assert_eq!(parse_resolved_address(&resolved_address(v6)), Some(v6));
+
+        assert_eq!(parse_resolved_address(&(libc::AF_INET, vec![1, 2, 3])), None);
+    }
+
+    #[test]
+    fn test_dnsmasq_config() {
+        assert_eq!(
+            DnsmasqConfigurator::config_contents(&config()),
+            "# Generated by snx-rs\nserver=/corp.example/10.0.0.1\nserver=/vpn.example/10.0.0.1\n"
+        );
+    }
+
+    #[test]
+    fn test_resolv_conf_contents() {
+        let original = "# comment\nnameserver 192.168.1.1\nsearch home.lan\noptions edns0\n";
+        let contents = ResolvConfConfigurator::contents(original, &config());
+        let lines = contents.lines().skip(1).collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                "nameserver 10.0.0.1",
+                "nameserver 192.168.1.1",
+                "search corp.example home.lan",
+                "options edns0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_detect_backend() {
+        let resolved = "nameserver 127.0.0.53\noptions edns0 trust-ad\n";
+        let nm = "# Generated by NetworkManager\nnameserver 192.168.1.1\n";
+        let local = "nameserver 127.0.0.1\n";
+        let plain = "nameserver 192.168.1.1\n";
+
+        assert_eq!(detect_backend(resolved, true, true, false), DnsBackend::Resolved);
+        assert_eq!(detect_backend(nm, true, true, false), DnsBackend::NetworkManager);
+        assert_eq!(detect_backend(local, false, false, true), DnsBackend::Dnsmasq);
+        assert_eq!(detect_backend(plain, true, false, true), DnsBackend::ResolvConf);
+        assert_eq!(detect_backend(resolved, false, false, false), DnsBackend::ResolvConf);
+    }
+
+    #[test]
+    fn test_nm_connection_routes() {
+        let route = |destination: Option<Ipv4Addr>, dst_len, table| RouteInfo {
+            table,
+            destination: destination.map(Into::into),
+            dst_len,
+            index: Some(5),
+            ..Default::default()
+        };
+
+        let routes = vec![
+            route(Some(Ipv4Addr::new(10, 0, 0, 0)), 24, RT_TABLE_MAIN),
+            route(Some(Ipv4Addr::new(172, 16, 0, 0)), 12, RT_TABLE_MAIN),
+            route(None, 0, 100),
+        ];
+
+        assert_eq!(
+            NetworkManagerConfigurator::connection_routes("10.0.0.10/24".parse().unwrap(), &routes),
+            vec![
+                ("172.16.0.0/12".parse().unwrap(), RT_TABLE_MAIN),
+                ("0.0.0.0/0".parse().unwrap(), 100),
+            ]
+        );
+    }
+}
@@ -1,16 +1,26 @@
 use std::{
-    net::Ipv4Addr,
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
     sync::{atomic::AtomicBool, atomic::Ordering},
+    time::Duration,
 };
 
 use anyhow::anyhow;
 use futures::StreamExt;
 use ipnet::Ipv4Net;
-use tracing::debug;
-use zbus::Connection;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+use zbus::{
+    zvariant::{ObjectPath, OwnedObjectPath, OwnedValue, Value},
+    Connection,
+};
+
+use super::netlink::{self, NetlinkMonitor, PortRule, RouteInfo};
 
 static ONLINE_STATE: AtomicBool = AtomicBool::new(true);
 
+const ADDRESS_SETTLE_DELAY: Duration = Duration::from_secs(2);
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 enum NetworkManagerState {
     Unknown,
@@ -52,6 +62,36 @@ impl NetworkManagerState {
 pub trait NetworkManager {
     #[zbus(property)]
     fn state(&self) -> zbus::Result<u32>;
+
+    fn get_device_by_ip_iface(&self, iface: &str) -> zbus::Result<OwnedObjectPath>;
+
+    fn add_and_activate_connection2(
+        &self,
+        connection: HashMap<&str, HashMap<&str, Value<'_>>>,
+        device: &ObjectPath<'_>,
+        specific_object: &ObjectPath<'_>,
+        options: HashMap<&str, Value<'_>>,
+    ) -> zbus::Result<(OwnedObjectPath, OwnedObjectPath, HashMap<String, OwnedValue>)>;
+
+    fn deactivate_connection(&self, active_connection: &ObjectPath<'_>) -> zbus::Result<()>;
+}
+
+#[zbus::proxy(
+    interface = "org.freedesktop.NetworkManager.Device",
+    default_service = "org.freedesktop.NetworkManager"
+)]
+pub trait NetworkManagerDevice {
+    #[zbus(property)]
+    fn device_type(&self) -> zbus::Result<u32>;
+
+    #[zbus(property)]
+    fn managed(&self) -> zbus::Result<bool>;
+
+    #[zbus(property)]
+    fn set_managed(&self, managed: bool) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn active_connection(&self) -> zbus::Result<OwnedObjectPath>;
 }
 
 pub async fn start_network_state_monitoring() -> anyhow::Result<()> {
@@ -89,31 +129,74 @@ pub fn poll_online() {
 }
 
 pub async fn get_default_ip() -> anyhow::Result<String> {
-    let default_route = crate::util::run_command("ip", ["-4", "route", "show", "default"]).await?;
-    let mut parts = default_route.split_whitespace();
-    while let Some(part) = parts.next() {
-        if part == "dev" {
-            if let Some(dev) = parts.next() {
-                let addr = crate::util::run_command("ip", ["-4", "-o", "addr", "show", "dev", dev]).await?;
-                let mut parts = addr.split_whitespace();
-                while let Some(part) = parts.next() {
-                    if part == "inet" {
-                        if let Some(ip) = parts.next() {
-                            if let Some((ip, _)) = ip.split_once('/') {
-                                return Ok(ip.to_string());
+    let index = netlink::get_default_route_index().await?;
+    Ok(netlink::get_link_ipv4_address(index).await?.to_string())
+}
+
+/// Local address which is used to reach the given destination
+pub async fn get_source_ip(dest: IpAddr) -> anyhow::Result<IpAddr> {
+    netlink::get_route(dest)
+        .await?
+        .source
+        .ok_or_else(|| anyhow!("Cannot determine source IP for {}", dest))
+}
+
+/// Watch the address and route changes and send the new source address for the destination when it changes
+pub async fn watch_source_ip(dest: IpAddr) -> anyhow::Result<mpsc::Receiver<IpAddr>> {
+    let mut current = get_source_ip(dest).await?;
+    let mut monitor = NetlinkMonitor::new()?;
+
+    let (sender, receiver) = mpsc::channel(1);
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                () = sender.closed() => break,
+                event = monitor.next() => {
+                    if event.is_err() {
+                        break;
+                    }
+
+                    // the changes come in bursts when the link goes up or down, the address is checked when they settle
+                    while let Ok(Ok(())) = tokio::time::timeout(ADDRESS_SETTLE_DELAY, monitor.next()).await {}
+
+                    if let Ok(address) = get_source_ip(dest).await {
+                        if address != current {
+                            debug!("Source address for {} changed from {} to {}", dest, current, address);
+                            current = address;
+                            if sender.send(address).await.is_err() {
+                                break;
                             }
                         }
                     }
                 }
             }
         }
+    });
+
+    Ok(receiver)
+}
+
+// route MTU takes precedence over the device MTU, it is also set when the path MTU is cached
+pub async fn get_link_mtu(dest: IpAddr) -> anyhow::Result<u16> {
+    let route = netlink::get_route(dest).await?;
+
+    match route {
+        RouteInfo { mtu: Some(mtu), .. } => Ok(mtu),
+        RouteInfo { index: Some(index), .. } => Ok(netlink::get_link_mtu(index).await?),
+        _ => Err(anyhow!("Cannot determine MTU for {}", dest)),
     }
-    Err(anyhow!("Cannot determine default IP!"))
+}
+
+pub async fn set_link_mtu(device: &str, mtu: u16) -> anyhow::Result<()> {
+    debug!("Setting MTU of {} to {}", device, mtu);
+    netlink::set_link_mtu(device, mtu).await?;
+    Ok(())
 }
 
 pub async fn add_route(route: Ipv4Net, device: &str, _ipaddr: Ipv4Addr) -> anyhow::Result<()> {
     debug!("Adding route: {} via {}", route, device);
-    crate::util::run_command("ip", ["route", "add", &route.to_string(), "dev", device]).await?;
+    netlink::add_route(route, netlink::link_index(device)?, None).await?;
     Ok(())
 }
 
@@ -139,46 +222,37 @@ pub async fn add_routes(routes: &[Ipv4Net], device: &str, ipaddr: Ipv4Addr) -> a
 
 pub async fn add_default_route(device: &str, _ipaddr: Ipv4Addr) -> anyhow::Result<()> {
     debug!("Adding default route for {}", device);
-    let _ = crate::util::run_command("ip", ["route", "add", "default", "dev", device]).await?;
+    netlink::add_route(Ipv4Net::default(), netlink::link_index(device)?, None).await?;
 
     Ok(())
 }
 
-pub async fn add_dns_suffixes<I, T>(suffixes: I, device: &str) -> anyhow::Result<()>
-where
-    I: IntoIterator<Item = T>,
-    T: AsRef<str>,
-{
-    let mut args = vec!["domain", device];
-
-    let suffixes = suffixes
-        .into_iter()
-        .map(|s| s.as_ref().trim().to_owned())
-        .collect::<Vec<_>>();
-
-    args.extend(suffixes.iter().map(|s| s.as_str()));
-
-    crate::util::run_command("resolvectl", args).await?;
-
-    crate::util::run_command("resolvectl", ["default-route", device, "false"]).await?;
-
-    Ok(())
+fn keepalive_rule(dest: Ipv4Addr, port: u16) -> PortRule {
+    PortRule {
+        destination: dest,
+        protocol: libc::IPPROTO_UDP as u8,
+        port,
+        table: port as u32,
+    }
 }
 
-pub async fn add_dns_servers<I, T>(servers: I, device: &str) -> anyhow::Result<()>
-where
-    I: IntoIterator<Item = T>,
-    T: AsRef<str>,
-{
-    let mut args = vec!["dns", device];
-
-    let servers = servers.into_iter().map(|s| s.as_ref().to_owned()).collect::<Vec<_>>();
+/// Route the UDP packets for the given destination and port via the device, using the port as the table number
+pub async fn add_keepalive_route(device: &str, dest: Ipv4Addr, port: u16) -> anyhow::Result<()> {
+    netlink::add_route(dest.into(), netlink::link_index(device)?, Some(port as u32)).await?;
 
-    args.extend(servers.iter().map(|s| s.as_str()));
-
-    crate::util::run_command("resolvectl", args).await?;
+    // the rule could be left over from the previous run
+    match netlink::add_rule(&keepalive_rule(dest, port)).await {
+        Err(e) if !e.is_exists() => Err(e.into()),
+        _ => Ok(()),
+    }
+}
 
-    Ok(())
+pub async fn delete_keepalive_route(dest: Ipv4Addr, port: u16) {
+    if let Err(e) = netlink::delete_rule(&keepalive_rule(dest, port)).await {
+        if !e.is_not_found() {
+            warn!("Cannot delete keepalive rule: {}", e);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,1114 @@
+use std::{
+    ffi::CString,
+    fmt, io, mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use ipnet::Ipv4Net;
+use tokio::io::unix::AsyncFd;
+use tracing::trace;
+
+// from /usr/include/linux/netlink.h
+const NLMSG_HEADER_LEN: usize = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLM_F_REQUEST: u16 = 0x01;
+const NLM_F_ACK: u16 = 0x04;
+const NLM_F_DUMP: u16 = 0x300;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+const NLA_HEADER_LEN: usize = 4;
+const NLA_F_NESTED: u16 = 0x8000;
+const NLA_TYPE_MASK: u16 = 0x3fff;
+
+// from /usr/include/linux/rtnetlink.h
+const RTM_NEWLINK: u16 = 16;
+const RTM_DELLINK: u16 = 17;
+const RTM_GETLINK: u16 = 18;
+const RTM_SETLINK: u16 = 19;
+const RTM_NEWADDR: u16 = 20;
+const RTM_GETADDR: u16 = 22;
+const RTM_NEWROUTE: u16 = 24;
+const RTM_GETROUTE: u16 = 26;
+const RTM_NEWRULE: u16 = 32;
+const RTM_DELRULE: u16 = 33;
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RTA_PREFSRC: u16 = 7;
+const RTA_METRICS: u16 = 8;
+const RTA_TABLE: u16 = 15;
+const RTAX_MTU: u16 = 2;
+const RT_TABLE_UNSPEC: u8 = 0;
+const RT_TABLE_MAIN: u32 = 254;
+const RT_TABLE_LOCAL: u32 = 255;
+const RTPROT_BOOT: u8 = 3;
+const RT_SCOPE_LINK: u8 = 253;
+const RTN_UNICAST: u8 = 1;
+const RTMGRP_IPV4_IFADDR: u32 = 0x10;
+const RTMGRP_IPV4_ROUTE: u32 = 0x40;
+const RTMGRP_IPV6_IFADDR: u32 = 0x100;
+const RTMGRP_IPV6_ROUTE: u32 = 0x400;
+
+// from /usr/include/linux/if_link.h and /usr/include/linux/if_addr.h
+const IFLA_IFNAME: u16 = 3;
+const IFLA_MTU: u16 = 4;
+const IFLA_LINKINFO: u16 = 18;
+const IFLA_INFO_KIND: u16 = 1;
+const IFLA_INFO_DATA: u16 = 2;
+const IFLA_XFRM_IF_ID: u16 = 2;
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+
+// from /usr/include/linux/fib_rules.h
+const FRA_DST: u16 = 1;
+const FRA_TABLE: u16 = 15;
+const FRA_IP_PROTO: u16 = 22;
+const FRA_DPORT_RANGE: u16 = 24;
+const FR_ACT_TO_TBL: u8 = 1;
+
+// from /usr/include/linux/xfrm.h
+const XFRM_MSG_NEWSA: u16 = 0x10;
+const XFRM_MSG_DELSA: u16 = 0x11;
+const XFRM_MSG_GETSA: u16 = 0x12;
+const XFRM_MSG_NEWPOLICY: u16 = 0x13;
+const XFRM_MSG_DELPOLICY: u16 = 0x14;
+const XFRMA_ALG_CRYPT: u16 = 2;
+const XFRMA_ENCAP: u16 = 4;
+const XFRMA_TMPL: u16 = 5;
+const XFRMA_SRCADDR: u16 = 9;
+const XFRMA_ALG_AEAD: u16 = 18;
+const XFRMA_ALG_AUTH_TRUNC: u16 = 20;
+const XFRMA_IF_ID: u16 = 31;
+const XFRM_MODE_TUNNEL: u8 = 1;
+const XFRM_STATE_AF_UNSPEC: u8 = 32;
+const XFRM_INF: u64 = u64::MAX;
+const XFRM_ALG_NAME_LEN: usize = 64;
+const XFRM_USERSA_INFO_LEN: usize = 224;
+const XFRM_USERSA_ID_LEN: usize = 24;
+const XFRM_USERPOLICY_INFO_LEN: usize = 168;
+const XFRM_USERPOLICY_ID_LEN: usize = 64;
+const XFRM_USER_TMPL_LEN: usize = 64;
+const XFRM_SELECTOR_LEN: usize = 56;
+const XFRM_ENCAP_TMPL_LEN: usize = 24;
+
+const IPPROTO_ESP: u8 = 50;
+const UDP_ENCAP_ESPINUDP: u16 = 2;
+
+const RECV_BUFFER_SIZE: usize = 65536;
+
+#[derive(Debug)]
+pub enum NetlinkError {
+    /// Socket level failure
+    Io(io::Error),
+    /// Request rejected by the kernel with the given errno
+    Kernel { request: &'static str, errno: i32 },
+    /// Link, route or address does not exist
+    NotFound(String),
+    /// Reply could not be decoded
+    Malformed(&'static str),
+}
+
+impl NetlinkError {
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            Self::Kernel { errno, .. } => Some(*errno),
+            Self::Io(e) => e.raw_os_error(),
+            _ => None,
+        }
+    }
+
+    pub fn is_exists(&self) -> bool {
+        self.errno() == Some(libc::EEXIST)
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, Self::NotFound(_)) || matches!(self.errno(), Some(libc::ENOENT | libc::ESRCH | libc::ENODEV))
+    }
+}
+
+impl fmt::Display for NetlinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "netlink socket error: {}", e),
+            Self::Kernel { request, errno } => {
+                write!(f, "{} failed: {}", request, io::Error::from_raw_os_error(*errno))
+            }
+            Self::NotFound(what) => write!(f, "{} not found", what),
+            Self::Malformed(what) => write!(f, "malformed netlink reply: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for NetlinkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for NetlinkError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, NetlinkError>;
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn family(address: IpAddr) -> u8 {
+    match address {
+        IpAddr::V4(_) => libc::AF_INET as u8,
+        IpAddr::V6(_) => libc::AF_INET6 as u8,
+    }
+}
+
+fn address_bytes(address: IpAddr) -> Vec<u8> {
+    match address {
+        IpAddr::V4(v4) => v4.octets().to_vec(),
+        IpAddr::V6(v6) => v6.octets().to_vec(),
+    }
+}
+
+// xfrm_address_t is a 16 bytes union, IPv4 address takes the first 4 bytes
+fn xfrm_address(address: IpAddr) -> [u8; 16] {
+    let mut result = [0u8; 16];
+    let bytes = address_bytes(address);
+    result[..bytes.len()].copy_from_slice(&bytes);
+    result
+}
+
+fn parse_address(data: &[u8]) -> Option<IpAddr> {
+    match data.len() {
+        4 => Some(Ipv4Addr::from(<[u8; 4]>::try_from(data).ok()?).into()),
+        16 => Some(Ipv6Addr::from(<[u8; 16]>::try_from(data).ok()?).into()),
+        _ => None,
+    }
+}
+
+fn parse_u32(data: &[u8]) -> Option<u32> {
+    Some(u32::from_ne_bytes(data.get(..4)?.try_into().ok()?))
+}
+
+fn parse_u64(data: &[u8]) -> Option<u64> {
+    Some(u64::from_ne_bytes(data.get(..8)?.try_into().ok()?))
+}
+
+/// Request message with the fixed header and the attributes
+struct NetlinkMessage {
+    buf: Vec<u8>,
+    nested: Vec<usize>,
+}
+
+impl NetlinkMessage {
+    fn new(message_type: u16, flags: u16) -> Self {
+        let mut buf = vec![0u8; NLMSG_HEADER_LEN];
+        buf[4..6].copy_from_slice(&message_type.to_ne_bytes());
+        buf[6..8].copy_from_slice(&(flags | NLM_F_REQUEST).to_ne_bytes());
+        Self {
+            buf,
+            nested: Vec::new(),
+        }
+    }
+
+    fn pad(&mut self) {
+        self.buf.resize(align(self.buf.len()), 0);
+    }
+
+    fn push(&mut self, data: &[u8]) -> &mut Self {
+        self.buf.extend_from_slice(data);
+        self.pad();
+        self
+    }
+
+    fn attr(&mut self, attr_type: u16, data: &[u8]) -> &mut Self {
+        self.buf
+            .extend_from_slice(&((NLA_HEADER_LEN + data.len()) as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&attr_type.to_ne_bytes());
+        self.push(data)
+    }
+
+    fn attr_u32(&mut self, attr_type: u16, value: u32) -> &mut Self {
+        self.attr(attr_type, &value.to_ne_bytes())
+    }
+
+    fn attr_str(&mut self, attr_type: u16, value: &str) -> &mut Self {
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        self.attr(attr_type, &data)
+    }
+
+    fn begin_nested(&mut self, attr_type: u16) -> &mut Self {
+        self.nested.push(self.buf.len());
+        self.attr(attr_type | NLA_F_NESTED, &[])
+    }
+
+    fn end_nested(&mut self) -> &mut Self {
+        if let Some(start) = self.nested.pop() {
+            let len = (self.buf.len() - start) as u16;
+            self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+        }
+        self
+    }
+
+    fn finish(mut self, seq: u32) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+        self.buf
+    }
+}
+
+/// Iterator over the netlink attributes: type and payload
+struct Attributes<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Attributes<'a> {
+    type Item = (u16, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.len() < NLA_HEADER_LEN {
+            return None;
+        }
+        let len = u16::from_ne_bytes([self.data[0], self.data[1]]) as usize;
+        let attr_type = u16::from_ne_bytes([self.data[2], self.data[3]]) & NLA_TYPE_MASK;
+        if len < NLA_HEADER_LEN || len > self.data.len() {
+            return None;
+        }
+        let payload = &self.data[NLA_HEADER_LEN..len];
+        self.data = &self.data[align(len).min(self.data.len())..];
+        Some((attr_type, payload))
+    }
+}
+
+fn attributes(data: &[u8], header_len: usize) -> Attributes<'_> {
+    Attributes {
+        data: data.get(align(header_len)..).unwrap_or_default(),
+    }
+}
+
+/// Reply message: type and payload without the netlink header
+#[derive(Debug)]
+struct Reply {
+    message_type: u16,
+    payload: Vec<u8>,
+}
+
+// splits the received datagram into messages, returns true when the reply is complete
+fn parse_replies(data: &[u8], seq: u32, request: &'static str, replies: &mut Vec<Reply>) -> Result<bool> {
+    let mut data = data;
+
+    while data.len() >= NLMSG_HEADER_LEN {
+        let len = u32::from_ne_bytes(data[0..4].try_into().unwrap()) as usize;
+        let message_type = u16::from_ne_bytes([data[4], data[5]]);
+        let message_seq = u32::from_ne_bytes(data[8..12].try_into().unwrap());
+
+        if len < NLMSG_HEADER_LEN || len > data.len() {
+            return Err(NetlinkError::Malformed("message length"));
+        }
+
+        let payload = &data[NLMSG_HEADER_LEN..len];
+        data = &data[align(len).min(data.len())..];
+
+        if message_seq != seq {
+            continue;
+        }
+
+        match message_type {
+            NLMSG_DONE => return Ok(true),
+            NLMSG_ERROR => {
+                let errno = payload
+                    .get(..4)
+                    .map(|e| -i32::from_ne_bytes(e.try_into().unwrap()))
+                    .ok_or(NetlinkError::Malformed("error message"))?;
+                return if errno == 0 {
+                    Ok(true)
+                } else {
+                    Err(NetlinkError::Kernel { request, errno })
+                };
+            }
+            _ => replies.push(Reply {
+                message_type,
+                payload: payload.to_vec(),
+            }),
+        }
+    }
+    Ok(false)
+}
+
+struct NetlinkSocket {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl NetlinkSocket {
+    fn new(protocol: libc::c_int, groups: u32) -> Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+                protocol,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut address: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as _;
+        address.nl_groups = groups;
+
+        let rc = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &address as *const libc::sockaddr_nl as _,
+                mem::size_of::<libc::sockaddr_nl>() as _,
+            )
+        };
+        if rc < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        Ok(Self { fd: AsyncFd::new(fd)? })
+    }
+
+    async fn send(&self, data: &[u8]) -> Result<()> {
+        let mut address: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as _;
+
+        loop {
+            let mut guard = self.fd.writable().await?;
+            let result = guard.try_io(|fd| {
+                let rc = unsafe {
+                    libc::sendto(
+                        fd.as_raw_fd(),
+                        data.as_ptr() as _,
+                        data.len(),
+                        0,
+                        &address as *const libc::sockaddr_nl as _,
+                        mem::size_of::<libc::sockaddr_nl>() as _,
+                    )
+                };
+                if rc < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(())
+                }
+            });
+            if let Ok(result) = result {
+                return Ok(result?);
+            }
+        }
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let result = guard.try_io(|fd| {
+                let rc = unsafe { libc::recv(fd.as_raw_fd(), buf.as_mut_ptr() as _, buf.len(), 0) };
+                if rc < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(rc as usize)
+                }
+            });
+            if let Ok(result) = result {
+                return Ok(result?);
+            }
+        }
+    }
+
+    async fn request(&self, request: &'static str, message: NetlinkMessage) -> Result<Vec<Reply>> {
+        let seq = rand::random::<u32>() | 1;
+        trace!("Netlink request: {}", request);
+
+        self.send(&message.finish(seq)).await?;
+
+        let mut buf = vec![0u8; RECV_BUFFER_SIZE];
+        let mut replies = Vec::new();
+
+        loop {
+            let size = self.recv(&mut buf).await?;
+            if parse_replies(&buf[..size], seq, request, &mut replies)? {
+                break;
+            }
+        }
+
+        Ok(replies)
+    }
+}
+
+async fn route_request(request: &'static str, message: NetlinkMessage) -> Result<Vec<Reply>> {
+    NetlinkSocket::new(libc::NETLINK_ROUTE, 0)?
+        .request(request, message)
+        .await
+}
+
+async fn xfrm_request(request: &'static str, message: NetlinkMessage) -> Result<Vec<Reply>> {
+    NetlinkSocket::new(libc::NETLINK_XFRM, 0)?
+        .request(request, message)
+        .await
+}
+
+/// Interface index by name
+pub fn link_index(name: &str) -> Result<u32> {
+    let c_name = CString::new(name).map_err(|_| NetlinkError::NotFound(name.to_owned()))?;
+    match unsafe { libc::if_nametoindex(c_name.as_ptr()) } {
+        0 => Err(NetlinkError::NotFound(format!("link {}", name))),
+        index => Ok(index),
+    }
+}
+
+// struct ifinfomsg
+fn ifinfomsg(index: u32, flags: u32, change: u32) -> [u8; 16] {
+    let mut msg = [0u8; 16];
+    msg[0] = libc::AF_UNSPEC as u8;
+    msg[4..8].copy_from_slice(&index.to_ne_bytes());
+    msg[8..12].copy_from_slice(&flags.to_ne_bytes());
+    msg[12..16].copy_from_slice(&change.to_ne_bytes());
+    msg
+}
+
+/// Create the XFRM interface with the given interface ID
+pub async fn add_xfrm_link(name: &str, if_id: u32, mtu: u16) -> Result<()> {
+    let mut message = NetlinkMessage::new(RTM_NEWLINK, NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL);
+    message
+        .push(&ifinfomsg(0, 0, 0))
+        .attr_str(IFLA_IFNAME, name)
+        .attr_u32(IFLA_MTU, mtu as u32)
+        .begin_nested(IFLA_LINKINFO)
+        .attr_str(IFLA_INFO_KIND, "xfrm")
+        .begin_nested(IFLA_INFO_DATA)
+        .attr_u32(IFLA_XFRM_IF_ID, if_id)
+        .end_nested()
+        .end_nested();
+
+    route_request("add link", message).await?;
+    Ok(())
+}
+
+pub async fn set_link_up(name: &str) -> Result<()> {
+    let flag = libc::IFF_UP as u32;
+    let mut message = NetlinkMessage::new(RTM_SETLINK, NLM_F_ACK);
+    message.push(&ifinfomsg(link_index(name)?, flag, flag));
+
+    route_request("set link up", message).await?;
+    Ok(())
+}
+
+pub async fn set_link_mtu(name: &str, mtu: u16) -> Result<()> {
+    let mut message = NetlinkMessage::new(RTM_SETLINK, NLM_F_ACK);
+    message
+        .push(&ifinfomsg(link_index(name)?, 0, 0))
+        .attr_u32(IFLA_MTU, mtu as u32);
+
+    route_request("set link MTU", message).await?;
+    Ok(())
+}
+
+pub async fn get_link_mtu(index: u32) -> Result<u16> {
+    let mut message = NetlinkMessage::new(RTM_GETLINK, NLM_F_ACK);
+    message.push(&ifinfomsg(index, 0, 0));
+
+    route_request("get link", message)
+        .await?
+        .iter()
+        .filter(|r| r.message_type == RTM_NEWLINK)
+        .flat_map(|r| attributes(&r.payload, 16))
+        .find_map(|(attr_type, data)| (attr_type == IFLA_MTU).then(|| parse_u32(data)).flatten())
+        .map(|mtu| mtu as u16)
+        .ok_or_else(|| NetlinkError::NotFound(format!("MTU of link {}", index)))
+}
+
+pub async fn delete_link(name: &str) -> Result<()> {
+    let mut message = NetlinkMessage::new(RTM_DELLINK, NLM_F_ACK);
+    message.push(&ifinfomsg(link_index(name)?, 0, 0));
+
+    route_request("delete link", message).await?;
+    Ok(())
+}
+
+// struct ifaddrmsg
+fn ifaddrmsg(family: u8, prefix_len: u8, index: u32) -> [u8; 8] {
+    let mut msg = [0u8; 8];
+    msg[0] = family;
+    msg[1] = prefix_len;
+    msg[4..8].copy_from_slice(&index.to_ne_bytes());
+    msg
+}
+
+pub async fn add_address(name: &str, address: Ipv4Net) -> Result<()> {
+    let mut message = NetlinkMessage::new(RTM_NEWADDR, NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL);
+    message
+        .push(&ifaddrmsg(libc::AF_INET as u8, address.prefix_len(), link_index(name)?))
+        .attr(IFA_LOCAL, &address.addr().octets())
+        .attr(IFA_ADDRESS, &address.addr().octets());
+
+    route_request("add address", message).await?;
+    Ok(())
+}
+
+/// The first IPv4 address assigned to the interface
+pub async fn get_link_ipv4_address(index: u32) -> Result<Ipv4Addr> {
+    Ok(get_link_ipv4_net(index).await?.addr())
+}
+
+/// IPv4 address of the link together with its prefix length
+pub async fn get_link_ipv4_net(index: u32) -> Result<Ipv4Net> {
+    let mut message = NetlinkMessage::new(RTM_GETADDR, NLM_F_DUMP);
+    message.push(&ifaddrmsg(libc::AF_INET as u8, 0, 0));
+
+    route_request("get addresses", message)
+        .await?
+        .iter()
+        .filter(|r| r.message_type == RTM_NEWADDR && parse_u32(r.payload.get(4..).unwrap_or_default()) == Some(index))
+        .find_map(|r| {
+            let mut local = None;
+            let mut address = None;
+            for (attr_type, data) in attributes(&r.payload, 8) {
+                match attr_type {
+                    IFA_LOCAL => local = parse_address(data),
+                    IFA_ADDRESS => address = parse_address(data),
+                    _ => {}
+                }
+            }
+            match local.or(address) {
+                Some(IpAddr::V4(v4)) => Ipv4Net::new(v4, r.payload.get(1).copied().unwrap_or(32)).ok(),
+                _ => None,
+            }
+        })
+        .ok_or_else(|| NetlinkError::NotFound(format!("IPv4 address of link {}", index)))
+}
+
+/// Route lookup result
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RouteInfo {
+    pub table: u32,
+    pub destination: Option<IpAddr>,
+    pub dst_len: u8,
+    pub index: Option<u32>,
+    pub source: Option<IpAddr>,
+    pub mtu: Option<u16>,
+}
+
+impl RouteInfo {
+    // rtmsg header followed by the attributes
+    fn parse(payload: &[u8]) -> Option<Self> {
+        if payload.len() < 12 {
+            return None;
+        }
+
+        let mut info = Self {
+            table: payload[4] as u32,
+            dst_len: payload[1],
+            ..Default::default()
+        };
+
+        for (attr_type, data) in attributes(payload, 12) {
+            match attr_type {
+                RTA_DST => info.destination = parse_address(data),
+                RTA_OIF => info.index = parse_u32(data),
+                RTA_PREFSRC => info.source = parse_address(data),
+                RTA_TABLE => info.table = parse_u32(data).unwrap_or(info.table),
+                RTA_METRICS => {
+                    info.mtu = Attributes { data }
+                        .find(|(metric, _)| *metric == RTAX_MTU)
+                        .and_then(|(_, value)| parse_u32(value))
+                        .map(|mtu| mtu as u16)
+                }
+                _ => {}
+            }
+        }
+        Some(info)
+    }
+}
+
+// struct rtmsg
+fn rtmsg(family: u8, dst_len: u8, table: u32, scope: u8) -> [u8; 12] {
+    let mut msg = [0u8; 12];
+    msg[0] = family;
+    msg[1] = dst_len;
+    msg[4] = if table < 256 { table as u8 } else { RT_TABLE_UNSPEC };
+    msg[5] = RTPROT_BOOT;
+    msg[6] = scope;
+    msg[7] = RTN_UNICAST;
+    msg
+}
+
+/// Add the device route to the given table, the main table is used when no table is given
+pub async fn add_route(destination: Ipv4Net, index: u32, table: Option<u32>) -> Result<()> {
+    let table = table.unwrap_or(RT_TABLE_MAIN);
+
+    let mut message = NetlinkMessage::new(RTM_NEWROUTE, NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL);
+    message.push(&rtmsg(
+        libc::AF_INET as u8,
+        destination.prefix_len(),
+        table,
+        RT_SCOPE_LINK,
+    ));
+    if destination.prefix_len() > 0 {
+        message.attr(RTA_DST, &destination.network().octets());
+    }
+    message.attr_u32(RTA_OIF, index).attr_u32(RTA_TABLE, table);
+
+    route_request("add route", message).await?;
+    Ok(())
+}
+
+/// Lookup the route to the destination, same as "ip route get"
+pub async fn get_route(destination: IpAddr) -> Result<RouteInfo> {
+    let dst_len = if destination.is_ipv4() { 32 } else { 128 };
+
+    let mut message = NetlinkMessage::new(RTM_GETROUTE, NLM_F_ACK);
+    message
+        .push(&[family(destination), dst_len, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+        .attr(RTA_DST, &address_bytes(destination));
+
+    route_request("get route", message)
+        .await?
+        .iter()
+        .filter(|r| r.message_type == RTM_NEWROUTE)
+        .find_map(|r| RouteInfo::parse(&r.payload))
+        .ok_or_else(|| NetlinkError::NotFound(format!("route to {}", destination)))
+}
+
+/// Interface of the IPv4 default route in the main table
+pub async fn get_default_route_index() -> Result<u32> {
+    let mut message = NetlinkMessage::new(RTM_GETROUTE, NLM_F_DUMP);
+    message.push(&[libc::AF_INET as u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+    route_request("get routes", message)
+        .await?
+        .iter()
+        .filter(|r| r.message_type == RTM_NEWROUTE)
+        .filter_map(|r| RouteInfo::parse(&r.payload))
+        .find(|r| r.table == RT_TABLE_MAIN && r.dst_len == 0)
+        .and_then(|r| r.index)
+        .ok_or_else(|| NetlinkError::NotFound("default route".to_owned()))
+}
+
+/// IPv4 routes via the link in all tables except the local one
+pub async fn get_link_routes(index: u32) -> Result<Vec<RouteInfo>> {
+    let mut message = NetlinkMessage::new(RTM_GETROUTE, NLM_F_DUMP);
+    message.push(&[libc::AF_INET as u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+    Ok(route_request("get routes", message)
+        .await?
+        .iter()
+        .filter(|r| r.message_type == RTM_NEWROUTE)
+        .filter_map(|r| RouteInfo::parse(&r.payload))
+        .filter(|r| r.index == Some(index) && r.table != RT_TABLE_LOCAL)
+        .collect())
+}
+
+/// Policy rule which sends the packets to the given destination and port to the routing table
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortRule {
+    pub destination: Ipv4Addr,
+    pub protocol: u8,
+    pub port: u16,
+    pub table: u32,
+}
+
+impl PortRule {
+    fn message(&self, message_type: u16, flags: u16) -> NetlinkMessage {
+        // struct fib_rule_hdr
+        let mut header = [0u8; 12];
+        header[0] = libc::AF_INET as u8;
+        header[1] = 32;
+        header[4] = if self.table < 256 {
+            self.table as u8
+        } else {
+            RT_TABLE_UNSPEC
+        };
+        header[7] = FR_ACT_TO_TBL;
+
+        // struct fib_rule_port_range
+        let mut port_range = [0u8; 4];
+        port_range[0..2].copy_from_slice(&self.port.to_ne_bytes());
+        port_range[2..4].copy_from_slice(&self.port.to_ne_bytes());
+
+        let mut message = NetlinkMessage::new(message_type, flags);
+        message
+            .push(&header)
+            .attr(FRA_DST, &self.destination.octets())
+            .attr_u32(FRA_TABLE, self.table)
+            .attr(FRA_IP_PROTO, &[self.protocol])
+            .attr(FRA_DPORT_RANGE, &port_range);
+        message
+    }
+}
+
+pub async fn add_rule(rule: &PortRule) -> Result<()> {
+    route_request(
+        "add rule",
+        rule.message(RTM_NEWRULE, NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL),
+    )
+    .await?;
+    Ok(())
+}
+
+pub async fn delete_rule(rule: &PortRule) -> Result<()> {
+    route_request("delete rule", rule.message(RTM_DELRULE, NLM_F_ACK)).await?;
+    Ok(())
+}
+
+/// Subscription to the address and route changes of both families
+pub struct NetlinkMonitor {
+    socket: NetlinkSocket,
+    buf: Vec<u8>,
+}
+
+impl NetlinkMonitor {
+    pub fn new() -> Result<Self> {
+        let groups = RTMGRP_IPV4_IFADDR | RTMGRP_IPV4_ROUTE | RTMGRP_IPV6_IFADDR | RTMGRP_IPV6_ROUTE;
+        Ok(Self {
+            socket: NetlinkSocket::new(libc::NETLINK_ROUTE, groups)?,
+            buf: vec![0u8; RECV_BUFFER_SIZE],
+        })
+    }
+
+    /// Wait for the next notification, the contents are not decoded
+    pub async fn next(&mut self) -> Result<()> {
+        self.socket.recv(&mut self.buf).await?;
+        Ok(())
+    }
+}
+
+/// Algorithm of the XFRM state with the key, the bit lengths are passed to the kernel
+#[derive(Debug, Clone, PartialEq)]
+pub enum XfrmAlgorithm<'a> {
+    Aead {
+        name: &'a str,
+        key: &'a [u8],
+        icv_bits: u32,
+    },
+    AuthTrunc {
+        name: &'a str,
+        key: &'a [u8],
+        trunc_bits: u32,
+    },
+    Crypt {
+        name: &'a str,
+        key: &'a [u8],
+    },
+}
+
+impl XfrmAlgorithm<'_> {
+    // struct xfrm_algo, xfrm_algo_aead or xfrm_algo_auth
+    fn encode(&self) -> (u16, Vec<u8>) {
+        let (attr_type, name, key, extra) = match *self {
+            Self::Aead { name, key, icv_bits } => (XFRMA_ALG_AEAD, name, key, Some(icv_bits)),
+            Self::AuthTrunc { name, key, trunc_bits } => (XFRMA_ALG_AUTH_TRUNC, name, key, Some(trunc_bits)),
+            Self::Crypt { name, key } => (XFRMA_ALG_CRYPT, name, key, None),
+        };
+
+        let mut data = vec![0u8; XFRM_ALG_NAME_LEN];
+        let name_len = name.len().min(XFRM_ALG_NAME_LEN - 1);
+        data[..name_len].copy_from_slice(&name.as_bytes()[..name_len]);
+        data.extend_from_slice(&((key.len() * 8) as u32).to_ne_bytes());
+        if let Some(extra) = extra {
+            data.extend_from_slice(&extra.to_ne_bytes());
+        }
+        data.extend_from_slice(key);
+
+        (attr_type, data)
+    }
+}
+
+/// Tunnel mode ESP state encapsulated into UDP
+#[derive(Debug, Clone, PartialEq)]
+pub struct XfrmStateInfo<'a> {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub spi: u32,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub if_id: u32,
+    pub algorithms: Vec<XfrmAlgorithm<'a>>,
+}
+
+impl XfrmStateInfo<'_> {
+    // struct xfrm_usersa_info
+    fn usersa_info(&self) -> [u8; XFRM_USERSA_INFO_LEN] {
+        let mut info = [0u8; XFRM_USERSA_INFO_LEN];
+
+        // the selector is left empty, XFRM_STATE_AF_UNSPEC makes it match the inner traffic of any family
+        info[56..72].copy_from_slice(&xfrm_address(self.dst));
+        info[72..76].copy_from_slice(&self.spi.to_be_bytes());
+        info[76] = IPPROTO_ESP;
+        info[80..96].copy_from_slice(&xfrm_address(self.src));
+
+        // byte and packet limits of the lifetime config
+        for offset in (96..128).step_by(8) {
+            info[offset..offset + 8].copy_from_slice(&XFRM_INF.to_ne_bytes());
+        }
+
+        info[212..214].copy_from_slice(&(family(self.dst) as u16).to_ne_bytes());
+        info[214] = XFRM_MODE_TUNNEL;
+        info[216] = XFRM_STATE_AF_UNSPEC;
+        info
+    }
+
+    // struct xfrm_encap_tmpl, the original address is not used for ESP in UDP
+    fn encap_tmpl(&self) -> [u8; XFRM_ENCAP_TMPL_LEN] {
+        let mut encap = [0u8; XFRM_ENCAP_TMPL_LEN];
+        encap[0..2].copy_from_slice(&UDP_ENCAP_ESPINUDP.to_ne_bytes());
+        encap[2..4].copy_from_slice(&self.src_port.to_be_bytes());
+        encap[4..6].copy_from_slice(&self.dst_port.to_be_bytes());
+        encap
+    }
+
+    fn message(&self) -> NetlinkMessage {
+        let mut message = NetlinkMessage::new(XFRM_MSG_NEWSA, NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL);
+        message.push(&self.usersa_info());
+        for algorithm in &self.algorithms {
+            let (attr_type, data) = algorithm.encode();
+            message.attr(attr_type, &data);
+        }
+        message
+            .attr(XFRMA_ENCAP, &self.encap_tmpl())
+            .attr_u32(XFRMA_IF_ID, self.if_id);
+        message
+    }
+}
+
+/// Traffic counters of the XFRM state
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct XfrmStateStats {
+    pub bytes: u64,
+    pub packets: u64,
+    pub errors: u64,
+}
+
+impl XfrmStateStats {
+    // current lifetime and the replay and integrity failures of xfrm_usersa_info
+    fn parse(info: &[u8]) -> Option<Self> {
+        if info.len() < XFRM_USERSA_INFO_LEN {
+            return None;
+        }
+
+        let errors = (192..204)
+            .step_by(4)
+            .filter_map(|offset| parse_u32(&info[offset..]))
+            .map(u64::from)
+            .sum();
+
+        Some(Self {
+            bytes: parse_u64(&info[160..])?,
+            packets: parse_u64(&info[168..])?,
+            errors,
+        })
+    }
+}
+
+// struct xfrm_usersa_id with the source address attribute
+fn usersa_id_message(message_type: u16, src: IpAddr, dst: IpAddr, spi: u32) -> NetlinkMessage {
+    let mut id = [0u8; XFRM_USERSA_ID_LEN];
+    id[0..16].copy_from_slice(&xfrm_address(dst));
+    id[16..20].copy_from_slice(&spi.to_be_bytes());
+    id[20..22].copy_from_slice(&(family(dst) as u16).to_ne_bytes());
+    id[22] = IPPROTO_ESP;
+
+    let mut message = NetlinkMessage::new(message_type, NLM_F_ACK);
+    message.push(&id).attr(XFRMA_SRCADDR, &xfrm_address(src));
+    message
+}
+
+pub async fn add_xfrm_state(state: &XfrmStateInfo<'_>) -> Result<()> {
+    xfrm_request("add XFRM state", state.message()).await?;
+    Ok(())
+}
+
+pub async fn delete_xfrm_state(src: IpAddr, dst: IpAddr, spi: u32) -> Result<()> {
+    xfrm_request("delete XFRM state", usersa_id_message(XFRM_MSG_DELSA, src, dst, spi)).await?;
+    Ok(())
+}
+
+pub async fn get_xfrm_state_stats(src: IpAddr, dst: IpAddr, spi: u32) -> Result<XfrmStateStats> {
+    xfrm_request("get XFRM state", usersa_id_message(XFRM_MSG_GETSA, src, dst, spi))
+        .await?
+        .iter()
+        .filter(|r| r.message_type == XFRM_MSG_NEWSA)
+        .find_map(|r| XfrmStateStats::parse(&r.payload))
+        .ok_or(NetlinkError::Malformed("XFRM state"))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum XfrmPolicyDir {
+    In = 0,
+    Out = 1,
+}
+
+// struct xfrm_selector for any IPv4 traffic
+fn any_ipv4_selector() -> [u8; XFRM_SELECTOR_LEN] {
+    let mut selector = [0u8; XFRM_SELECTOR_LEN];
+    selector[40..42].copy_from_slice(&(libc::AF_INET as u16).to_ne_bytes());
+    selector
+}
+
+/// Policy which sends all IPv4 traffic of the XFRM interface through the tunnel between the given addresses
+pub async fn add_xfrm_policy(dir: XfrmPolicyDir, src: IpAddr, dst: IpAddr, if_id: u32) -> Result<()> {
+    // struct xfrm_userpolicy_info
+    let mut info = [0u8; XFRM_USERPOLICY_INFO_LEN];
+    info[..XFRM_SELECTOR_LEN].copy_from_slice(&any_ipv4_selector());
+    for offset in (56..88).step_by(8) {
+        info[offset..offset + 8].copy_from_slice(&XFRM_INF.to_ne_bytes());
+    }
+    info[160] = dir as u8;
+
+    // struct xfrm_user_tmpl, all algorithms are allowed
+    let mut tmpl = [0u8; XFRM_USER_TMPL_LEN];
+    tmpl[0..16].copy_from_slice(&xfrm_address(dst));
+    tmpl[20] = IPPROTO_ESP;
+    tmpl[24..26].copy_from_slice(&(family(dst) as u16).to_ne_bytes());
+    tmpl[28..44].copy_from_slice(&xfrm_address(src));
+    tmpl[48] = XFRM_MODE_TUNNEL;
+    tmpl[52..64].fill(0xff);
+
+    let mut message = NetlinkMessage::new(XFRM_MSG_NEWPOLICY, NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL);
+    message.push(&info).attr(XFRMA_TMPL, &tmpl).attr_u32(XFRMA_IF_ID, if_id);
+
+    xfrm_request("add XFRM policy", message).await?;
+    Ok(())
+}
+
+pub async fn delete_xfrm_policy(dir: XfrmPolicyDir, if_id: u32) -> Result<()> {
+    // struct xfrm_userpolicy_id
+    let mut id = [0u8; XFRM_USERPOLICY_ID_LEN];
+    id[..XFRM_SELECTOR_LEN].copy_from_slice(&any_ipv4_selector());
+    id[60] = dir as u8;
+
+    let mut message = NetlinkMessage::new(XFRM_MSG_DELPOLICY, NLM_F_ACK);
+    message.push(&id).attr_u32(XFRMA_IF_ID, if_id);
+
+    xfrm_request("delete XFRM policy", message).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_encoding() {
+        let mut message = NetlinkMessage::new(RTM_NEWLINK, NLM_F_ACK);
+        message
+            .push(&ifinfomsg(0, 0, 0))
+            .attr_str(IFLA_IFNAME, "snx")
+            .begin_nested(IFLA_LINKINFO)
+            .attr_str(IFLA_INFO_KIND, "xfrm")
+            .end_nested();
+        let data = message.finish(7);
+
+        assert_eq!(u32::from_ne_bytes(data[0..4].try_into().unwrap()) as usize, data.len());
+        assert_eq!(u16::from_ne_bytes([data[6], data[7]]), NLM_F_REQUEST | NLM_F_ACK);
+        assert_eq!(u32::from_ne_bytes(data[8..12].try_into().unwrap()), 7);
+
+        let attrs = attributes(&data[NLMSG_HEADER_LEN..], 16).collect::<Vec<_>>();
+        assert_eq!(attrs[0], (IFLA_IFNAME, &b"snx\0"[..]));
+        assert_eq!(attrs[1].0, IFLA_LINKINFO);
+        assert_eq!(
+            Attributes { data: attrs[1].1 }.collect::<Vec<_>>(),
+            vec![(IFLA_INFO_KIND, &b"xfrm\0"[..])]
+        );
+    }
+
+    #[test]
+    fn test_parse_replies() {
+        let mut reply = NetlinkMessage::new(RTM_NEWROUTE, 0);
+        reply
+            .push(&rtmsg(libc::AF_INET as u8, 32, RT_TABLE_MAIN, 0))
+            .attr(RTA_DST, &[10, 0, 0, 1])
+            .attr_u32(RTA_OIF, 2)
+            .attr(RTA_PREFSRC, &[192, 168, 1, 10])
+            .begin_nested(RTA_METRICS)
+            .attr_u32(RTAX_MTU, 1400)
+            .end_nested();
+
+        let mut ack = NetlinkMessage::new(NLMSG_ERROR, 0);
+        ack.push(&0i32.to_ne_bytes());
+
+        let mut data = reply.finish(5);
+        data.extend(ack.finish(5));
+
+        let mut replies = Vec::new();
+        assert!(parse_replies(&data, 5, "get route", &mut replies).unwrap());
+        assert_eq!(replies.len(), 1);
+        assert_eq!(
+            RouteInfo::parse(&replies[0].payload),
+            Some(RouteInfo {
+                table: RT_TABLE_MAIN,
+                destination: Some(Ipv4Addr::new(10, 0, 0, 1).into()),
+                dst_len: 32,
+                index: Some(2),
+                source: Some(Ipv4Addr::new(192, 168, 1, 10).into()),
+                mtu: Some(1400),
+            })
+        );
+
+        let mut error = NetlinkMessage::new(NLMSG_ERROR, 0);
+        error.push(&(-libc::EEXIST).to_ne_bytes());
+        let error = parse_replies(&error.finish(6), 6, "add route", &mut replies).unwrap_err();
+        assert!(error.is_exists());
+        assert_eq!(error.to_string(), "add route failed: File exists (os error 17)");
+    }
+
+    #[test]
+    fn test_xfrm_state_encoding() {
+        let key = [0xaa; 20];
+        let state = XfrmStateInfo {
+            src: Ipv4Addr::new(192, 168, 1, 10).into(),
+            dst: "2001:db8::1".parse().unwrap(),
+            spi: 0x12345678,
+            src_port: 41000,
+            dst_port: 4500,
+            if_id: 0x1234,
+            algorithms: vec![XfrmAlgorithm::Aead {
+                name: "rfc4106(gcm(aes))",
+                key: &key,
+                icv_bits: 128,
+            }],
+        };
+
+        let info = state.usersa_info();
+        assert_eq!(&info[72..76], &[0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(&info[80..84], &[192, 168, 1, 10]);
+        assert_eq!(u16::from_ne_bytes([info[212], info[213]]), libc::AF_INET6 as u16);
+
+        let (attr_type, data) = state.algorithms[0].encode();
+        assert_eq!(attr_type, XFRMA_ALG_AEAD);
+        assert_eq!(&data[..17], b"rfc4106(gcm(aes))");
+        assert_eq!(u32::from_ne_bytes(data[64..68].try_into().unwrap()), 160);
+        assert_eq!(u32::from_ne_bytes(data[68..72].try_into().unwrap()), 128);
+        assert_eq!(&data[72..], &key);
+
+        assert_eq!(&state.encap_tmpl()[2..6], &[0xa0, 0x28, 0x11, 0x94]);
+    }
+
+    #[test]
+    fn test_parse_xfrm_state_stats() {
+        let mut info = [0u8; XFRM_USERSA_INFO_LEN];
+        info[160..168].copy_from_slice(&1234567u64.to_ne_bytes());
+        info[168..176].copy_from_slice(&890u64.to_ne_bytes());
+        info[192..196].copy_from_slice(&1u32.to_ne_bytes());
+        info[196..200].copy_from_slice(&2u32.to_ne_bytes());
+        info[200..204].copy_from_slice(&3u32.to_ne_bytes());
+
+        assert_eq!(
+            XfrmStateStats::parse(&info),
+            Some(XfrmStateStats {
+                bytes: 1234567,
+                packets: 890,
+                errors: 6
+            })
+        );
+        assert_eq!(XfrmStateStats::parse(&info[..100]), None);
+    }
+}
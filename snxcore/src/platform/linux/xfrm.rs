@@ -1,68 +1,74 @@
-use std::{net::Ipv4Addr, sync::Arc};
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
+use anyhow::anyhow;
 use ipnet::Ipv4Net;
-use isakmp::model::{EspAuthAlgorithm, EspCryptMaterial, TransformId};
+use isakmp::model::EspCryptMaterial;
 use rand::random;
-use tracing::{debug, trace};
+use tokio::task::JoinHandle;
+use tracing::{debug, trace, warn};
 
 use crate::{
-    model::{params::TunnelParams, IpsecSession},
-    platform::{self, IpsecConfigurator},
+    happy_eyeballs,
+    model::{params::TunnelParams, IpsecSession, TunnelStatistics},
+    mtu,
+    platform::{self, DnsConfig, IpsecConfigurator},
+    transform::EspTransform,
     util,
 };
 
-async fn iproute2(args: &[&str]) -> anyhow::Result<String> {
-    util::run_command("ip", args).await
-}
+use super::netlink::{self, XfrmAlgorithm, XfrmPolicyDir, XfrmStateInfo, XfrmStateStats};
+
+// the gateway may still use the previous inbound SA for the packets in flight after rekeying
+const REKEY_GRACE_PERIOD: Duration = Duration::from_secs(30);
 
 struct XfrmLink<'a> {
     name: &'a str,
     if_id: u32,
     address: Ipv4Net,
+    mtu: u16,
+    unmanaged: bool,
 }
 
 impl<'a> XfrmLink<'a> {
+    async fn set_sysctl(&self, option: &str, value: &str) -> anyhow::Result<()> {
+        let path = format!("/proc/sys/net/ipv4/conf/{}/{}", self.name, option);
+        tokio::fs::write(&path, value)
+            .await
+            .map_err(|e| anyhow!("Cannot write {}: {}", path, e))
+    }
+
     async fn add(&self) -> anyhow::Result<()> {
         let _ = self.delete().await;
 
-        iproute2(&[
-            "link",
-            "add",
-            self.name,
-            "type",
-            "xfrm",
-            "if_id",
-            &self.if_id.to_string(),
-        ])
-        .await?;
-
-        platform::unmanage_device(self.name).await;
-
-        let opt = format!("net.ipv4.conf.{}.disable_policy=1", self.name);
-        util::run_command("sysctl", ["-qw", &opt]).await?;
-
-        let opt = format!("net.ipv4.conf.{}.rp_filter=0", self.name);
-        util::run_command("sysctl", ["-qw", &opt]).await?;
+        netlink::add_xfrm_link(self.name, self.if_id, self.mtu).await?;
 
-        let opt = format!("net.ipv4.conf.{}.forwarding=1", self.name);
-        util::run_command("sysctl", ["-qw", &opt]).await?;
+        if self.unmanaged {
+            platform::unmanage_device(self.name).await;
+        }
 
-        iproute2(&["link", "set", self.name, "up"]).await?;
+        self.set_sysctl("disable_policy", "1").await?;
+        self.set_sysctl("rp_filter", "0").await?;
+        self.set_sysctl("forwarding", "1").await?;
 
-        iproute2(&["addr", "add", &self.address.to_string(), "dev", self.name]).await?;
+        netlink::set_link_up(self.name).await?;
+        netlink::add_address(self.name, self.address).await?;
 
         Ok(())
     }
 
     async fn delete(&self) -> anyhow::Result<()> {
-        iproute2(&["link", "del", "name", self.name]).await?;
+        netlink::delete_link(self.name).await?;
         Ok(())
     }
 }
 
 struct XfrmState<'a> {
-    src: Ipv4Addr,
-    dst: Ipv4Addr,
+    src: IpAddr,
+    dst: IpAddr,
     src_port: u16,
     dst_port: u16,
     if_id: u32,
@@ -70,128 +76,137 @@ struct XfrmState<'a> {
 }
 
 impl<'a> XfrmState<'a> {
-    fn auth_alg_as_xfrm_name(&self) -> &'static str {
-        match self.params.auth_algorithm {
-            EspAuthAlgorithm::HmacSha96 | EspAuthAlgorithm::HmacSha160 => "hmac(sha1)",
-            EspAuthAlgorithm::HmacSha256 | EspAuthAlgorithm::HmacSha256v2 => "hmac(sha256)",
-            EspAuthAlgorithm::Other(_) => "",
-        }
-    }
-    fn enc_alg_as_xfrm_name(&self) -> &'static str {
-        match self.params.transform_id {
-            TransformId::EspAesCbc => "cbc(aes)",
-            TransformId::Esp3Des => "cbc(des3_ede)",
-            _ => "",
-        }
+    // AEAD ciphers are configured with a single "aead" algorithm, the others with "auth-trunc" and "enc" pair.
+    // Salt or nonce is passed to the kernel as the trailing part of the encryption key.
+    fn algorithms(&self) -> anyhow::Result<Vec<XfrmAlgorithm<'a>>> {
+        let transform = EspTransform::new(self.params)?;
+        let icv_bits = (transform.icv_len * 8) as u32;
+
+        let crypt_name = transform.encryption.xfrm_name();
+        let crypt_key = &self.params.sk_e[..];
+
+        let algorithms = match transform.integrity {
+            None => vec![XfrmAlgorithm::Aead {
+                name: crypt_name,
+                key: crypt_key,
+                icv_bits,
+            }],
+            Some(integrity) => vec![
+                XfrmAlgorithm::AuthTrunc {
+                    name: integrity.xfrm_name(),
+                    key: &self.params.sk_a[..],
+                    trunc_bits: icv_bits,
+                },
+                XfrmAlgorithm::Crypt {
+                    name: crypt_name,
+                    key: crypt_key,
+                },
+            ],
+        };
+
+        Ok(algorithms)
     }
 
     async fn add(&self) -> anyhow::Result<()> {
-        let authkey = format!("0x{}", hex::encode(&self.params.sk_a));
-        let enckey = format!("0x{}", hex::encode(&self.params.sk_e));
-        let trunc_len = (self.params.auth_algorithm.hash_len() * 8).to_string();
-
-        let spi = format!("0x{:x}", self.params.spi);
-        let src = self.src.to_string();
-        let dst = self.dst.to_string();
-
-        iproute2(&[
-            "xfrm",
-            "state",
-            "add",
-            "src",
-            &src,
-            "dst",
-            &dst,
-            "proto",
-            "esp",
-            "spi",
-            &spi,
-            "mode",
-            "tunnel",
-            "flag",
-            "af-unspec",
-            "auth-trunc",
-            self.auth_alg_as_xfrm_name(),
-            &authkey,
-            &trunc_len,
-            "enc",
-            self.enc_alg_as_xfrm_name(),
-            &enckey,
-            "if_id",
-            &self.if_id.to_string(),
-            "encap",
-            "espinudp",
-            &self.src_port.to_string(),
-            &self.dst_port.to_string(),
-            "0.0.0.0",
-        ])
-        .await?;
+        let state = XfrmStateInfo {
+            src: self.src,
+            dst: self.dst,
+            spi: self.params.spi,
+            src_port: self.src_port,
+            dst_port: self.dst_port,
+            if_id: self.if_id,
+            algorithms: self.algorithms()?,
+        };
+
+        netlink::add_xfrm_state(&state).await?;
 
         Ok(())
     }
 
     async fn delete(&self) -> anyhow::Result<()> {
-        let src = self.src.to_string();
-        let dst = self.dst.to_string();
-        let spi = format!("0x{:x}", self.params.spi);
+        netlink::delete_xfrm_state(self.src, self.dst, self.params.spi).await?;
+        Ok(())
+    }
 
-        iproute2(&[
-            "xfrm", "state", "del", "src", &src, "dst", &dst, "proto", "esp", "spi", &spi,
-        ])
-        .await?;
+    async fn stats(&self) -> anyhow::Result<XfrmStateStats> {
+        Ok(netlink::get_xfrm_state_stats(self.src, self.dst, self.params.spi).await?)
+    }
+}
 
-        Ok(())
+impl XfrmStateStats {
+    fn add_to(&self, stats: &mut TunnelStatistics, sent: bool) {
+        if sent {
+            stats.bytes_sent += self.bytes;
+            stats.packets_sent += self.packets;
+        } else {
+            stats.bytes_received += self.bytes;
+            stats.packets_received += self.packets;
+        }
+        stats.errors += self.errors;
     }
 }
 
+// traffic of the deleted states and the session which is being retired after rekeying
+#[derive(Default)]
+struct XfrmCounters {
+    base: TunnelStatistics,
+    retired: Option<IpsecSession>,
+}
+
+async fn add_session_stats(
+    stats: &mut TunnelStatistics,
+    sent: &XfrmState<'_>,
+    received: &XfrmState<'_>,
+) -> anyhow::Result<()> {
+    let sent = sent.stats().await?;
+    let received = received.stats().await?;
+    sent.add_to(stats, true);
+    received.add_to(stats, false);
+    Ok(())
+}
+
+// the final counters of the retired states are kept before they are deleted
+async fn retire_states(sent: XfrmState<'_>, received: XfrmState<'_>, counters: &Mutex<XfrmCounters>) {
+    let mut stats = TunnelStatistics::default();
+    let result = add_session_stats(&mut stats, &sent, &received).await;
+
+    {
+        let mut counters = counters.lock().unwrap();
+        if result.is_ok() {
+            counters.base.bytes_sent += stats.bytes_sent;
+            counters.base.bytes_received += stats.bytes_received;
+            counters.base.packets_sent += stats.packets_sent;
+            counters.base.packets_received += stats.packets_received;
+            counters.base.errors += stats.errors;
+        }
+        counters.retired = None;
+    }
+
+    debug!(
+        "Removing previous XFRM states, SPI: {:04x}, {:04x}",
+        sent.params.spi, received.params.spi
+    );
+
+    let _ = sent.delete().await;
+    let _ = received.delete().await;
+}
+
 struct XfrmPolicy {
-    dir: PolicyDir,
-    src: Ipv4Addr,
-    dst: Ipv4Addr,
+    dir: XfrmPolicyDir,
+    src: IpAddr,
+    dst: IpAddr,
     if_id: u32,
 }
 
 impl XfrmPolicy {
+    // the tunneled traffic is always IPv4, even when the outer addresses are IPv6
     async fn add(&self) -> anyhow::Result<()> {
-        iproute2(&[
-            "xfrm",
-            "policy",
-            "add",
-            "dir",
-            self.dir.as_str(),
-            "tmpl",
-            "src",
-            &self.src.to_string(),
-            "dst",
-            &self.dst.to_string(),
-            "proto",
-            "esp",
-            "mode",
-            "tunnel",
-            "if_id",
-            &self.if_id.to_string(),
-        ])
-        .await?;
-
+        netlink::add_xfrm_policy(self.dir, self.src, self.dst, self.if_id).await?;
         Ok(())
     }
 
     async fn delete(&self) -> anyhow::Result<()> {
-        iproute2(&[
-            "xfrm",
-            "policy",
-            "del",
-            "dir",
-            self.dir.as_str(),
-            "if_id",
-            &self.if_id.to_string(),
-            "src",
-            "0.0.0.0/0",
-            "dst",
-            "0.0.0.0/0",
-        ])
-        .await?;
-
+        netlink::delete_xfrm_policy(self.dir, self.if_id).await?;
         Ok(())
     }
 }
@@ -203,30 +218,20 @@ enum CommandType {
     Delete,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-enum PolicyDir {
-    In,
-    Out,
-}
-
-impl PolicyDir {
-    fn as_str(self) -> &'static str {
-        match self {
-            PolicyDir::In => "in",
-            PolicyDir::Out => "out",
-        }
-    }
-}
-
 pub struct XfrmConfigurator {
     name: String,
     tunnel_params: Arc<TunnelParams>,
     ipsec_session: IpsecSession,
-    source_ip: Ipv4Addr,
+    source_ip: IpAddr,
     if_id: u32,
     src_port: u16,
-    dest_ip: Ipv4Addr,
+    dest_ip: IpAddr,
+    dest_port: u16,
+    keepalive_ip: Ipv4Addr,
     subnets: Vec<Ipv4Net>,
+    counters: Arc<Mutex<XfrmCounters>>,
+    retire_task: Option<JoinHandle<()>>,
+    mtu: u16,
 }
 
 impl XfrmConfigurator {
@@ -234,7 +239,9 @@ impl XfrmConfigurator {
         tunnel_params: Arc<TunnelParams>,
         ipsec_session: IpsecSession,
         src_port: u16,
-        dest_ip: Ipv4Addr,
+        dest_ip: IpAddr,
+        dest_port: u16,
+        keepalive_ip: Ipv4Addr,
         subnets: Vec<Ipv4Net>,
     ) -> anyhow::Result<Self> {
         let if_id = random();
@@ -248,11 +255,16 @@ impl XfrmConfigurator {
             name,
             tunnel_params,
             ipsec_session,
-            source_ip: Ipv4Addr::new(0, 0, 0, 0),
+            source_ip: happy_eyeballs::unspecified(dest_ip),
             dest_ip,
+            dest_port,
+            keepalive_ip,
             if_id,
             src_port,
             subnets,
+            counters: Arc::new(Mutex::new(XfrmCounters::default())),
+            retire_task: None,
+            mtu: mtu::DEFAULT_LINK_MTU,
         })
     }
 
@@ -262,6 +274,19 @@ impl XfrmConfigurator {
             if_id: self.if_id,
             address: Ipv4Net::with_netmask(self.ipsec_session.address, self.ipsec_session.netmask)
                 .unwrap_or_else(|_| Ipv4Net::from(self.ipsec_session.address)),
+            mtu: self.mtu,
+            unmanaged: !self.tunnel_params.keep_device_managed,
+        }
+    }
+
+    fn new_xfrm_state<'a>(&self, src: IpAddr, dst: IpAddr, params: &'a EspCryptMaterial) -> XfrmState<'a> {
+        XfrmState {
+            src,
+            dst,
+            src_port: self.src_port,
+            dst_port: self.dest_port,
+            if_id: self.if_id,
+            params,
         }
     }
 
@@ -269,21 +294,71 @@ impl XfrmConfigurator {
         self.new_xfrm_link().add().await
     }
 
+    async fn detect_mtu(&self) -> u16 {
+        if let Some(mtu) = self.tunnel_params.mtu {
+            return mtu;
+        }
+
+        let link_mtu = match platform::get_link_mtu(self.dest_ip).await {
+            Ok(link_mtu) => link_mtu,
+            Err(e) => {
+                warn!("Cannot determine link MTU, using default: {}", e);
+                mtu::DEFAULT_LINK_MTU
+            }
+        };
+
+        mtu::esp_mtu(mtu::outer_link_mtu(link_mtu, self.dest_ip), &self.ipsec_session.esp_out)
+    }
+
+    // POSTROUTING sees both the locally originated and the forwarded traffic leaving via the tunnel device,
+    // the rule is checked first so that it is never duplicated or deleted when not present
+    async fn configure_mss_clamping(&self, command: CommandType) -> anyhow::Result<()> {
+        let iptables = |action: &'static str| {
+            util::run_command(
+                "iptables",
+                [
+                    "-w",
+                    "-t",
+                    "mangle",
+                    action,
+                    "POSTROUTING",
+                    "-o",
+                    &self.name,
+                    "-p",
+                    "tcp",
+                    "--tcp-flags",
+                    "SYN,RST",
+                    "SYN",
+                    "-j",
+                    "TCPMSS",
+                    "--clamp-mss-to-pmtu",
+                ],
+            )
+        };
+
+        let exists = iptables("-C").await.is_ok();
+
+        match command {
+            CommandType::Add if !exists => {
+                iptables("-A").await?;
+            }
+            CommandType::Delete if exists => {
+                iptables("-D").await?;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
     async fn configure_xfrm_state(
         &self,
         command: CommandType,
-        src: Ipv4Addr,
-        dst: Ipv4Addr,
+        src: IpAddr,
+        dst: IpAddr,
         params: &EspCryptMaterial,
     ) -> anyhow::Result<()> {
-        let state = XfrmState {
-            src,
-            dst,
-            src_port: self.src_port,
-            dst_port: 4500,
-            if_id: self.if_id,
-            params,
-        };
+        let state = self.new_xfrm_state(src, dst, params);
         match command {
             CommandType::Add => state.add().await?,
             CommandType::Delete => state.delete().await?,
@@ -295,9 +370,9 @@ impl XfrmConfigurator {
     async fn configure_xfrm_policy(
         &self,
         command: CommandType,
-        dir: PolicyDir,
-        src: Ipv4Addr,
-        dst: Ipv4Addr,
+        dir: XfrmPolicyDir,
+        src: IpAddr,
+        dst: IpAddr,
     ) -> anyhow::Result<()> {
         let policy = XfrmPolicy {
             dir,
@@ -313,6 +388,50 @@ impl XfrmConfigurator {
         Ok(())
     }
 
+    // completes the pending removal of the previous session states right away
+    async fn finish_retirement(&mut self) {
+        if let Some(task) = self.retire_task.take() {
+            task.abort();
+        }
+
+        let retired = self.counters.lock().unwrap().retired.clone();
+        if let Some(retired) = retired {
+            let sent = self.new_xfrm_state(self.source_ip, self.dest_ip, &retired.esp_out);
+            let received = self.new_xfrm_state(self.dest_ip, self.source_ip, &retired.esp_in);
+            retire_states(sent, received, &self.counters).await;
+        }
+    }
+
+    fn start_retirement(&mut self, previous: IpsecSession) {
+        self.counters.lock().unwrap().retired = Some(previous.clone());
+
+        let counters = self.counters.clone();
+        let (source_ip, dest_ip) = (self.source_ip, self.dest_ip);
+        let (src_port, dst_port, if_id) = (self.src_port, self.dest_port, self.if_id);
+
+        self.retire_task = Some(tokio::spawn(async move {
+            tokio::time::sleep(REKEY_GRACE_PERIOD).await;
+
+            let sent = XfrmState {
+                src: source_ip,
+                dst: dest_ip,
+                src_port,
+                dst_port,
+                if_id,
+                params: &previous.esp_out,
+            };
+            let received = XfrmState {
+                src: dest_ip,
+                dst: source_ip,
+                src_port,
+                dst_port,
+                if_id,
+                params: &previous.esp_in,
+            };
+            retire_states(sent, received, &counters).await;
+        }));
+    }
+
     async fn setup_xfrm_state_and_policies(&self) -> anyhow::Result<()> {
         self.configure_xfrm_state(
             CommandType::Add,
@@ -329,9 +448,9 @@ impl XfrmConfigurator {
         )
         .await?;
 
-        self.configure_xfrm_policy(CommandType::Add, PolicyDir::Out, self.source_ip, self.dest_ip)
+        self.configure_xfrm_policy(CommandType::Add, XfrmPolicyDir::Out, self.source_ip, self.dest_ip)
             .await?;
-        self.configure_xfrm_policy(CommandType::Add, PolicyDir::In, self.dest_ip, self.source_ip)
+        self.configure_xfrm_policy(CommandType::Add, XfrmPolicyDir::In, self.dest_ip, self.source_ip)
             .await?;
 
         Ok(())
@@ -350,22 +469,16 @@ impl XfrmConfigurator {
             }
         }
 
-        subnets.retain(|s| !s.contains(&self.dest_ip));
+        if let IpAddr::V4(dest_ip) = self.dest_ip {
+            subnets.retain(|s| !s.contains(&dest_ip));
+        }
 
         if !subnets.is_empty() {
             let _ = platform::add_routes(&subnets, &self.name, self.ipsec_session.address).await;
         }
 
-        let port = TunnelParams::IPSEC_KEEPALIVE_PORT.to_string();
-        let dst = self.dest_ip.to_string();
-
         // set up routing correctly so that keepalive packets are not wrapped into ESP
-        iproute2(&["route", "add", "table", &port, &dst, "dev", &self.name]).await?;
-
-        iproute2(&[
-            "rule", "add", "to", &dst, "ipproto", "udp", "dport", &port, "table", &port,
-        ])
-        .await?;
+        platform::add_keepalive_route(&self.name, self.keepalive_ip, TunnelParams::IPSEC_KEEPALIVE_PORT).await?;
 
         Ok(())
     }
@@ -374,23 +487,15 @@ impl XfrmConfigurator {
         if !self.tunnel_params.no_dns {
             debug!("Adding acquired DNS suffixes: {:?}", self.ipsec_session.domains);
             debug!("Adding provided DNS suffixes: {:?}", self.tunnel_params.search_domains);
-            let suffixes = self
-                .ipsec_session
-                .domains
-                .iter()
-                .map(|s| s.as_str())
-                .chain(self.tunnel_params.search_domains.iter().map(|s| s.as_ref()))
-                .filter(|&s| {
-                    !self
-                        .tunnel_params
-                        .ignore_search_domains
-                        .iter()
-                        .any(|d| d.to_lowercase() == s.to_lowercase())
-                });
-            let _ = platform::add_dns_suffixes(suffixes, &self.name).await;
-
-            let servers = self.ipsec_session.dns.iter().map(|server| server.to_string());
-            let _ = platform::add_dns_servers(servers, &self.name).await;
+            let config = DnsConfig::new(
+                &self.tunnel_params,
+                &self.ipsec_session.domains,
+                self.ipsec_session.dns.iter().map(|&server| server.into()).collect(),
+            );
+            let dns = platform::new_dns_configurator(&self.tunnel_params).await;
+            if let Err(e) = dns.configure(&self.name, &config).await {
+                warn!("Cannot configure DNS: {}", e);
+            }
         }
         Ok(())
     }
@@ -399,16 +504,26 @@ impl XfrmConfigurator {
 #[async_trait::async_trait]
 impl IpsecConfigurator for XfrmConfigurator {
     async fn configure(&mut self) -> anyhow::Result<()> {
-        self.source_ip = platform::get_default_ip().await?.parse()?;
+        self.source_ip = platform::get_source_ip(self.dest_ip).await?;
         debug!("Source IP: {}", self.source_ip);
         debug!("Target IP: {}", self.dest_ip);
 
         self.cleanup().await;
+
+        self.mtu = self.detect_mtu().await;
+        debug!("Tunnel MTU: {}", self.mtu);
+
         self.setup_xfrm_link().await?;
         self.setup_xfrm_state_and_policies().await?;
         self.setup_routing().await?;
         self.setup_dns().await?;
 
+        if self.tunnel_params.mss_clamp {
+            if let Err(e) = self.configure_mss_clamping(CommandType::Add).await {
+                warn!("Cannot enable MSS clamping: {}", e);
+            }
+        }
+
         Ok(())
     }
 
@@ -418,45 +533,39 @@ impl IpsecConfigurator for XfrmConfigurator {
             session.esp_in,
             session.esp_out
         );
-        let _ = self
-            .configure_xfrm_state(
-                CommandType::Delete,
-                self.source_ip,
-                self.dest_ip,
-                &self.ipsec_session.esp_out,
-            )
-            .await;
 
-        let _ = self
-            .configure_xfrm_state(
-                CommandType::Delete,
-                self.dest_ip,
-                self.source_ip,
-                &self.ipsec_session.esp_in,
-            )
-            .await;
+        // only one previous session is kept at a time
+        self.finish_retirement().await;
 
-        self.ipsec_session = session.clone();
+        // the new inbound SA is installed first, so that the gateway could switch to it at any moment.
+        // The kernel selects the most recently added outbound state, the traffic moves to the new SA right away.
+        self.configure_xfrm_state(CommandType::Add, self.dest_ip, self.source_ip, &session.esp_in)
+            .await?;
 
-        self.configure_xfrm_state(
-            CommandType::Add,
-            self.source_ip,
-            self.dest_ip,
-            &self.ipsec_session.esp_out,
-        )
-        .await?;
-        self.configure_xfrm_state(
-            CommandType::Add,
-            self.dest_ip,
-            self.source_ip,
-            &self.ipsec_session.esp_in,
-        )
-        .await?;
+        if let Err(e) = self
+            .configure_xfrm_state(CommandType::Add, self.source_ip, self.dest_ip, &session.esp_out)
+            .await
+        {
+            let _ = self
+                .configure_xfrm_state(CommandType::Delete, self.dest_ip, self.source_ip, &session.esp_in)
+                .await;
+            return Err(e);
+        }
+
+        // the previous states are removed after the grace period
+        let previous = std::mem::replace(&mut self.ipsec_session, session.clone());
+        self.start_retirement(previous);
 
         Ok(())
     }
 
     async fn cleanup(&mut self) {
+        self.finish_retirement().await;
+
+        if self.tunnel_params.mss_clamp {
+            let _ = self.configure_mss_clamping(CommandType::Delete).await;
+        }
+
         let _ = self
             .configure_xfrm_state(
                 CommandType::Delete,
@@ -476,21 +585,135 @@ impl IpsecConfigurator for XfrmConfigurator {
             .await;
 
         let _ = self
-            .configure_xfrm_policy(CommandType::Delete, PolicyDir::Out, self.source_ip, self.dest_ip)
+            .configure_xfrm_policy(CommandType::Delete, XfrmPolicyDir::Out, self.source_ip, self.dest_ip)
             .await;
 
         let _ = self
-            .configure_xfrm_policy(CommandType::Delete, PolicyDir::In, self.dest_ip, self.source_ip)
+            .configure_xfrm_policy(CommandType::Delete, XfrmPolicyDir::In, self.dest_ip, self.source_ip)
             .await;
 
+        if !self.tunnel_params.no_dns {
+            platform::new_dns_configurator(&self.tunnel_params)
+                .await
+                .revert(&self.name)
+                .await;
+        }
+
         let _ = self.new_xfrm_link().delete().await;
 
-        let dst = self.dest_ip.to_string();
-        let port = TunnelParams::IPSEC_KEEPALIVE_PORT.to_string();
+        platform::delete_keepalive_route(self.keepalive_ip, TunnelParams::IPSEC_KEEPALIVE_PORT).await;
+    }
+
+    async fn statistics(&self) -> anyhow::Result<TunnelStatistics> {
+        let (mut stats, retired) = {
+            let counters = self.counters.lock().unwrap();
+            (counters.base.clone(), counters.retired.clone())
+        };
+
+        add_session_stats(
+            &mut stats,
+            &self.new_xfrm_state(self.source_ip, self.dest_ip, &self.ipsec_session.esp_out),
+            &self.new_xfrm_state(self.dest_ip, self.source_ip, &self.ipsec_session.esp_in),
+        )
+        .await?;
+
+        if let Some(retired) = retired {
+            let _ = add_session_stats(
+                &mut stats,
+                &self.new_xfrm_state(self.source_ip, self.dest_ip, &retired.esp_out),
+                &self.new_xfrm_state(self.dest_ip, self.source_ip, &retired.esp_in),
+            )
+            .await;
+        }
+
+        Ok(stats)
+    }
+
+    fn device_name(&self) -> &str {
+        &self.name
+    }
+
+    fn mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    async fn set_mtu(&mut self, mtu: u16) -> anyhow::Result<()> {
+        platform::set_link_mtu(&self.name, mtu).await?;
+        self.mtu = mtu;
+        Ok(())
+    }
+
+    // XFRM states and policies are bound to the source address, they are recreated with the new one
+    async fn reanchor(&mut self, source_ip: IpAddr) -> anyhow::Result<()> {
+        if source_ip == self.source_ip {
+            return Ok(());
+        }
+
+        debug!("Moving XFRM states from {} to {}", self.source_ip, source_ip);
 
-        let _ = iproute2(&[
-            "rule", "del", "to", &dst, "ipproto", "udp", "dport", &port, "table", &port,
-        ])
-        .await;
+        self.finish_retirement().await;
+
+        let sent = self.new_xfrm_state(self.source_ip, self.dest_ip, &self.ipsec_session.esp_out);
+        let received = self.new_xfrm_state(self.dest_ip, self.source_ip, &self.ipsec_session.esp_in);
+        retire_states(sent, received, &self.counters).await;
+
+        let _ = self
+            .configure_xfrm_policy(CommandType::Delete, XfrmPolicyDir::Out, self.source_ip, self.dest_ip)
+            .await;
+        let _ = self
+            .configure_xfrm_policy(CommandType::Delete, XfrmPolicyDir::In, self.dest_ip, self.source_ip)
+            .await;
+
+        self.source_ip = source_ip;
+
+        self.setup_xfrm_state_and_policies().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xfrm_state_stats() {
+        let state_stats = XfrmStateStats {
+            bytes: 1234567,
+            packets: 890,
+            errors: 6,
+        };
+
+        let mut stats = TunnelStatistics::default();
+        state_stats.add_to(&mut stats, true);
+        state_stats.add_to(&mut stats, false);
+        assert_eq!(stats.bytes_sent, 1234567);
+        assert_eq!(stats.packets_received, 890);
+        assert_eq!(stats.errors, 12);
+    }
+
+    #[test]
+    fn test_xfrm_algorithms() {
+        let params = EspCryptMaterial {
+            transform_id: isakmp::model::TransformId::EspAesGcm16,
+            sk_e: vec![0xaa; 20].into(),
+            ..Default::default()
+        };
+
+        let state = XfrmState {
+            src: Ipv4Addr::new(10, 0, 0, 1).into(),
+            dst: Ipv4Addr::new(10, 0, 0, 2).into(),
+            src_port: 4500,
+            dst_port: 4500,
+            if_id: 1,
+            params: &params,
+        };
+
+        assert_eq!(
+            state.algorithms().unwrap(),
+            vec![XfrmAlgorithm::Aead {
+                name: "rfc4106(gcm(aes))",
+                key: &[0xaa; 20],
+                icv_bits: 128
+            }]
+        );
     }
 }
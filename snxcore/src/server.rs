@@ -65,6 +65,21 @@ impl CommandServer {
                             TunnelEvent::Disconnected => {
                                 self.reset();
                             }
+                            TunnelEvent::RemoteDisconnected(code, message) => {
+                                warn!("Tunnel disconnected by the gateway, code: {}, message: {}", code, message);
+                                self.connection_status.last_disconnect_reason =
+                                    Some(format!("{} (code {})", message, code));
+                            }
+                            TunnelEvent::ChallengeRequired(session) if self.is_connected() => {
+                                // the tunnel keeps running while the user input is awaited
+                                if let SessionState::PendingChallenge(ref challenge) = session.state {
+                                    self.connection_status.mfa = Some(challenge.clone());
+                                }
+                                self.session = Some(session);
+                            }
+                            TunnelEvent::Statistics(stats) if self.is_connected() => {
+                                self.connection_status.statistics = Some(stats);
+                            }
                             _ => {}
                         }
                     }
@@ -119,7 +134,11 @@ impl CommandServer {
                     Ok(()) => TunnelServiceResponse::Ok,
                     Err(e) => {
                         warn!("{}", e);
-                        self.reset();
+                        if self.is_connected() {
+                            let _ = self.disconnect().await;
+                        } else {
+                            self.reset();
+                        }
                         TunnelServiceResponse::Error(e.to_string())
                     }
                 }
@@ -143,7 +162,20 @@ impl CommandServer {
         if let SessionState::PendingChallenge(ref challenge) = session.state {
             debug!("Pending multi-factor, awaiting for it");
             self.session = Some(session.clone());
-            self.connection_status = ConnectionStatus::mfa(challenge.clone());
+            if self.connection_status.connected_since.is_some() {
+                self.connection_status.mfa = Some(challenge.clone());
+            } else {
+                self.connection_status = ConnectionStatus::mfa(challenge.clone());
+            }
+            return Ok(());
+        }
+
+        // re-authentication of the running tunnel is completed, the device and routes are kept
+        if self.connection_status.connected_since.is_some() {
+            debug!("Handing over the re-authenticated session to the tunnel");
+            connector.update_session(session).await?;
+            self.session = None;
+            self.connection_status.mfa = None;
             return Ok(());
         }
 
@@ -201,10 +233,14 @@ impl CommandServer {
         Ok(())
     }
 
+    // the reason of the remote disconnect is kept to be reported in the status
     fn reset(&mut self) {
         self.session = None;
         self.connector = None;
-        self.connection_status = ConnectionStatus::disconnected();
+        self.connection_status = ConnectionStatus {
+            last_disconnect_reason: self.connection_status.last_disconnect_reason.take(),
+            ..ConnectionStatus::disconnected()
+        };
     }
 
     fn get_status(&self) -> &ConnectionStatus {
@@ -3,13 +3,66 @@ use crate::{
     ccc::CccHttpClient,
     model::{
         params::TunnelParams,
-        proto::{LoginFactor, ServerInfoResponse},
+        proto::{ConnectivityInfo, LoginFactor, ServerInfoResponse},
     },
+    proxy,
     sexpr::SExpression,
 };
 use cached::proc_macro::cached;
-use std::{collections::VecDeque, sync::Arc};
-use tracing::trace;
+use std::{collections::VecDeque, net::IpAddr, sync::Arc, time::Duration};
+use tracing::{debug, trace, warn};
+
+const DEFAULT_TCPT_PORT: u16 = 443;
+const DEFAULT_NATT_PORT: u16 = 4500;
+const REACHABILITY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Transport endpoints of the tunnel data plane
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransportEndpoints {
+    pub server_ip: Option<IpAddr>,
+    pub tcpt_port: u16,
+    pub natt_port: u16,
+}
+
+impl TransportEndpoints {
+    pub fn new(params: &TunnelParams, info: Option<&ConnectivityInfo>) -> Self {
+        let advertised_ip = info
+            .map(|i| i.server_ip)
+            .filter(|ip| !ip.is_unspecified())
+            .filter(|ip| {
+                if ip.is_loopback() {
+                    warn!("Ignoring loopback server IP advertised by the gateway: {}", ip);
+                }
+                !ip.is_loopback()
+            })
+            .map(IpAddr::V4);
+
+        Self {
+            server_ip: params.server_ip.or(advertised_ip),
+            tcpt_port: params
+                .tcpt_port
+                .or(info.map(|i| i.tcpt_port).filter(|&p| p != 0))
+                .unwrap_or(DEFAULT_TCPT_PORT),
+            natt_port: params
+                .natt_port
+                .or(info.map(|i| i.natt_port).filter(|&p| p != 0))
+                .unwrap_or(DEFAULT_NATT_PORT),
+        }
+    }
+
+    /// Advertised private addresses may belong to gateways behind NAT and must be probed before use
+    fn needs_reachability_check(&self, params: &TunnelParams) -> bool {
+        params.server_ip.is_none()
+            && matches!(self.server_ip, Some(IpAddr::V4(ip)) if ip.is_private() || ip.is_link_local())
+    }
+
+    /// Address to connect to: either the data-plane IP or the configured server name
+    pub fn server_address(&self, params: &TunnelParams) -> String {
+        self.server_ip
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| params.server_name.clone())
+    }
+}
 
 pub async fn get(params: &TunnelParams) -> anyhow::Result<ServerInfoResponse> {
     let client = CccHttpClient::new(Arc::new(params.clone()), None);
@@ -22,6 +75,38 @@ pub async fn get(params: &TunnelParams) -> anyhow::Result<ServerInfoResponse> {
         .try_into()
 }
 
+pub async fn get_transport_endpoints(params: &TunnelParams) -> TransportEndpoints {
+    let info = match get(params).await {
+        Ok(info) => Some(info.connectivity_info),
+        Err(e) => {
+            warn!("Cannot retrieve connectivity info, using defaults: {}", e);
+            None
+        }
+    };
+
+    let mut endpoints = TransportEndpoints::new(params, info.as_ref());
+
+    if let (true, Some(server_ip)) = (endpoints.needs_reachability_check(params), endpoints.server_ip) {
+        if let Err(e) = check_reachable(params, server_ip, endpoints.tcpt_port).await {
+            warn!(
+                "Ignoring private server IP {} advertised by the gateway, falling back to {}: {}",
+                server_ip, params.server_name, e
+            );
+            endpoints.server_ip = None;
+        }
+    }
+
+    debug!("Transport endpoints: {:?}", endpoints);
+
+    endpoints
+}
+
+async fn check_reachable(params: &TunnelParams, server_ip: IpAddr, port: u16) -> anyhow::Result<()> {
+    let address = server_ip.to_string();
+    tokio::time::timeout(REACHABILITY_TIMEOUT, proxy::connect(params, &address, port)).await??;
+    Ok(())
+}
+
 pub async fn get_mfa_prompts(params: &TunnelParams) -> anyhow::Result<VecDeque<String>> {
     let factors = get_login_factors(params).await?;
 
@@ -64,3 +149,61 @@ pub async fn get_login_factors(params: &TunnelParams) -> anyhow::Result<Vec<Logi
 
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, net::Ipv4Addr};
+
+    use super::*;
+
+    fn connectivity_info(server_ip: Ipv4Addr) -> ConnectivityInfo {
+        ConnectivityInfo {
+            default_authentication_method: "client_decide".to_owned(),
+            client_enabled: true,
+            supported_data_tunnel_protocols: vec!["IPSec".to_owned(), "SSL".to_owned()],
+            connectivity_type: "IPSec".to_owned(),
+            server_ip,
+            ipsec_transport: "auto_detect".to_owned(),
+            tcpt_port: 8443,
+            natt_port: 4501,
+            connect_with_certificate_url: "/clients/cert/".into(),
+            cookie_name: "CPCVPN_SESSION_ID".to_owned(),
+            internal_ca_fingerprint: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_transport_endpoints() {
+        let mut params = TunnelParams::default();
+
+        let endpoints = TransportEndpoints::new(&params, None);
+        assert_eq!(endpoints.server_ip, None);
+        assert_eq!(endpoints.tcpt_port, 443);
+        assert_eq!(endpoints.natt_port, 4500);
+
+        let info = connectivity_info(Ipv4Addr::new(203, 0, 113, 10));
+        let endpoints = TransportEndpoints::new(&params, Some(&info));
+        assert_eq!(endpoints.server_ip, Some(Ipv4Addr::new(203, 0, 113, 10).into()));
+        assert_eq!(endpoints.tcpt_port, 8443);
+        assert_eq!(endpoints.natt_port, 4501);
+
+        assert!(!endpoints.needs_reachability_check(&params));
+
+        let info = connectivity_info(Ipv4Addr::new(127, 0, 0, 1));
+        assert_eq!(TransportEndpoints::new(&params, Some(&info)).server_ip, None);
+
+        let info = connectivity_info(Ipv4Addr::new(10, 0, 0, 1));
+        let endpoints = TransportEndpoints::new(&params, Some(&info));
+        assert_eq!(endpoints.server_ip, Some(Ipv4Addr::new(10, 0, 0, 1).into()));
+        assert!(endpoints.needs_reachability_check(&params));
+
+        params.server_ip = Some(Ipv4Addr::new(198, 51, 100, 1).into());
+        params.tcpt_port = Some(10443);
+        params.natt_port = Some(14500);
+        let endpoints = TransportEndpoints::new(&params, Some(&info));
+        assert_eq!(endpoints.server_ip, Some(Ipv4Addr::new(198, 51, 100, 1).into()));
+        assert_eq!(endpoints.tcpt_port, 10443);
+        assert_eq!(endpoints.natt_port, 14500);
+        assert!(!endpoints.needs_reachability_check(&params));
+    }
+}
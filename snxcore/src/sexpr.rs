@@ -352,6 +352,17 @@ mod tests {
         println!("{expr}");
     }
 
+    #[test]
+    fn test_parse_disconnect() {
+        let data = "(disconnect :code (28) :message (\"User has disconnected.\"))";
+        let expr = data.parse::<SExpression>().unwrap();
+        assert_eq!(expr.object_name(), Some("disconnect"));
+
+        let disconnect = expr.try_into::<crate::model::proto::DisconnectRequest>().unwrap();
+        assert_eq!(disconnect.data.code, 28);
+        assert_eq!(disconnect.data.message.unwrap().0, "User has disconnected.");
+    }
+
     #[test]
     fn test_parse_array() {
         let data = "(Response :data (: (hello) : (world)))";
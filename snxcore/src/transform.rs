@@ -0,0 +1,262 @@
+use std::fmt;
+
+use anyhow::anyhow;
+use isakmp::model::{EspAuthAlgorithm, EspCryptMaterial, TransformId};
+
+// salt of AES-GCM (RFC 4106) and nonce of AES-CTR (RFC 3686) are the trailing bytes of the encryption key
+const SALT_LEN: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EspEncryption {
+    AesCbc,
+    TripleDesCbc,
+    AesCtr,
+    AesGcm,
+}
+
+impl EspEncryption {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::AesCbc => "aes-cbc",
+            Self::TripleDesCbc => "3des",
+            Self::AesCtr => "aes-ctr",
+            Self::AesGcm => "aes-gcm",
+        }
+    }
+
+    pub fn xfrm_name(&self) -> &'static str {
+        match self {
+            Self::AesCbc => "cbc(aes)",
+            Self::TripleDesCbc => "cbc(des3_ede)",
+            Self::AesCtr => "rfc3686(ctr(aes))",
+            Self::AesGcm => "rfc4106(gcm(aes))",
+        }
+    }
+
+    pub fn is_aead(&self) -> bool {
+        *self == Self::AesGcm
+    }
+
+    pub fn salt_len(&self) -> usize {
+        match self {
+            Self::AesCtr | Self::AesGcm => SALT_LEN,
+            _ => 0,
+        }
+    }
+
+    pub fn iv_len(&self) -> usize {
+        match self {
+            Self::AesCbc => 16,
+            Self::TripleDesCbc | Self::AesCtr | Self::AesGcm => 8,
+        }
+    }
+
+    /// Alignment of the encrypted part of the ESP payload
+    pub fn block_size(&self) -> usize {
+        match self {
+            Self::AesCbc => 16,
+            Self::TripleDesCbc => 8,
+            Self::AesCtr | Self::AesGcm => 4,
+        }
+    }
+
+    fn is_valid_key_len(&self, len: usize) -> bool {
+        match self {
+            Self::TripleDesCbc => len == 24,
+            _ => matches!(len, 16 | 24 | 32),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EspIntegrity {
+    HmacSha1,
+    HmacSha256,
+    HmacSha384,
+    HmacSha512,
+}
+
+impl EspIntegrity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::HmacSha1 => "sha1",
+            Self::HmacSha256 => "sha256",
+            Self::HmacSha384 => "sha384",
+            Self::HmacSha512 => "sha512",
+        }
+    }
+
+    pub fn xfrm_name(&self) -> &'static str {
+        match self {
+            Self::HmacSha1 => "hmac(sha1)",
+            Self::HmacSha256 => "hmac(sha256)",
+            Self::HmacSha384 => "hmac(sha384)",
+            Self::HmacSha512 => "hmac(sha512)",
+        }
+    }
+
+    pub fn key_len(&self) -> usize {
+        match self {
+            Self::HmacSha1 => 20,
+            Self::HmacSha256 => 32,
+            Self::HmacSha384 => 48,
+            Self::HmacSha512 => 64,
+        }
+    }
+}
+
+fn encryption_for(transform_id: TransformId) -> anyhow::Result<(EspEncryption, Option<usize>)> {
+    match transform_id {
+        TransformId::EspAesCbc => Ok((EspEncryption::AesCbc, None)),
+        TransformId::Esp3Des => Ok((EspEncryption::TripleDesCbc, None)),
+        TransformId::EspAesCtr => Ok((EspEncryption::AesCtr, None)),
+        TransformId::EspAesGcm16 => Ok((EspEncryption::AesGcm, Some(16))),
+        TransformId::EspAesGcm12 => Ok((EspEncryption::AesGcm, Some(12))),
+        TransformId::EspAesGcm8 => Ok((EspEncryption::AesGcm, Some(8))),
+        other => Err(anyhow!("Unsupported ESP transform: {:?}", other)),
+    }
+}
+
+fn integrity_for(auth_algorithm: &EspAuthAlgorithm) -> anyhow::Result<EspIntegrity> {
+    match auth_algorithm {
+        EspAuthAlgorithm::HmacSha96 | EspAuthAlgorithm::HmacSha160 => Ok(EspIntegrity::HmacSha1),
+        EspAuthAlgorithm::HmacSha256 | EspAuthAlgorithm::HmacSha256v2 => Ok(EspIntegrity::HmacSha256),
+        EspAuthAlgorithm::HmacSha384 => Ok(EspIntegrity::HmacSha384),
+        EspAuthAlgorithm::HmacSha512 => Ok(EspIntegrity::HmacSha512),
+        EspAuthAlgorithm::Other(other) => Err(anyhow!("Unsupported ESP auth algorithm: {}", other)),
+    }
+}
+
+/// Algorithms and key sizes of the negotiated ESP SA
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EspTransform {
+    pub encryption: EspEncryption,
+    pub key_len: usize,
+    pub integrity: Option<EspIntegrity>,
+    pub icv_len: usize,
+}
+
+impl EspTransform {
+    pub fn new(material: &EspCryptMaterial) -> anyhow::Result<Self> {
+        let (encryption, aead_icv_len) = encryption_for(material.transform_id)?;
+
+        let key_len = material.sk_e.len().saturating_sub(encryption.salt_len());
+        if !encryption.is_valid_key_len(key_len) {
+            return Err(anyhow!(
+                "Invalid {} key length: {}",
+                encryption.as_str(),
+                material.sk_e.len()
+            ));
+        }
+
+        // AEAD ciphers provide the integrity protection themselves
+        let (integrity, icv_len) = match aead_icv_len {
+            Some(icv_len) => (None, icv_len),
+            None => {
+                let integrity = integrity_for(&material.auth_algorithm)?;
+
+                if material.sk_a.len() != integrity.key_len() {
+                    return Err(anyhow!(
+                        "Invalid {} key length: {}",
+                        integrity.as_str(),
+                        material.sk_a.len()
+                    ));
+                }
+
+                (Some(integrity), material.auth_algorithm.hash_len())
+            }
+        };
+
+        Ok(Self {
+            encryption,
+            key_len,
+            integrity,
+            icv_len,
+        })
+    }
+
+    /// Check the transform against the list of allowed algorithms.
+    /// Encryption could be specified with or without the key size, e.g. "aes-gcm" or "aes-gcm-256".
+    /// Empty list allows everything.
+    pub fn is_allowed<S: AsRef<str>>(&self, allowed: &[S]) -> bool {
+        if allowed.is_empty() {
+            return true;
+        }
+
+        let is_listed = |name: &str| allowed.iter().any(|a| a.as_ref().eq_ignore_ascii_case(name));
+
+        let encryption = self.encryption.as_str();
+        let encryption_allowed = is_listed(encryption) || is_listed(&format!("{}-{}", encryption, self.key_len * 8));
+
+        encryption_allowed && self.integrity.is_none_or(|i| is_listed(i.as_str()))
+    }
+}
+
+impl fmt::Display for EspTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.encryption.as_str(), self.key_len * 8)?;
+        match self.integrity {
+            Some(integrity) => write!(f, "/{}", integrity.as_str()),
+            None => write!(f, "/icv{}", self.icv_len * 8),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn material(
+        transform_id: TransformId,
+        enc_len: usize,
+        auth: EspAuthAlgorithm,
+        auth_len: usize,
+    ) -> EspCryptMaterial {
+        EspCryptMaterial {
+            transform_id,
+            sk_e: vec![0; enc_len].into(),
+            auth_algorithm: auth,
+            sk_a: vec![0; auth_len].into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_esp_transform() {
+        let gcm = EspTransform::new(&material(TransformId::EspAesGcm16, 36, EspAuthAlgorithm::Other(0), 0)).unwrap();
+        assert_eq!(gcm.encryption, EspEncryption::AesGcm);
+        assert_eq!(gcm.key_len, 32);
+        assert_eq!(gcm.integrity, None);
+        assert_eq!(gcm.icv_len, 16);
+        assert_eq!(gcm.to_string(), "aes-gcm-256/icv128");
+
+        let ctr = EspTransform::new(&material(TransformId::EspAesCtr, 20, EspAuthAlgorithm::HmacSha512, 64)).unwrap();
+        assert_eq!(ctr.key_len, 16);
+        assert_eq!(ctr.integrity, Some(EspIntegrity::HmacSha512));
+
+        let cbc = EspTransform::new(&material(TransformId::EspAesCbc, 24, EspAuthAlgorithm::HmacSha384, 48)).unwrap();
+        assert_eq!(cbc.to_string(), "aes-cbc-192/sha384");
+
+        // wrong key sizes and unknown algorithms
+        assert!(EspTransform::new(&material(TransformId::EspAesGcm8, 32, EspAuthAlgorithm::Other(0), 0)).is_err());
+        assert!(EspTransform::new(&material(TransformId::Esp3Des, 16, EspAuthAlgorithm::HmacSha96, 20)).is_err());
+        assert!(EspTransform::new(&material(TransformId::EspAesCbc, 16, EspAuthAlgorithm::HmacSha256, 20)).is_err());
+        assert!(EspTransform::new(&material(TransformId::EspAesCbc, 16, EspAuthAlgorithm::Other(100), 0)).is_err());
+        assert!(EspTransform::new(&material(TransformId::Other(99), 16, EspAuthAlgorithm::HmacSha96, 20)).is_err());
+    }
+
+    #[test]
+    fn test_allowed_transforms() {
+        let gcm = EspTransform::new(&material(TransformId::EspAesGcm16, 36, EspAuthAlgorithm::Other(0), 0)).unwrap();
+        let cbc = EspTransform::new(&material(TransformId::EspAesCbc, 16, EspAuthAlgorithm::HmacSha256, 32)).unwrap();
+
+        assert!(gcm.is_allowed::<&str>(&[]));
+        assert!(gcm.is_allowed(&["aes-gcm"]));
+        assert!(gcm.is_allowed(&["AES-GCM-256"]));
+        assert!(!gcm.is_allowed(&["aes-gcm-128"]));
+
+        assert!(cbc.is_allowed(&["aes-gcm", "aes-cbc", "sha256"]));
+        assert!(!cbc.is_allowed(&["aes-cbc", "sha512"]));
+        assert!(!cbc.is_allowed(&["aes-cbc-256", "sha256"]));
+    }
+}
@@ -1,7 +1,8 @@
-use std::sync::Arc;
+use std::{net::IpAddr, sync::Arc};
 
 use async_trait::async_trait;
 use bytes::Bytes;
+use ipnet::Ipv4Net;
 use tokio::sync::mpsc;
 
 use crate::{
@@ -9,24 +10,46 @@ use crate::{
         params::{TunnelParams, TunnelType},
         *,
     },
+    platform::DnsConfig,
     tunnel::{ipsec::connector::IpsecTunnelConnector, ssl::connector::CccTunnelConnector},
 };
 
 mod ipsec;
-mod ssl;
+pub mod ssl;
+mod stats;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum TunnelCommand {
     Terminate,
     ReKey(IpsecSession),
+    RemoteDisconnect(u32, String),
+    UpdateSession(Arc<VpnSession>),
+}
+
+/// Network configuration of the established tunnel, for the cases when it is applied by an external agent
+#[derive(Debug, Clone, PartialEq)]
+pub struct TunnelConfig {
+    pub device: String,
+    pub address: Ipv4Net,
+    /// External address of the gateway which must stay reachable outside of the tunnel
+    pub gateway: IpAddr,
+    /// Routes acquired from the gateway, the routing options of the tunnel parameters are not applied
+    pub routes: Vec<Ipv4Net>,
+    pub dns: DnsConfig,
+    pub mtu: u16,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum TunnelEvent {
+    Configured(TunnelConfig),
     Connected,
     Disconnected,
     RekeyCheck,
     RemoteControlData(Bytes),
+    RemoteDisconnected(u32, String),
+    ChallengeRequired(Arc<VpnSession>),
+    Statistics(TunnelStatistics),
+    AddressChanged(IpAddr),
 }
 
 #[async_trait]
@@ -48,6 +71,7 @@ pub trait TunnelConnector {
         command_sender: mpsc::Sender<TunnelCommand>,
     ) -> anyhow::Result<Box<dyn VpnTunnel + Send>>;
     async fn terminate_tunnel(&mut self) -> anyhow::Result<()>;
+    async fn update_session(&mut self, session: Arc<VpnSession>) -> anyhow::Result<()>;
     async fn handle_tunnel_event(&mut self, event: TunnelEvent) -> anyhow::Result<()>;
 }
 
@@ -1,5 +1,5 @@
 use std::{
-    net::{IpAddr, ToSocketAddrs},
+    net::IpAddr,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
@@ -8,33 +8,64 @@ use std::{
 };
 
 use anyhow::anyhow;
+use bytes::Bytes;
+use ipnet::Ipv4Net;
 use tokio::{net::UdpSocket, sync::mpsc, time::MissedTickBehavior};
-use tracing::debug;
+use tracing::{debug, trace, warn};
 
 use crate::{
     ccc::CccHttpClient,
-    model::{params::TunnelParams, VpnSession},
-    platform::{self, IpsecConfigurator, UdpEncap, UdpSocketExt},
+    happy_eyeballs,
+    model::{
+        params::{IpsecDatapath, IpsecTransportType, TunnelParams},
+        VpnSession,
+    },
+    platform::{self, DnsConfig, IpsecConfigurator, UdpEncap, UdpSocketExt},
+    server_info::TransportEndpoints,
     tunnel::{
-        ipsec::{keepalive::KeepaliveRunner, natt::start_natt_listener},
-        TunnelCommand, TunnelEvent, VpnTunnel,
+        ipsec::{
+            keepalive::KeepaliveRunner,
+            natt::{start_control_forwarder, start_natt_listener},
+            pmtu::PmtuProber,
+            tcpt::TcptEspStream,
+            userspace::{EspTransport, UserspaceConfigurator},
+        },
+        stats::{StatsSampler, TunnelCounters, STATS_INTERVAL},
+        TunnelCommand, TunnelConfig, TunnelEvent, VpnTunnel,
     },
     util,
 };
 
 pub mod connector;
+pub mod esp;
 pub mod keepalive;
 pub mod natt;
+pub mod pmtu;
+pub mod tcpt;
+pub mod userspace;
+
+const CONTROL_CHANNEL_SIZE: usize = 16;
 
 pub(crate) struct IpsecTunnel {
     configurator: Box<dyn IpsecConfigurator + Send + Sync>,
     keepalive_runner: KeepaliveRunner,
+    pmtu_prober: Option<PmtuProber>,
     natt_socket: Arc<UdpSocket>,
+    gateway_address: IpAddr,
+    control_receiver: Option<mpsc::Receiver<Bytes>>,
     ready: Arc<AtomicBool>,
+    counters: Arc<TunnelCounters>,
+    tunnel_config: TunnelConfig,
+    tcpt_stream: Option<Arc<TcptEspStream>>,
 }
 
 impl IpsecTunnel {
-    pub(crate) async fn create(params: Arc<TunnelParams>, session: Arc<VpnSession>) -> anyhow::Result<Self> {
+    pub(crate) async fn create(
+        params: Arc<TunnelParams>,
+        session: Arc<VpnSession>,
+        endpoints: &TransportEndpoints,
+        transport_type: IpsecTransportType,
+    ) -> anyhow::Result<Self> {
         let ipsec_session = session
             .ipsec_session
             .as_ref()
@@ -43,41 +74,128 @@ impl IpsecTunnel {
         let client = CccHttpClient::new(params.clone(), Some(session.clone()));
         let client_settings = client.get_client_settings().await?;
 
-        let gateway_address = format!("{}:{}", params.server_name, params.ike_port)
-            .to_socket_addrs()?
-            .find_map(|addr| match addr.ip() {
-                IpAddr::V4(v4) => Some(v4),
-                IpAddr::V6(_) => None,
-            })
-            .ok_or_else(|| anyhow!("No gateway address!"))?;
+        let gateway_address = match endpoints.server_ip {
+            Some(server_ip) => server_ip,
+            None => happy_eyeballs::resolve_udp(&params.server_name, params.ike_port)
+                .await?
+                .ip(),
+        };
 
         debug!(
             "Resolved gateway address: {}, acquired internal address: {}",
             gateway_address, client_settings.gw_internal_ip
         );
 
+        // keepalive and PMTU probes are sent inside the tunnel, which is always IPv4
+        let keepalive_address = match gateway_address {
+            IpAddr::V4(address) => address,
+            IpAddr::V6(_) => client_settings.gw_internal_ip,
+        };
+
         let ready = Arc::new(AtomicBool::new(false));
-        let keepalive_runner = KeepaliveRunner::new(ipsec_session.address, gateway_address, ready.clone());
+        let counters = Arc::new(TunnelCounters::default());
+        let keepalive_runner = KeepaliveRunner::new(
+            ipsec_session.address,
+            keepalive_address,
+            ready.clone(),
+            counters.clone(),
+        );
 
-        let natt_socket = UdpSocket::bind("0.0.0.0:0").await?;
-        natt_socket.set_encap(UdpEncap::EspInUdp)?;
+        // the MTU is lowered automatically only when it is not set explicitly,
+        // TCP takes care of the path MTU for the TCPT transport
+        let detect_black_hole = params.mtu.is_none() && transport_type != IpsecTransportType::Tcpt;
 
-        let mut configurator = platform::new_ipsec_configurator(
-            params,
-            ipsec_session.clone(),
-            natt_socket.local_addr()?.port(),
-            gateway_address,
-            util::ranges_to_subnets(&client_settings.updated_policies.range.settings).collect(),
-        )?;
+        let natt_socket = Arc::new(happy_eyeballs::bind_udp(gateway_address).await?);
+        let subnets: Vec<Ipv4Net> = util::ranges_to_subnets(&client_settings.updated_policies.range.settings).collect();
+
+        // ESP packets cannot be encapsulated into TCPT by the kernel
+        let esp_transport = if transport_type == IpsecTransportType::Tcpt {
+            let stream =
+                TcptEspStream::connect(params.clone(), &endpoints.server_address(&params), endpoints.tcpt_port).await?;
+            EspTransport::Tcpt(Arc::new(stream))
+        } else {
+            EspTransport::Udp(natt_socket.clone())
+        };
+
+        let tcpt_stream = match esp_transport {
+            EspTransport::Tcpt(ref stream) => Some(stream.clone()),
+            EspTransport::Udp(_) => None,
+        };
+
+        let datapath = match esp_transport {
+            EspTransport::Tcpt(_) => IpsecDatapath::Userspace,
+            EspTransport::Udp(_) => params.ipsec_datapath,
+        };
+
+        // with the userspace datapath the NAT-T socket is read by the configurator,
+        // which passes the non-ESP packets back via the control channel
+        let (mut configurator, control_receiver): (Box<dyn IpsecConfigurator + Send + Sync>, _) = match datapath {
+            IpsecDatapath::Kernel => {
+                natt_socket.set_encap(UdpEncap::EspInUdp)?;
+                let configurator = platform::new_ipsec_configurator(
+                    params.clone(),
+                    ipsec_session.clone(),
+                    natt_socket.local_addr()?.port(),
+                    gateway_address,
+                    endpoints.natt_port,
+                    keepalive_address,
+                    subnets.clone(),
+                )?;
+                (Box::new(configurator), None)
+            }
+            IpsecDatapath::Userspace => {
+                let (control_sender, control_receiver) = mpsc::channel(CONTROL_CHANNEL_SIZE);
+                let configurator = UserspaceConfigurator::new(
+                    params.clone(),
+                    ipsec_session.clone(),
+                    esp_transport,
+                    gateway_address,
+                    endpoints.natt_port,
+                    keepalive_address,
+                    subnets.clone(),
+                    control_sender,
+                )?;
+                (Box::new(configurator), Some(control_receiver))
+            }
+        };
 
         configurator.configure().await?;
         ready.store(true, Ordering::SeqCst);
 
+        let tunnel_config = TunnelConfig {
+            device: configurator.device_name().to_owned(),
+            address: Ipv4Net::with_netmask(ipsec_session.address, ipsec_session.netmask)
+                .unwrap_or_else(|_| ipsec_session.address.into()),
+            gateway: gateway_address,
+            routes: subnets,
+            dns: DnsConfig::new(
+                &params,
+                &ipsec_session.domains,
+                ipsec_session.dns.iter().map(|&server| server.into()).collect(),
+            ),
+            mtu: configurator.mtu(),
+        };
+
+        let pmtu_prober = detect_black_hole.then(|| {
+            PmtuProber::new(
+                ipsec_session.address,
+                keepalive_address,
+                configurator.mtu(),
+                ready.clone(),
+            )
+        });
+
         Ok(Self {
-            configurator: Box::new(configurator),
+            configurator,
             keepalive_runner,
-            natt_socket: Arc::new(natt_socket),
+            pmtu_prober,
+            natt_socket,
+            gateway_address,
+            control_receiver,
             ready,
+            counters,
+            tunnel_config,
+            tcpt_stream,
         })
     }
 }
@@ -91,8 +209,14 @@ impl VpnTunnel for IpsecTunnel {
     ) -> anyhow::Result<()> {
         debug!("Running IPSec tunnel");
 
-        let natt_stopper = start_natt_listener(self.natt_socket.clone(), event_sender.clone()).await?;
+        let natt_stopper = match self.control_receiver.take() {
+            Some(receiver) => start_control_forwarder(receiver, event_sender.clone()),
+            None => start_natt_listener(self.natt_socket.clone(), event_sender.clone()).await?,
+        };
 
+        let _ = event_sender
+            .send(TunnelEvent::Configured(self.tunnel_config.clone()))
+            .await;
         let _ = event_sender.send(TunnelEvent::Connected).await;
 
         let sender = event_sender.clone();
@@ -106,26 +230,99 @@ impl VpnTunnel for IpsecTunnel {
             Ok::<_, anyhow::Error>(())
         });
 
+        let (mtu_sender, mut mtu_receiver) = mpsc::channel(1);
+        let pmtu_task = self.pmtu_prober.take().map(|prober| {
+            tokio::spawn(async move {
+                if let Err(e) = prober.run(mtu_sender).await {
+                    warn!("PMTU black hole detection stopped: {}", e);
+                }
+            })
+        });
+
+        // the tunnel follows the local address when the device moves between the networks
+        let mut address_receiver = match platform::watch_source_ip(self.gateway_address).await {
+            Ok(receiver) => receiver,
+            Err(e) => {
+                warn!("Cannot watch the source address changes: {}", e);
+                mpsc::channel(1).1
+            }
+        };
+
+        let tcpt_stream = self.tcpt_stream.clone();
+
         let fut = async {
-            while let Some(cmd) = command_receiver.recv().await {
-                match cmd {
-                    TunnelCommand::Terminate => break,
-                    TunnelCommand::ReKey(session) => {
-                        debug!(
-                            "Rekey command received, new lifetime: {}, configuring xfrm",
-                            session.lifetime.as_secs()
-                        );
+            let mut stats_interval = tokio::time::interval(STATS_INTERVAL);
+            let mut stats_sampler = StatsSampler::default();
+
+            loop {
+                tokio::select! {
+                    cmd = command_receiver.recv() => match cmd {
+                        Some(TunnelCommand::ReKey(session)) => {
+                            debug!(
+                                "Rekey command received, new lifetime: {}, configuring xfrm",
+                                session.lifetime.as_secs()
+                            );
+                            self.ready.store(false, Ordering::SeqCst);
+                            if let Err(e) = self.configurator.rekey(&session).await {
+                                warn!("Cannot rekey IPSec tunnel: {}", e);
+                            }
+                            self.ready.store(true, Ordering::SeqCst);
+                        }
+                        Some(TunnelCommand::RemoteDisconnect(code, message)) => {
+                            let _ = event_sender.send(TunnelEvent::RemoteDisconnected(code, message)).await;
+                            break Ok(());
+                        }
+                        Some(TunnelCommand::UpdateSession(_)) => {
+                            warn!("Session update is not supported for IPSec tunnel");
+                        }
+                        Some(TunnelCommand::Terminate) | None => break Ok(()),
+                    },
+
+                    // reported by the ESP receiver, which resumes once the connection is replaced
+                    () = util::await_optional(tcpt_stream.as_ref().map(|stream| stream.lost())) => {
+                        warn!("TCPT transport lost, reconnecting");
+                        self.ready.store(false, Ordering::SeqCst);
+                        if let Err(e) = tcpt_stream.as_ref().unwrap().reconnect().await {
+                            break Err(anyhow!("Cannot reconnect TCPT transport: {}", e));
+                        }
+                        self.ready.store(true, Ordering::SeqCst);
+                        debug!("TCPT transport reconnected");
+                    }
+
+                    Some(address) = address_receiver.recv() => {
+                        debug!("Local address changed to {}, re-anchoring the tunnel", address);
                         self.ready.store(false, Ordering::SeqCst);
-                        let _ = self.configurator.rekey(&session).await;
+                        if let Err(e) = self.configurator.reanchor(address).await {
+                            warn!("Cannot move IPSec tunnel to the new address: {}", e);
+                        }
                         self.ready.store(true, Ordering::SeqCst);
+                        let _ = event_sender.send(TunnelEvent::AddressChanged(address)).await;
+                    }
+
+                    Some(mtu) = mtu_receiver.recv() => {
+                        if let Err(e) = self.configurator.set_mtu(mtu).await {
+                            warn!("Cannot change tunnel MTU: {}", e);
+                        }
+                    }
+
+                    _ = stats_interval.tick() => {
+                        match self.configurator.statistics().await {
+                            Ok(stats) => self.counters.set_traffic(&stats),
+                            Err(e) => trace!("Cannot read XFRM statistics: {}", e),
+                        }
+                        let stats = stats_sampler.sample(&self.counters);
+                        let _ = event_sender.send(TunnelEvent::Statistics(stats)).await;
                     }
                 }
             }
         };
         let result = tokio::select! {
-            () = fut => {
-                debug!("Terminating IPSec tunnel due to stop command");
-                Ok(())
+            result = fut => {
+                match result {
+                    Ok(()) => debug!("Terminating IPSec tunnel due to stop command"),
+                    Err(ref e) => debug!("Terminating IPSec tunnel due to transport failure: {}", e),
+                }
+                result
             }
 
             err = self.keepalive_runner.run() => {
@@ -134,6 +331,10 @@ impl VpnTunnel for IpsecTunnel {
             }
         };
 
+        if let Some(task) = pmtu_task {
+            task.abort();
+        }
+
         let _ = natt_stopper.send(());
         let _ = event_sender.send(TunnelEvent::Disconnected).await;
 
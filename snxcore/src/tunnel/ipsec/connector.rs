@@ -1,18 +1,28 @@
 use std::{
     net::{IpAddr, Ipv4Addr},
-    sync::Arc,
+    sync::{Arc, Mutex},
     time::{Duration, SystemTime},
 };
 
 use crate::{
+    happy_eyeballs,
     model::{
-        params::{CertType, TunnelParams},
+        params::{CertType, IpsecTransportType, TunnelParams},
         proto::{AuthenticationRealm, ClientLoggingData},
         IpsecSession, MfaChallenge, MfaType, SessionState, VpnSession,
     },
-    platform, server_info,
+    platform,
+    server_info::{self, TransportEndpoints},
     sexpr::SExpression,
-    tunnel::{ipsec::natt::NattProber, ipsec::IpsecTunnel, TunnelCommand, TunnelConnector, TunnelEvent, VpnTunnel},
+    transform::EspTransform,
+    tunnel::{
+        ipsec::{
+            natt::NattProber,
+            tcpt::{self, TcptDataType, TcptTransport},
+            IpsecTunnel,
+        },
+        TunnelCommand, TunnelConnector, TunnelEvent, VpnTunnel,
+    },
 };
 use anyhow::anyhow;
 use async_trait::async_trait;
@@ -20,16 +30,18 @@ use byteorder::{BigEndian, ReadBytesExt};
 use bytes::{Buf, Bytes};
 use isakmp::{
     ikev1::{codec::Ikev1Codec, service::Ikev1Service, session::Ikev1Session},
-    message::IsakmpMessageCodec,
-    model::{ConfigAttributeType, EspAttributeType, Identity, PayloadType},
-    payload::AttributesPayload,
+    message::{IsakmpMessage, IsakmpMessageCodec},
+    model::{ConfigAttributeType, EspAttributeType, Identity, NotifyMessageType, PayloadType, ProtocolId},
+    payload::{AttributesPayload, Payload},
     session::IsakmpSession,
-    transport::UdpTransport,
+    transport::{IsakmpTransport, UdpTransport},
 };
+use once_cell::sync::Lazy;
 use tokio::{net::UdpSocket, sync::mpsc::Sender};
 use tracing::{debug, trace, warn};
 
 const MIN_ESP_LIFETIME: Duration = Duration::from_secs(60);
+const MIN_IKE_LIFETIME: Duration = Duration::from_secs(300);
 
 fn get_challenge_attribute_type(payload: &AttributesPayload) -> ConfigAttributeType {
     payload
@@ -76,78 +88,328 @@ fn get_short_attribute(payload: &AttributesPayload, attr: ConfigAttributeType) -
         .find_map(|a| if a.attribute_type == attr { a.as_short() } else { None })
 }
 
+// IKE exchange runs either over UDP or over the TCPT connection (visitor mode)
+enum IkeTransport {
+    Udp(UdpTransport<Ikev1Codec<Ikev1Session>>),
+    Tcpt(TcptTransport<Ikev1Codec<Ikev1Session>>),
+}
+
+#[async_trait]
+impl IsakmpTransport for IkeTransport {
+    async fn send(&mut self, message: &IsakmpMessage) -> anyhow::Result<()> {
+        match self {
+            Self::Udp(transport) => transport.send(message).await,
+            Self::Tcpt(transport) => transport.send(message).await,
+        }
+    }
+
+    async fn send_receive(&mut self, message: &IsakmpMessage, timeout: Duration) -> anyhow::Result<IsakmpMessage> {
+        match self {
+            Self::Udp(transport) => transport.send_receive(message, timeout).await,
+            Self::Tcpt(transport) => transport.send_receive(message, timeout).await,
+        }
+    }
+}
+
+async fn select_transport_type(params: &TunnelParams, prober: &NattProber) -> anyhow::Result<IpsecTransportType> {
+    match params.ipsec_transport {
+        IpsecTransportType::Udp => {
+            prober.probe().await?;
+            Ok(IpsecTransportType::Udp)
+        }
+        IpsecTransportType::Tcpt => Ok(IpsecTransportType::Tcpt),
+        IpsecTransportType::Auto if params.proxy_url.is_some() => {
+            debug!("Proxy is configured, using TCPT transport");
+            Ok(IpsecTransportType::Tcpt)
+        }
+        IpsecTransportType::Auto => match prober.probe().await {
+            Ok(()) => Ok(IpsecTransportType::Udp),
+            Err(e) => {
+                warn!("{} Falling back to TCPT transport", e);
+                Ok(IpsecTransportType::Tcpt)
+            }
+        },
+    }
+}
+
+// action requested by the gateway with the Delete and Notify payloads of the informational exchange
+#[derive(Debug, Clone, PartialEq)]
+enum GatewayRequest {
+    None,
+    RekeyEsp,
+    RekeyIke,
+    Reconnect(String),
+}
+
+// Delete payloads for the previous ESP SAs are expected after rekeying, only the current ones are taken into account
+fn get_gateway_request(payloads: &[Payload], esp_spis: &[u32]) -> GatewayRequest {
+    let mut esp_deleted = false;
+    let mut ike_deleted = false;
+    let mut invalid_spi = false;
+
+    for payload in payloads {
+        match payload {
+            Payload::Delete(delete) => match delete.protocol_id {
+                ProtocolId::Isakmp => ike_deleted = true,
+                ProtocolId::IpsecEsp => {
+                    esp_deleted |= delete.spis.iter().any(|spi| {
+                        let spi: Option<[u8; 4]> = spi.as_ref().try_into().ok();
+                        spi.is_some_and(|spi| esp_spis.contains(&u32::from_be_bytes(spi)))
+                    })
+                }
+                _ => {}
+            },
+            Payload::Notification(notify) => match NotifyMessageType::from(notify.message_type) {
+                NotifyMessageType::InvalidSpi => invalid_spi = true,
+                NotifyMessageType::AuthenticationFailed => {
+                    return GatewayRequest::Reconnect("Authentication failed".to_owned());
+                }
+                other => debug!("Ignoring notification: {:?}", other),
+            },
+            _ => {}
+        }
+    }
+
+    if ike_deleted && esp_deleted {
+        GatewayRequest::Reconnect("Session terminated by the gateway".to_owned())
+    } else if ike_deleted {
+        GatewayRequest::RekeyIke
+    } else if esp_deleted || invalid_spi {
+        GatewayRequest::RekeyEsp
+    } else {
+        GatewayRequest::None
+    }
+}
+
+// the SA is renegotiated the given margin before it expires, or halfway through if the lifetime is shorter than that
+fn is_rekey_due(established: Option<SystemTime>, lifetime: Duration, margin: Duration, now: SystemTime) -> bool {
+    let lifetime = if lifetime <= margin {
+        lifetime / 2
+    } else {
+        lifetime - margin
+    };
+
+    established.is_some_and(|established| now.duration_since(established).unwrap_or(lifetime) >= lifetime)
+}
+
+// the CCC session of the last authenticated connection, which is presented to the gateway as oldSessionId
+// when reconnecting after a network change, so that the session could be resumed without the MFA prompts
+static LAST_SESSION: Lazy<Mutex<Option<ResumableSession>>> = Lazy::new(|| Mutex::new(None));
+
+#[derive(Debug, Clone, PartialEq)]
+struct ResumableSession {
+    key: String,
+    ccc_session: String,
+    authenticated_at: SystemTime,
+}
+
+impl ResumableSession {
+    fn new(params: &TunnelParams, ccc_session: &str) -> Self {
+        Self {
+            key: Self::session_key(params),
+            ccc_session: ccc_session.to_owned(),
+            authenticated_at: SystemTime::now(),
+        }
+    }
+
+    fn session_key(params: &TunnelParams) -> String {
+        format!("{}:{}:{}", params.server_name, params.user_name, params.login_type)
+    }
+
+    // the session is considered valid on the gateway for the lifetime of the IKE SA it was authenticated with
+    fn is_valid_for(&self, params: &TunnelParams, now: SystemTime) -> bool {
+        self.key == Self::session_key(params)
+            && now
+                .duration_since(self.authenticated_at)
+                .is_ok_and(|elapsed| elapsed < params.ike_lifetime)
+    }
+
+    fn remember(params: &TunnelParams, ccc_session: &str) {
+        *LAST_SESSION.lock().unwrap() = Some(Self::new(params, ccc_session));
+    }
+
+    fn forget() {
+        LAST_SESSION.lock().unwrap().take();
+    }
+
+    fn resumable_session_id(params: &TunnelParams) -> Option<String> {
+        LAST_SESSION
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|session| session.is_valid_for(params, SystemTime::now()))
+            .map(|session| session.ccc_session.clone())
+    }
+}
+
 pub struct IpsecTunnelConnector {
     params: Arc<TunnelParams>,
-    service: Ikev1Service<UdpTransport<Ikev1Codec<Ikev1Session>>>,
-    gateway_address: Ipv4Addr,
+    service: Ikev1Service<IkeTransport>,
+    gateway_address: IpAddr,
+    endpoints: TransportEndpoints,
+    transport_type: IpsecTransportType,
     last_message_id: u32,
     last_identifier: u16,
     last_challenge_type: ConfigAttributeType,
     ccc_session: String,
     ipsec_session: IpsecSession,
     last_rekey: Option<SystemTime>,
+    ike_established: Option<SystemTime>,
     command_sender: Option<Sender<TunnelCommand>>,
 }
 
-impl IpsecTunnelConnector {
-    pub async fn new(params: Arc<TunnelParams>) -> anyhow::Result<Self> {
-        let identity = match params.cert_type {
-            CertType::Pkcs12 => match (&params.cert_path, &params.cert_password) {
-                (Some(path), Some(password)) => Identity::Pkcs12 {
-                    path: path.clone(),
-                    password: password.clone(),
-                },
-                _ => return Err(anyhow!("No PKCS12 path and password provided!")),
-            },
-            CertType::Pkcs8 => match params.cert_path {
-                Some(ref path) => Identity::Pkcs8 { path: path.clone() },
-                None => return Err(anyhow!("No PKCS8 PEM path provided!")),
+fn new_identity(params: &TunnelParams) -> anyhow::Result<Identity> {
+    let identity = match params.cert_type {
+        CertType::Pkcs12 => match (&params.cert_path, &params.cert_password) {
+            (Some(path), Some(password)) => Identity::Pkcs12 {
+                path: path.clone(),
+                password: password.clone(),
             },
-            CertType::Pkcs11 => match params.cert_password {
-                Some(ref pin) => Identity::Pkcs11 {
-                    driver_path: params.cert_path.clone().unwrap_or_else(|| "opensc-pkcs11.so".into()),
-                    pin: pin.clone(),
-                    key_id: params
-                        .cert_id
-                        .as_ref()
-                        .map(|s| hex::decode(s.replace(':', "")).unwrap_or_default().into()),
-                },
-                None => return Err(anyhow!("No PKCS11 pin provided!")),
+            _ => return Err(anyhow!("No PKCS12 path and password provided!")),
+        },
+        CertType::Pkcs8 => match params.cert_path {
+            Some(ref path) => Identity::Pkcs8 { path: path.clone() },
+            None => return Err(anyhow!("No PKCS8 PEM path provided!")),
+        },
+        CertType::Pkcs11 => match params.cert_password {
+            Some(ref pin) => Identity::Pkcs11 {
+                driver_path: params.cert_path.clone().unwrap_or_else(|| "opensc-pkcs11.so".into()),
+                pin: pin.clone(),
+                key_id: params
+                    .cert_id
+                    .as_ref()
+                    .map(|s| hex::decode(s.replace(':', "")).unwrap_or_default().into()),
             },
+            None => return Err(anyhow!("No PKCS11 pin provided!")),
+        },
 
-            CertType::None => Identity::None,
-        };
+        CertType::None => Identity::None,
+    };
 
-        let socket = UdpSocket::bind("0.0.0.0:0").await?;
-        socket
-            .connect(format!("{}:{}", params.server_name, params.ike_port))
+    Ok(identity)
+}
+
+async fn new_ike_socket(params: &TunnelParams) -> anyhow::Result<(UdpSocket, IpAddr)> {
+    let address = happy_eyeballs::resolve_udp(&params.server_name, params.ike_port).await?;
+    let socket = happy_eyeballs::bind_udp(address.ip()).await?;
+    socket.connect(address).await?;
+
+    Ok((socket, address.ip()))
+}
+
+async fn new_service(
+    params: &TunnelParams,
+    endpoints: &TransportEndpoints,
+    transport_type: IpsecTransportType,
+    socket: UdpSocket,
+) -> anyhow::Result<Ikev1Service<IkeTransport>> {
+    let ikev1_session = Ikev1Session::new(new_identity(params)?)?;
+    let codec = Ikev1Codec::new(ikev1_session.clone());
+
+    let transport = match transport_type {
+        IpsecTransportType::Tcpt => {
+            let stream = tcpt::connect(
+                params,
+                &endpoints.server_address(params),
+                endpoints.tcpt_port,
+                TcptDataType::Ike,
+            )
             .await?;
+            IkeTransport::Tcpt(TcptTransport::new(stream, codec))
+        }
+        _ => IkeTransport::Udp(UdpTransport::new(socket, codec)),
+    };
 
-        let IpAddr::V4(gateway_address) = socket.peer_addr()?.ip() else {
-            return Err(anyhow!("No IPv4 address for {}", params.server_name));
-        };
+    Ikev1Service::new(transport, ikev1_session)
+}
 
-        let prober = NattProber::new(gateway_address);
-        prober.probe().await?;
+// phase 1 exchange: SA proposal, key exchange and identity protection with the authentication realm
+async fn do_main_mode(
+    params: &TunnelParams,
+    service: &mut Ikev1Service<IkeTransport>,
+    gateway_address: IpAddr,
+    old_session_id: String,
+) -> anyhow::Result<()> {
+    // NAT discovery of the IKE exchange supports IPv4 addresses only. With the IPv6 gateway the hashes
+    // never match, the gateway assumes NAT and ESP is encapsulated into UDP, which is what is used anyway.
+    let (my_address, gateway_address) = match gateway_address {
+        IpAddr::V4(gateway_address) => (platform::get_default_ip().await?.parse::<Ipv4Addr>()?, gateway_address),
+        IpAddr::V6(_) => (Ipv4Addr::UNSPECIFIED, Ipv4Addr::UNSPECIFIED),
+    };
+
+    service.do_sa_proposal(params.ike_lifetime).await?;
+    service.do_key_exchange(my_address, gateway_address).await?;
+
+    let realm = AuthenticationRealm {
+        client_type: params.tunnel_type.as_client_type().to_owned(),
+        old_session_id,
+        protocol_version: 100,
+        client_mode: params.client_mode.clone(),
+        selected_realm_id: params.login_type.clone(),
+        secondary_realm_hash: None,
+        client_logging_data: Some(ClientLoggingData {
+            os_name: Some("Windows".to_owned()),
+            device_id: Some(crate::util::get_device_id().into()),
+            ..Default::default()
+        }),
+    };
+
+    let realm_expr = SExpression::from(&realm);
+
+    trace!("Authentication blob: {}", realm_expr);
+
+    service
+        .do_identity_protection(
+            gateway_address,
+            Bytes::copy_from_slice(realm_expr.to_string().as_bytes()),
+            params.ipsec_cert_check,
+            &params.ca_cert,
+        )
+        .await?;
 
-        let ikev1_session = Ikev1Session::new(identity)?;
-        let transport = UdpTransport::new(socket, Ikev1Codec::new(ikev1_session.clone()));
-        let service = Ikev1Service::new(transport, ikev1_session)?;
+    Ok(())
+}
+
+impl IpsecTunnelConnector {
+    pub async fn new(params: Arc<TunnelParams>) -> anyhow::Result<Self> {
+        let (socket, gateway_address) = new_ike_socket(&params).await?;
+
+        let endpoints = server_info::get_transport_endpoints(&params).await;
+
+        let prober = NattProber::new(endpoints.server_ip.unwrap_or(gateway_address), endpoints.natt_port);
+        let transport_type = select_transport_type(&params, &prober).await?;
+
+        debug!("IPSec transport: {}", transport_type.as_str());
+
+        let service = new_service(&params, &endpoints, transport_type, socket).await?;
 
         Ok(Self {
             params,
             service,
             gateway_address,
+            endpoints,
+            transport_type,
             last_message_id: 0,
             last_identifier: 0,
             last_challenge_type: ConfigAttributeType::Other(0),
             ccc_session: String::new(),
             ipsec_session: IpsecSession::default(),
             last_rekey: None,
+            ike_established: None,
             command_sender: None,
         })
     }
 
+    // the IKE exchange cannot be continued after the failure, so it starts over with the new IKE SA
+    async fn reset_service(&mut self) -> anyhow::Result<()> {
+        let (socket, _) = new_ike_socket(&self.params).await?;
+        self.service = new_service(&self.params, &self.endpoints, self.transport_type, socket).await?;
+        self.last_message_id = 0;
+        self.last_identifier = 0;
+        self.last_challenge_type = ConfigAttributeType::Other(0);
+        Ok(())
+    }
+
     fn do_challenge_attr(&mut self, attr: &Bytes) -> anyhow::Result<Arc<VpnSession>> {
         let parts = attr
             .split(|c| *c == b'\0')
@@ -227,6 +489,8 @@ impl IpsecTunnelConnector {
 
         self.last_rekey = Some(SystemTime::now());
 
+        ResumableSession::remember(&self.params, &self.ccc_session);
+
         let session = Arc::new(VpnSession {
             ccc_session_id: self.ccc_session.clone(),
             ipsec_session: Some(self.ipsec_session.clone()),
@@ -318,9 +582,24 @@ impl IpsecTunnelConnector {
         debug!("ESP lifetime: {} seconds", lifetime);
 
         let session = self.service.session();
+        let esp_in = session.esp_in();
+        let esp_out = session.esp_out();
+
+        // the gateway may select any of the proposed transforms, the ones not approved by the configuration are refused
+        for material in [&esp_in, &esp_out] {
+            let transform = EspTransform::new(material)?;
+            if !transform.is_allowed(&self.params.esp_transforms) {
+                return Err(anyhow!(
+                    "ESP transform {} is not allowed by the configuration!",
+                    transform
+                ));
+            }
+            debug!("ESP transform: {}", transform);
+        }
+
         self.ipsec_session.lifetime = Duration::from_secs(lifetime as u64);
-        self.ipsec_session.esp_in = session.esp_in();
-        self.ipsec_session.esp_out = session.esp_out();
+        self.ipsec_session.esp_in = esp_in;
+        self.ipsec_session.esp_out = esp_out;
 
         Ok(())
     }
@@ -338,87 +617,204 @@ impl IpsecTunnelConnector {
             if payload_types.iter().any(|p| *p == PayloadType::SecurityAssociation) {
                 self.rekey_tunnel().await?;
             }
+
+            let esp_spis = [self.ipsec_session.esp_in.spi, self.ipsec_session.esp_out.spi];
+
+            match get_gateway_request(&msg.payloads, &esp_spis) {
+                GatewayRequest::None => {}
+                GatewayRequest::RekeyEsp => {
+                    debug!("ESP SA is no longer valid on the gateway");
+                    self.do_esp_rekey().await?;
+                }
+                GatewayRequest::RekeyIke => {
+                    debug!("IKE SA deleted by the gateway");
+                    if let Err(e) = self.do_ike_rekey().await {
+                        self.reconnect(format!("IKE SA deleted by the gateway: {}", e)).await?;
+                    }
+                }
+                GatewayRequest::Reconnect(reason) => {
+                    self.reconnect(reason).await?;
+                }
+            }
         }
         Ok(())
     }
 
-    async fn rekey_tunnel(&mut self) -> anyhow::Result<()> {
-        let lifetime = if self.ipsec_session.lifetime < MIN_ESP_LIFETIME {
-            self.ipsec_session.lifetime
-        } else {
-            self.ipsec_session.lifetime - MIN_ESP_LIFETIME
+    // the SAs are no longer valid on the gateway, the saved session is presented over the new IKE SA
+    // to restore the tunnel without the user input, the tunnel is disconnected only if that fails
+    async fn reconnect(&mut self, reason: String) -> anyhow::Result<()> {
+        warn!("Reconnecting, reason: {}", reason);
+
+        let result = match ResumableSession::resumable_session_id(&self.params) {
+            Some(old_session_id) => self.resume_session(old_session_id).await,
+            None => Err(anyhow!("No session to resume")),
         };
 
+        match result {
+            Ok(()) => {
+                debug!("Session resumed");
+                Ok(())
+            }
+            Err(e) => {
+                self.remote_disconnect(0, format!("{}, reconnect failed: {}", reason, e))
+                    .await
+            }
+        }
+    }
+
+    async fn resume_session(&mut self, old_session_id: String) -> anyhow::Result<()> {
+        let address = self.ipsec_session.address;
+
+        self.reset_service().await?;
+
+        let session = self.do_authenticate(old_session_id).await?;
+        if !matches!(session.state, SessionState::Authenticated(_)) {
+            return Err(anyhow!("User input is required"));
+        }
+
+        // the tunnel device keeps the previous address, only the ESP SAs are replaced
+        if self.ipsec_session.address != address {
+            return Err(anyhow!("Tunnel address changed: {}", self.ipsec_session.address));
+        }
+
+        match self.command_sender {
+            Some(ref sender) => Ok(sender.send(TunnelCommand::ReKey(self.ipsec_session.clone())).await?),
+            None => Err(anyhow!("No sender!")),
+        }
+    }
+
+    // the session is ended by the gateway, it cannot be resumed later
+    async fn remote_disconnect(&mut self, code: u32, message: String) -> anyhow::Result<()> {
+        warn!("Disconnecting, code: {}, reason: {}", code, message);
+        ResumableSession::forget();
+        if let Some(sender) = self.command_sender.take() {
+            sender.send(TunnelCommand::RemoteDisconnect(code, message)).await?;
+        }
+        Ok(())
+    }
+
+    async fn rekey_tunnel(&mut self) -> anyhow::Result<()> {
         if platform::is_online()
-            && self
-                .last_rekey
-                .is_some_and(|last_rekey| SystemTime::now().duration_since(last_rekey).unwrap_or(lifetime) >= lifetime)
+            && is_rekey_due(
+                self.last_rekey,
+                self.ipsec_session.lifetime,
+                MIN_ESP_LIFETIME,
+                SystemTime::now(),
+            )
         {
             debug!("Start rekeying IPSec tunnel");
-            self.do_esp_proposal().await?;
+            self.do_esp_rekey().await
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn do_esp_rekey(&mut self) -> anyhow::Result<()> {
+        self.do_esp_proposal().await?;
 
-            self.last_rekey = Some(SystemTime::now());
+        self.last_rekey = Some(SystemTime::now());
 
-            debug!(
-                "New ESP SPI: {:04x}, {:04x}",
-                self.ipsec_session.esp_in.spi, self.ipsec_session.esp_out.spi
-            );
+        debug!(
+            "New ESP SPI: {:04x}, {:04x}",
+            self.ipsec_session.esp_in.spi, self.ipsec_session.esp_out.spi
+        );
 
-            if let Some(ref mut sender) = self.command_sender {
-                Ok(sender.send(TunnelCommand::ReKey(self.ipsec_session.clone())).await?)
-            } else {
-                Err(anyhow!("No sender!"))
-            }
+        if let Some(ref mut sender) = self.command_sender {
+            Ok(sender.send(TunnelCommand::ReKey(self.ipsec_session.clone())).await?)
         } else {
-            Ok(())
+            Err(anyhow!("No sender!"))
         }
     }
 
-    async fn delete_sa(&mut self) -> anyhow::Result<()> {
-        self.service.delete_sa().await
+    async fn rekey_ike_sa(&mut self) -> anyhow::Result<()> {
+        if platform::is_online()
+            && is_rekey_due(
+                self.ike_established,
+                self.params.ike_lifetime,
+                MIN_IKE_LIFETIME,
+                SystemTime::now(),
+            )
+        {
+            debug!("Start rekeying IKE SA");
+            self.do_ike_rekey().await
+        } else {
+            Ok(())
+        }
     }
 
-    async fn is_multi_factor_cert_login_type(&self) -> anyhow::Result<bool> {
-        Ok(server_info::get_login_factors(&self.params)
-            .await?
-            .into_iter()
-            .any(|factor| factor.factor_type != "certificate"))
+    // phase 1 is renegotiated over the current transport and bound to the authenticated session,
+    // the gateway confirms the session in the following XAUTH and mode config exchanges.
+    // The ESP SAs are not affected and are renegotiated later over the new IKE SA.
+    // The previous IKE SA is left to expire on the gateway.
+    async fn do_ike_rekey(&mut self) -> anyhow::Result<()> {
+        do_main_mode(
+            &self.params,
+            &mut self.service,
+            self.gateway_address,
+            self.ccc_session.clone(),
+        )
+        .await?;
+
+        self.ike_established = Some(SystemTime::now());
+        self.last_identifier = 0;
+
+        let (attrs_reply, message_id) = self.service.get_auth_attributes().await?;
+        self.last_message_id = message_id;
+
+        match get_short_attribute(&attrs_reply, ConfigAttributeType::Status) {
+            Some(1) => {
+                self.service
+                    .send_ack_response(attrs_reply.identifier, self.last_message_id)
+                    .await?;
+            }
+            status => {
+                return Err(anyhow!(
+                    "Session is not accepted for the new IKE SA, status: {:?}",
+                    status
+                ))
+            }
+        }
+
+        let om_reply = self.service.send_om_request().await?;
+
+        let address: Ipv4Addr = get_long_attribute(&om_reply, ConfigAttributeType::Ipv4Address)
+            .ok_or_else(|| anyhow!("No IPv4 in reply!"))?
+            .reader()
+            .read_u32::<BigEndian>()?
+            .into();
+
+        if address != self.ipsec_session.address {
+            return Err(anyhow!("Tunnel address changed after IKE rekey: {}", address));
+        }
+
+        debug!("IKE SA rekeyed");
+
+        Ok(())
     }
-}
 
-#[async_trait]
-impl TunnelConnector for IpsecTunnelConnector {
-    async fn authenticate(&mut self) -> anyhow::Result<Arc<VpnSession>> {
-        let my_address = platform::get_default_ip().await?.parse::<Ipv4Addr>()?;
-        self.service.do_sa_proposal(self.params.ike_lifetime).await?;
-        self.service.do_key_exchange(my_address, self.gateway_address).await?;
-
-        let realm = AuthenticationRealm {
-            client_type: self.params.tunnel_type.as_client_type().to_owned(),
-            old_session_id: String::new(),
-            protocol_version: 100,
-            client_mode: self.params.client_mode.clone(),
-            selected_realm_id: self.params.login_type.clone(),
-            secondary_realm_hash: None,
-            client_logging_data: Some(ClientLoggingData {
-                os_name: Some("Windows".to_owned()),
-                device_id: Some(crate::util::get_device_id().into()),
-                ..Default::default()
-            }),
-        };
+    fn is_ike_sa_expired(&self) -> bool {
+        is_rekey_due(
+            self.ike_established,
+            self.params.ike_lifetime,
+            Duration::ZERO,
+            SystemTime::now(),
+        )
+    }
 
-        let realm_expr = SExpression::from(&realm);
+    async fn stop_tunnel(&mut self) -> anyhow::Result<()> {
+        if let Some(sender) = self.command_sender.take() {
+            let _ = sender.send(TunnelCommand::Terminate).await;
+        }
+        Ok(())
+    }
 
-        trace!("Authentication blob: {}", realm_expr);
+    async fn delete_sa(&mut self) -> anyhow::Result<()> {
+        self.service.delete_sa().await
+    }
 
-        self.service
-            .do_identity_protection(
-                self.gateway_address,
-                Bytes::copy_from_slice(realm_expr.to_string().as_bytes()),
-                self.params.ipsec_cert_check,
-                &self.params.ca_cert,
-            )
-            .await?;
+    async fn do_authenticate(&mut self, old_session_id: String) -> anyhow::Result<Arc<VpnSession>> {
+        do_main_mode(&self.params, &mut self.service, self.gateway_address, old_session_id).await?;
+        self.ike_established = Some(SystemTime::now());
 
         if self.params.cert_type == CertType::None || self.is_multi_factor_cert_login_type().await.unwrap_or(false) {
             debug!("Awaiting authentication factors");
@@ -431,6 +827,32 @@ impl TunnelConnector for IpsecTunnelConnector {
         }
     }
 
+    async fn is_multi_factor_cert_login_type(&self) -> anyhow::Result<bool> {
+        Ok(server_info::get_login_factors(&self.params)
+            .await?
+            .into_iter()
+            .any(|factor| factor.factor_type != "certificate"))
+    }
+}
+
+#[async_trait]
+impl TunnelConnector for IpsecTunnelConnector {
+    async fn authenticate(&mut self) -> anyhow::Result<Arc<VpnSession>> {
+        if let Some(old_session_id) = ResumableSession::resumable_session_id(&self.params) {
+            debug!("Trying to resume the previous session");
+            match self.do_authenticate(old_session_id).await {
+                Ok(session) => return Ok(session),
+                Err(e) => {
+                    warn!("Session resumption failed, falling back to full authentication: {}", e);
+                    ResumableSession::forget();
+                    self.reset_service().await?;
+                }
+            }
+        }
+
+        self.do_authenticate(String::new()).await
+    }
+
     async fn challenge_code(&mut self, _session: Arc<VpnSession>, user_input: &str) -> anyhow::Result<Arc<VpnSession>> {
         let id_reply = self
             .service
@@ -452,14 +874,19 @@ impl TunnelConnector for IpsecTunnelConnector {
         command_sender: Sender<TunnelCommand>,
     ) -> anyhow::Result<Box<dyn VpnTunnel + Send>> {
         self.command_sender = Some(command_sender);
-        Ok(Box::new(IpsecTunnel::create(self.params.clone(), session).await?))
+        Ok(Box::new(
+            IpsecTunnel::create(self.params.clone(), session, &self.endpoints, self.transport_type).await?,
+        ))
     }
 
     async fn terminate_tunnel(&mut self) -> anyhow::Result<()> {
-        if let Some(sender) = self.command_sender.take() {
-            let _ = sender.send(TunnelCommand::Terminate).await;
-        }
-        Ok(())
+        // explicit disconnect ends the session, it should not be resumed later
+        ResumableSession::forget();
+        self.stop_tunnel().await
+    }
+
+    async fn update_session(&mut self, _session: Arc<VpnSession>) -> anyhow::Result<()> {
+        Err(anyhow!("Session update is not supported for IPSec tunnel!"))
     }
 
     async fn handle_tunnel_event(&mut self, event: TunnelEvent) -> anyhow::Result<()> {
@@ -472,11 +899,33 @@ impl TunnelConnector for IpsecTunnelConnector {
                 let _ = self.delete_sa().await;
             }
             TunnelEvent::RekeyCheck => {
+                if let Err(e) = self.rekey_ike_sa().await {
+                    // the current IKE SA remains usable until it expires, the rekey is retried on the next check
+                    if self.is_ike_sa_expired() {
+                        self.reconnect(format!("IKE SA expired, rekey failed: {}", e)).await?;
+                    } else {
+                        warn!("IKE SA rekey failed: {}", e);
+                    }
+                }
                 self.rekey_tunnel().await?;
             }
             TunnelEvent::RemoteControlData(data) => {
                 self.parse_isakmp(data).await?;
             }
+            TunnelEvent::RemoteDisconnected(code, message) => {
+                debug!(
+                    "Tunnel disconnected by the gateway, code: {}, message: {}",
+                    code, message
+                );
+            }
+            TunnelEvent::Configured(_) | TunnelEvent::ChallengeRequired(_) | TunnelEvent::Statistics(_) => {}
+            TunnelEvent::AddressChanged(address) => {
+                // the IKE socket is bound to the previous address, the next ESP rekey would not get through
+                debug!("Local address changed to {}, renegotiating IKE SA", address);
+                if let Err(e) = self.do_ike_rekey().await {
+                    warn!("IKE SA renegotiation failed: {}", e);
+                }
+            }
         }
         Ok(())
     }
@@ -488,9 +937,126 @@ impl Drop for IpsecTunnelConnector {
             s.spawn(|| {
                 crate::util::block_on(async {
                     self.delete_sa().await?;
-                    self.terminate_tunnel().await
+                    self.stop_tunnel().await
                 })
             });
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resumable_session() {
+        let mut params = TunnelParams {
+            server_name: "vpn.example.com".to_owned(),
+            user_name: "user".to_owned(),
+            ike_lifetime: Duration::from_secs(3600),
+            ..Default::default()
+        };
+
+        let session = ResumableSession::new(&params, "session-id");
+        let now = session.authenticated_at;
+
+        assert!(session.is_valid_for(&params, now + Duration::from_secs(60)));
+        assert!(!session.is_valid_for(&params, now + Duration::from_secs(3600)));
+
+        params.user_name = "other".to_owned();
+        assert!(!session.is_valid_for(&params, now));
+    }
+
+    #[test]
+    fn test_gateway_request() {
+        use isakmp::payload::{DeletePayload, NotificationPayload};
+
+        let delete = |protocol_id, spi: u32| {
+            Payload::Delete(DeletePayload {
+                doi: 1,
+                protocol_id,
+                spi_size: 4,
+                spis: vec![Bytes::copy_from_slice(&spi.to_be_bytes())],
+            })
+        };
+
+        let esp_spis = [0x1111, 0x2222];
+
+        assert_eq!(get_gateway_request(&[], &esp_spis), GatewayRequest::None);
+        assert_eq!(
+            get_gateway_request(&[delete(ProtocolId::IpsecEsp, 0x3333)], &esp_spis),
+            GatewayRequest::None
+        );
+        assert_eq!(
+            get_gateway_request(&[delete(ProtocolId::IpsecEsp, 0x2222)], &esp_spis),
+            GatewayRequest::RekeyEsp
+        );
+        assert_eq!(
+            get_gateway_request(&[delete(ProtocolId::Isakmp, 0)], &esp_spis),
+            GatewayRequest::RekeyIke
+        );
+        assert!(matches!(
+            get_gateway_request(
+                &[delete(ProtocolId::IpsecEsp, 0x1111), delete(ProtocolId::Isakmp, 0)],
+                &esp_spis
+            ),
+            GatewayRequest::Reconnect(..)
+        ));
+
+        let notify = Payload::Notification(NotificationPayload {
+            doi: 1,
+            protocol_id: ProtocolId::IpsecEsp,
+            message_type: NotifyMessageType::InvalidSpi.into(),
+            spi: Bytes::new(),
+            data: Bytes::new(),
+        });
+        assert_eq!(get_gateway_request(&[notify], &esp_spis), GatewayRequest::RekeyEsp);
+
+        let notify = Payload::Notification(NotificationPayload {
+            doi: 1,
+            protocol_id: ProtocolId::Isakmp,
+            message_type: NotifyMessageType::AuthenticationFailed.into(),
+            spi: Bytes::new(),
+            data: Bytes::new(),
+        });
+        assert!(matches!(
+            get_gateway_request(&[notify], &esp_spis),
+            GatewayRequest::Reconnect(..)
+        ));
+    }
+
+    #[test]
+    fn test_is_rekey_due() {
+        let now = SystemTime::now();
+        let lifetime = Duration::from_secs(28800);
+
+        assert!(!is_rekey_due(None, lifetime, MIN_IKE_LIFETIME, now));
+        assert!(!is_rekey_due(
+            Some(now),
+            lifetime,
+            MIN_IKE_LIFETIME,
+            now + Duration::from_secs(28000)
+        ));
+        assert!(is_rekey_due(
+            Some(now),
+            lifetime,
+            MIN_IKE_LIFETIME,
+            now + Duration::from_secs(28500)
+        ));
+        assert!(is_rekey_due(Some(now), lifetime, Duration::ZERO, now + lifetime));
+
+        // lifetime shorter than the margin
+        assert!(!is_rekey_due(
+            Some(now),
+            Duration::from_secs(120),
+            MIN_IKE_LIFETIME,
+            now + Duration::from_secs(30)
+        ));
+        assert!(is_rekey_due(
+            Some(now),
+            Duration::from_secs(120),
+            MIN_IKE_LIFETIME,
+            now + Duration::from_secs(60)
+        ));
+    }
+}
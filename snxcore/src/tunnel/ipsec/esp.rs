@@ -0,0 +1,358 @@
+use anyhow::anyhow;
+use bytes::{BufMut, Bytes, BytesMut};
+use isakmp::model::EspCryptMaterial;
+use openssl::{
+    hash::MessageDigest,
+    memcmp,
+    pkey::{PKey, Private},
+    sign::Signer,
+    symm::{Cipher, Crypter, Mode},
+};
+
+use crate::transform::{EspEncryption, EspIntegrity, EspTransform};
+
+const ESP_HEADER_LEN: usize = 8;
+const REPLAY_WINDOW_SIZE: u32 = 64;
+const NEXT_HEADER_IPV4: u8 = 4;
+const NEXT_HEADER_IPV6: u8 = 41;
+const NEXT_HEADER_NONE: u8 = 59;
+
+/// Sliding window of the received sequence numbers, as described in RFC 4303, appendix A
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    last: u32,
+    bitmap: u64,
+}
+
+impl ReplayWindow {
+    fn check(&self, seq: u32) -> bool {
+        if seq == 0 {
+            return false;
+        }
+        if seq > self.last {
+            return true;
+        }
+        let diff = self.last - seq;
+        diff < REPLAY_WINDOW_SIZE && self.bitmap & (1 << diff) == 0
+    }
+
+    fn update(&mut self, seq: u32) {
+        if seq > self.last {
+            let shift = seq - self.last;
+            self.bitmap = if shift < REPLAY_WINDOW_SIZE {
+                (self.bitmap << shift) | 1
+            } else {
+                1
+            };
+            self.last = seq;
+        } else {
+            self.bitmap |= 1 << (self.last - seq);
+        }
+    }
+}
+
+fn message_digest(integrity: EspIntegrity) -> MessageDigest {
+    match integrity {
+        EspIntegrity::HmacSha1 => MessageDigest::sha1(),
+        EspIntegrity::HmacSha256 => MessageDigest::sha256(),
+        EspIntegrity::HmacSha384 => MessageDigest::sha384(),
+        EspIntegrity::HmacSha512 => MessageDigest::sha512(),
+    }
+}
+
+/// ESP security association in tunnel mode with CBC or CTR encryption and HMAC authentication,
+/// or with AES-GCM authenticated encryption
+pub struct EspSa {
+    spi: u32,
+    encryption: EspEncryption,
+    cipher: Cipher,
+    enc_key: Vec<u8>,
+    salt: Vec<u8>,
+    hmac: Option<(MessageDigest, PKey<Private>)>,
+    icv_len: usize,
+    seq: u32,
+    replay: ReplayWindow,
+}
+
+impl EspSa {
+    pub fn new(material: &EspCryptMaterial) -> anyhow::Result<Self> {
+        let transform = EspTransform::new(material)?;
+        let (enc_key, salt) = material.sk_e.split_at(transform.key_len);
+
+        Self::with_params(
+            material.spi,
+            transform.encryption,
+            enc_key,
+            salt,
+            transform.integrity.map(|integrity| (integrity, material.sk_a.as_ref())),
+            transform.icv_len,
+        )
+    }
+
+    fn with_params(
+        spi: u32,
+        encryption: EspEncryption,
+        enc_key: &[u8],
+        salt: &[u8],
+        integrity: Option<(EspIntegrity, &[u8])>,
+        icv_len: usize,
+    ) -> anyhow::Result<Self> {
+        let cipher = match (encryption, enc_key.len()) {
+            (EspEncryption::AesCbc, 16) => Cipher::aes_128_cbc(),
+            (EspEncryption::AesCbc, 24) => Cipher::aes_192_cbc(),
+            (EspEncryption::AesCbc, 32) => Cipher::aes_256_cbc(),
+            (EspEncryption::TripleDesCbc, 24) => Cipher::des_ede3_cbc(),
+            (EspEncryption::AesCtr, 16) => Cipher::aes_128_ctr(),
+            (EspEncryption::AesCtr, 24) => Cipher::aes_192_ctr(),
+            (EspEncryption::AesCtr, 32) => Cipher::aes_256_ctr(),
+            (EspEncryption::AesGcm, 16) => Cipher::aes_128_gcm(),
+            (EspEncryption::AesGcm, 24) => Cipher::aes_192_gcm(),
+            (EspEncryption::AesGcm, 32) => Cipher::aes_256_gcm(),
+            (other, len) => {
+                return Err(anyhow!(
+                    "Unsupported ESP cipher: {}, key length: {}",
+                    other.as_str(),
+                    len
+                ))
+            }
+        };
+
+        if salt.len() != encryption.salt_len() {
+            return Err(anyhow!("Invalid {} salt length: {}", encryption.as_str(), salt.len()));
+        }
+
+        let hmac = match integrity {
+            Some((integrity, key)) => Some((message_digest(integrity), PKey::hmac(key)?)),
+            None if encryption.is_aead() => None,
+            None => return Err(anyhow!("No integrity algorithm for {}", encryption.as_str())),
+        };
+
+        Ok(Self {
+            spi,
+            encryption,
+            cipher,
+            enc_key: enc_key.to_vec(),
+            salt: salt.to_vec(),
+            hmac,
+            icv_len,
+            seq: 0,
+            replay: ReplayWindow::default(),
+        })
+    }
+
+    pub fn spi(&self) -> u32 {
+        self.spi
+    }
+
+    fn icv(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let (digest, key) = self.hmac.as_ref().ok_or_else(|| anyhow!("No HMAC key"))?;
+        let mut signer = Signer::new(*digest, key)?;
+        signer.update(data)?;
+        let mut icv = signer.sign_to_vec()?;
+        icv.truncate(self.icv_len);
+        Ok(icv)
+    }
+
+    // IV of the cipher: CBC uses the explicit IV, CTR and GCM prepend the salt to it
+    fn cipher_iv(&self, iv: &[u8]) -> Vec<u8> {
+        let mut cipher_iv = [self.salt.as_slice(), iv].concat();
+        if self.encryption == EspEncryption::AesCtr {
+            cipher_iv.extend_from_slice(&1u32.to_be_bytes());
+        }
+        cipher_iv
+    }
+
+    fn crypt(&self, mode: Mode, iv: &[u8], aad: &[u8], data: &[u8], tag: Option<&[u8]>) -> anyhow::Result<Vec<u8>> {
+        let mut crypter = Crypter::new(self.cipher, mode, &self.enc_key, Some(&self.cipher_iv(iv)))?;
+        crypter.pad(false);
+
+        if self.encryption.is_aead() {
+            crypter.aad_update(aad)?;
+            if let Some(tag) = tag {
+                crypter.set_tag(tag)?;
+            }
+        }
+
+        let mut output = vec![0u8; data.len() + self.cipher.block_size()];
+        let mut size = crypter.update(data, &mut output)?;
+        // tag mismatch of the AEAD cipher is reported by finalize
+        size += crypter.finalize(&mut output[size..]).map_err(|e| {
+            if self.encryption.is_aead() {
+                anyhow!("ESP integrity check failed")
+            } else {
+                e.into()
+            }
+        })?;
+        output.truncate(size);
+
+        if self.encryption.is_aead() && matches!(mode, Mode::Encrypt) {
+            let mut tag = vec![0u8; self.icv_len];
+            crypter.get_tag(&mut tag)?;
+            output.extend_from_slice(&tag);
+        }
+
+        Ok(output)
+    }
+
+    /// Encapsulate the IP packet into the ESP payload
+    pub fn encrypt(&mut self, packet: &[u8]) -> anyhow::Result<Bytes> {
+        self.seq = self
+            .seq
+            .checked_add(1)
+            .ok_or_else(|| anyhow!("ESP sequence number overflow"))?;
+
+        let next_header = match packet.first().map(|b| b >> 4) {
+            Some(6) => NEXT_HEADER_IPV6,
+            _ => NEXT_HEADER_IPV4,
+        };
+
+        let block_size = self.encryption.block_size();
+        let pad_len = (block_size - (packet.len() + 2) % block_size) % block_size;
+
+        let mut plaintext = Vec::with_capacity(packet.len() + pad_len + 2);
+        plaintext.extend_from_slice(packet);
+        plaintext.extend((1..=pad_len).map(|i| i as u8));
+        plaintext.push(pad_len as u8);
+        plaintext.push(next_header);
+
+        // counter modes require unique IVs, which is guaranteed by the sequence number
+        let iv = match self.encryption {
+            EspEncryption::AesCtr | EspEncryption::AesGcm => (self.seq as u64).to_be_bytes().to_vec(),
+            _ => {
+                let mut iv = vec![0u8; self.encryption.iv_len()];
+                openssl::rand::rand_bytes(&mut iv)?;
+                iv
+            }
+        };
+
+        let mut output = BytesMut::with_capacity(ESP_HEADER_LEN + iv.len() + plaintext.len() + self.icv_len);
+        output.put_u32(self.spi);
+        output.put_u32(self.seq);
+
+        let ciphertext = self.crypt(Mode::Encrypt, &iv, &output, &plaintext, None)?;
+
+        output.put_slice(&iv);
+        output.put_slice(&ciphertext);
+
+        if !self.encryption.is_aead() {
+            let icv = self.icv(&output)?;
+            output.put_slice(&icv);
+        }
+
+        Ok(output.freeze())
+    }
+
+    /// Decapsulate the ESP payload, returns None for the dummy packets
+    pub fn decrypt(&mut self, data: &[u8]) -> anyhow::Result<Option<Bytes>> {
+        let block_size = self.encryption.block_size();
+        let iv_len = self.encryption.iv_len();
+
+        if data.len() < ESP_HEADER_LEN + iv_len + block_size + self.icv_len {
+            return Err(anyhow!("ESP packet is too short: {}", data.len()));
+        }
+
+        let seq = u32::from_be_bytes(data[4..8].try_into()?);
+        if !self.replay.check(seq) {
+            return Err(anyhow!("Replayed ESP packet, sequence: {}", seq));
+        }
+
+        let (signed, icv) = data.split_at(data.len() - self.icv_len);
+        if !self.encryption.is_aead() && !memcmp::eq(&self.icv(signed)?, icv) {
+            return Err(anyhow!("ESP integrity check failed"));
+        }
+
+        let (header, payload) = signed.split_at(ESP_HEADER_LEN);
+        let (iv, ciphertext) = payload.split_at(iv_len);
+        if ciphertext.len() % block_size != 0 {
+            return Err(anyhow!("Invalid ESP payload length: {}", ciphertext.len()));
+        }
+
+        let mut plaintext = self.crypt(Mode::Decrypt, iv, header, ciphertext, Some(icv))?;
+
+        self.replay.update(seq);
+
+        let next_header = plaintext[plaintext.len() - 1];
+        let pad_len = plaintext[plaintext.len() - 2] as usize;
+
+        if pad_len + 2 > plaintext.len() {
+            return Err(anyhow!("Invalid ESP padding length: {}", pad_len));
+        }
+
+        if next_header == NEXT_HEADER_NONE {
+            return Ok(None);
+        }
+
+        plaintext.truncate(plaintext.len() - pad_len - 2);
+
+        Ok(Some(plaintext.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_sa(encryption: EspEncryption, key_len: usize, integrity: Option<EspIntegrity>, icv_len: usize) -> EspSa {
+        let auth_key = vec![0x22; integrity.map_or(0, |i| i.key_len())];
+        EspSa::with_params(
+            0x1234_5678,
+            encryption,
+            &vec![0x11; key_len],
+            &vec![0x33; encryption.salt_len()],
+            integrity.map(|i| (i, auth_key.as_slice())),
+            icv_len,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::default();
+        assert!(!window.check(0));
+
+        for seq in [1, 3, 2, 100] {
+            assert!(window.check(seq));
+            window.update(seq);
+            assert!(!window.check(seq));
+        }
+
+        assert!(window.check(99));
+        assert!(window.check(37));
+        assert!(!window.check(36));
+        assert!(!window.check(3));
+    }
+
+    #[test]
+    fn test_esp_roundtrip() {
+        let mut packet = (0..=255u8).cycle().take(1000).collect::<Vec<_>>();
+        packet[0] = 0x45;
+
+        for (encryption, key_len, integrity, icv_len) in [
+            (EspEncryption::AesCbc, 16, Some(EspIntegrity::HmacSha256), 16),
+            (EspEncryption::AesCbc, 32, Some(EspIntegrity::HmacSha1), 12),
+            (EspEncryption::TripleDesCbc, 24, Some(EspIntegrity::HmacSha1), 12),
+            (EspEncryption::AesCtr, 32, Some(EspIntegrity::HmacSha512), 32),
+            (EspEncryption::AesGcm, 16, None, 16),
+            (EspEncryption::AesGcm, 32, None, 8),
+        ] {
+            let mut sender = new_sa(encryption, key_len, integrity, icv_len);
+            let mut receiver = new_sa(encryption, key_len, integrity, icv_len);
+
+            let encrypted = sender.encrypt(&packet).unwrap();
+            assert_eq!(&encrypted[0..4], &0x1234_5678u32.to_be_bytes());
+            assert_eq!(&encrypted[4..8], &1u32.to_be_bytes());
+            assert_eq!((encrypted.len() - 8 - icv_len) % encryption.block_size(), 0);
+
+            let decrypted = receiver.decrypt(&encrypted).unwrap().unwrap();
+            assert_eq!(decrypted.as_ref(), packet.as_slice());
+
+            // replayed packet is rejected
+            assert!(receiver.decrypt(&encrypted).is_err());
+
+            // tampered packet is rejected
+            let mut tampered = sender.encrypt(&packet).unwrap().to_vec();
+            tampered[20] ^= 1;
+            assert!(receiver.decrypt(&tampered).is_err());
+        }
+    }
+}
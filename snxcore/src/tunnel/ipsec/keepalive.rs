@@ -4,7 +4,7 @@ use std::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::anyhow;
@@ -13,6 +13,7 @@ use tracing::{debug, trace, warn};
 use crate::{
     model::params::TunnelParams,
     platform::{self, UdpSocketExt},
+    tunnel::stats::TunnelCounters,
 };
 
 const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(20);
@@ -21,7 +22,7 @@ const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(5);
 const KEEPALIVE_MAX_RETRIES: u32 = 5;
 
 // picked from wireshark logs
-fn make_keepalive_packet() -> [u8; 84] {
+pub(crate) fn make_keepalive_packet() -> [u8; 84] {
     let mut data = [0u8; 84];
 
     // 0x00000011 looks like a packet type, KEEPALIVE in this case
@@ -43,11 +44,17 @@ pub struct KeepaliveRunner {
     src: Ipv4Addr,
     dst: Ipv4Addr,
     ready: Arc<AtomicBool>,
+    counters: Arc<TunnelCounters>,
 }
 
 impl KeepaliveRunner {
-    pub fn new(src: Ipv4Addr, dst: Ipv4Addr, ready: Arc<AtomicBool>) -> Self {
-        Self { src, dst, ready }
+    pub fn new(src: Ipv4Addr, dst: Ipv4Addr, ready: Arc<AtomicBool>, counters: Arc<TunnelCounters>) -> Self {
+        Self {
+            src,
+            dst,
+            ready,
+            counters,
+        }
     }
 
     pub async fn run(&self) -> anyhow::Result<()> {
@@ -67,12 +74,15 @@ impl KeepaliveRunner {
                 trace!("Sending keepalive to {}", self.dst);
 
                 let data = make_keepalive_packet();
+                let started = Instant::now();
                 let result = udp.send_receive(&data, KEEPALIVE_TIMEOUT).await;
 
                 if let Ok(reply) = result {
                     trace!("Received keepalive response from {}, size: {}", self.dst, reply.len());
+                    self.counters.set_keepalive_rtt(started.elapsed());
                     num_failures = 0;
                 } else {
+                    self.counters.keepalive_failed();
                     num_failures += 1;
                     if num_failures >= KEEPALIVE_MAX_RETRIES {
                         warn!("Maximum number of keepalive retries reached, exiting");
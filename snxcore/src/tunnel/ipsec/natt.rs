@@ -1,4 +1,4 @@
-use std::{net::Ipv4Addr, sync::Arc, time::Duration};
+use std::{net::IpAddr, sync::Arc, time::Duration};
 
 use anyhow::anyhow;
 use bytes::Bytes;
@@ -8,18 +8,18 @@ use tokio::{
 };
 use tracing::debug;
 
-use crate::{platform::UdpSocketExt, tunnel::TunnelEvent};
+use crate::{happy_eyeballs, platform::UdpSocketExt, tunnel::TunnelEvent};
 
 const MAX_NATT_PROBES: usize = 3;
 
 pub struct NattProber {
-    address: Ipv4Addr,
+    address: IpAddr,
     port: u16,
 }
 
 impl NattProber {
-    pub fn new(address: Ipv4Addr) -> Self {
-        Self { address, port: 4500 }
+    pub fn new(address: IpAddr, port: u16) -> Self {
+        Self { address, port }
     }
 
     pub async fn probe(&self) -> anyhow::Result<()> {
@@ -32,9 +32,9 @@ impl NattProber {
     }
 
     async fn send_probe(&self) -> anyhow::Result<()> {
-        debug!("Sending NAT-T probe to {}", self.address);
-        let udp = UdpSocket::bind("0.0.0.0:0").await?;
-        udp.connect(format!("{}:{}", self.address, self.port)).await?;
+        debug!("Sending NAT-T probe to {}:{}", self.address, self.port);
+        let udp = happy_eyeballs::bind_udp(self.address).await?;
+        udp.connect((self.address, self.port)).await?;
 
         let data = vec![0u8; 32];
 
@@ -89,3 +89,30 @@ pub async fn start_natt_listener(
 
     Ok(tx)
 }
+
+// with the userspace ESP datapath the socket is read by the datapath, which passes the non-ESP packets here
+pub fn start_control_forwarder(
+    mut receiver: mpsc::Receiver<Bytes>,
+    sender: mpsc::Sender<TunnelEvent>,
+) -> oneshot::Sender<()> {
+    let (tx, mut rx) = oneshot::channel();
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                data = receiver.recv() => match data {
+                    Some(data) => {
+                        let _ = sender.send(TunnelEvent::RemoteControlData(data)).await;
+                    }
+                    None => break,
+                },
+                _ = &mut rx => {
+                    break;
+                }
+            }
+        }
+        debug!("Control forwarder stopped");
+    });
+
+    tx
+}
@@ -0,0 +1,122 @@
+use std::{
+    future::Future,
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{net::UdpSocket, sync::mpsc};
+use tracing::{trace, warn};
+
+use crate::{
+    model::params::TunnelParams,
+    mtu::MIN_TUNNEL_MTU,
+    platform::{self, UdpSocketExt},
+    tunnel::ipsec::keepalive::make_keepalive_packet,
+};
+
+const PMTU_PROBE_INTERVAL: Duration = Duration::from_secs(60);
+const PMTU_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+const PMTU_PROBE_RETRIES: u32 = 2;
+const IP_UDP_HEADER_LEN: u16 = 28;
+
+/// Path MTU black hole detection. Keepalive packets padded to the tunnel MTU are sent through the tunnel;
+/// if they are lost while the minimal ones get through, the largest working MTU is reported to the tunnel.
+pub struct PmtuProber {
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    mtu: u16,
+    ready: Arc<AtomicBool>,
+}
+
+impl PmtuProber {
+    pub fn new(src: Ipv4Addr, dst: Ipv4Addr, mtu: u16, ready: Arc<AtomicBool>) -> Self {
+        Self { src, dst, mtu, ready }
+    }
+
+    pub async fn run(mut self, sender: mpsc::Sender<u16>) -> anyhow::Result<()> {
+        let udp = UdpSocket::bind((self.src, 0)).await?;
+        udp.connect((self.dst, TunnelParams::IPSEC_KEEPALIVE_PORT)).await?;
+        udp.set_no_check(true)?;
+        udp.set_dont_fragment(true)?;
+
+        loop {
+            tokio::time::sleep(PMTU_PROBE_INTERVAL).await;
+
+            if !platform::is_online() || !self.ready.load(Ordering::SeqCst) || self.mtu <= MIN_TUNNEL_MTU {
+                continue;
+            }
+
+            if let Some(mtu) = self.detect(&udp).await {
+                warn!(
+                    "Path MTU black hole detected, lowering tunnel MTU from {} to {}",
+                    self.mtu, mtu
+                );
+                self.mtu = mtu;
+                sender.send(mtu).await?;
+            }
+        }
+    }
+
+    async fn detect(&self, udp: &UdpSocket) -> Option<u16> {
+        // no reply to the minimal probe means the tunnel is down or the gateway ignores the padded packets
+        if probe(udp, self.mtu).await || !probe(udp, MIN_TUNNEL_MTU).await {
+            return None;
+        }
+
+        Some(search_mtu(MIN_TUNNEL_MTU, self.mtu, |size| probe(udp, size)).await)
+    }
+}
+
+async fn probe(udp: &UdpSocket, size: u16) -> bool {
+    let mut data = make_keepalive_packet().to_vec();
+    data.resize(data.len().max(size.saturating_sub(IP_UDP_HEADER_LEN) as usize), 0);
+
+    for _ in 0..PMTU_PROBE_RETRIES {
+        if udp.send_receive(&data, PMTU_PROBE_TIMEOUT).await.is_ok() {
+            trace!("PMTU probe of size {} succeeded", size);
+            return true;
+        }
+    }
+    trace!("PMTU probe of size {} failed", size);
+
+    false
+}
+
+// the probe is known to succeed with the low size and to fail with the high one
+async fn search_mtu<F, R>(mut low: u16, mut high: u16, mut probe: F) -> u16
+where
+    F: FnMut(u16) -> R,
+    R: Future<Output = bool>,
+{
+    while high - low > 1 {
+        let mid = low + (high - low) / 2;
+        if probe(mid).await {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    low
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_search_mtu() {
+        let mut probes = 0;
+        let mtu = search_mtu(MIN_TUNNEL_MTU, 1422, |size| {
+            probes += 1;
+            async move { size <= 1350 }
+        })
+        .await;
+
+        assert_eq!(mtu, 1350);
+        assert!(probes <= 10);
+    }
+}
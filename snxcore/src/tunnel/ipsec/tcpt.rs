@@ -0,0 +1,386 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use isakmp::{
+    message::{IsakmpMessage, IsakmpMessageCodec},
+    transport::IsakmpTransport,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::{Mutex, Notify},
+};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use tracing::{debug, trace, warn};
+
+use crate::{model::params::TunnelParams, proxy};
+
+const TCPT_VERSION: u32 = 1;
+const TCPT_HEADER_LEN: usize = 8;
+const MAX_FRAME_SIZE: usize = 65536;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Type of the data carried by the TCPT frame
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TcptDataType {
+    Command,
+    Ike,
+    Esp,
+}
+
+impl TcptDataType {
+    fn as_u32(&self) -> u32 {
+        match self {
+            Self::Command => 1,
+            Self::Ike => 2,
+            Self::Esp => 3,
+        }
+    }
+}
+
+impl TryFrom<u32> for TcptDataType {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Self::Command),
+            2 => Ok(Self::Ike),
+            3 => Ok(Self::Esp),
+            other => Err(anyhow!("Unknown TCPT data type: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TcptPacket {
+    pub data_type: TcptDataType,
+    pub data: Bytes,
+}
+
+impl TcptPacket {
+    pub fn new(data_type: TcptDataType, data: Bytes) -> Self {
+        Self { data_type, data }
+    }
+}
+
+/// TCPT framing: 4 bytes of payload length and 4 bytes of data type, followed by the payload
+pub struct TcptCodec;
+
+impl Decoder for TcptCodec {
+    type Item = TcptPacket;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.remaining() < TCPT_HEADER_LEN {
+            return Ok(None);
+        }
+
+        let len = u32::from_be_bytes(src[0..4].try_into()?) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(anyhow!("TCPT frame is too large: {}", len));
+        }
+
+        if src.remaining() < TCPT_HEADER_LEN + len {
+            src.reserve(TCPT_HEADER_LEN + len - src.remaining());
+            return Ok(None);
+        }
+
+        let data_type = u32::from_be_bytes(src[4..8].try_into()?).try_into()?;
+
+        let mut packet = src.split_to(TCPT_HEADER_LEN + len);
+        packet.advance(TCPT_HEADER_LEN);
+
+        Ok(Some(TcptPacket::new(data_type, packet.freeze())))
+    }
+}
+
+impl Encoder<TcptPacket> for TcptCodec {
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, item: TcptPacket, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.reserve(TCPT_HEADER_LEN + item.data.len());
+
+        dst.put_u32(item.data.len() as u32);
+        dst.put_u32(item.data_type.as_u32());
+        dst.put_slice(&item.data);
+
+        Ok(())
+    }
+}
+
+// the client requests the type of data for the connection, the gateway replies with zero status on success
+async fn handshake<S>(stream: S, data_type: TcptDataType) -> anyhow::Result<Framed<S, TcptCodec>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut framed = Framed::new(stream, TcptCodec);
+
+    let mut request = BytesMut::with_capacity(8);
+    request.put_u32(TCPT_VERSION);
+    request.put_u32(data_type.as_u32());
+
+    framed
+        .send(TcptPacket::new(TcptDataType::Command, request.freeze()))
+        .await?;
+
+    let reply = tokio::time::timeout(HANDSHAKE_TIMEOUT, framed.next())
+        .await?
+        .ok_or_else(|| anyhow!("TCPT connection closed during handshake"))??;
+
+    if reply.data_type != TcptDataType::Command || reply.data.len() < 4 {
+        return Err(anyhow!("Invalid TCPT handshake reply"));
+    }
+
+    match u32::from_be_bytes(reply.data[0..4].try_into()?) {
+        0 => Ok(framed),
+        status => Err(anyhow!("TCPT handshake failed, status: {}", status)),
+    }
+}
+
+/// Open TCPT connection to the gateway for the given type of data
+pub async fn connect(
+    params: &TunnelParams,
+    address: &str,
+    port: u16,
+    data_type: TcptDataType,
+) -> anyhow::Result<Framed<TcpStream, TcptCodec>> {
+    debug!(
+        "Connecting to {}:{} via TCPT, data type: {:?}",
+        address, port, data_type
+    );
+
+    let stream = proxy::connect(params, address, port).await?;
+    stream.set_nodelay(true)?;
+
+    handshake(stream, data_type).await
+}
+
+/// IKE transport over the TCPT connection
+pub struct TcptTransport<C> {
+    stream: Framed<TcpStream, TcptCodec>,
+    codec: C,
+}
+
+impl<C: IsakmpMessageCodec> TcptTransport<C> {
+    pub fn new(stream: Framed<TcpStream, TcptCodec>, codec: C) -> Self {
+        Self { stream, codec }
+    }
+
+    async fn receive(&mut self) -> anyhow::Result<IsakmpMessage> {
+        loop {
+            let packet = self
+                .stream
+                .next()
+                .await
+                .ok_or_else(|| anyhow!("TCPT connection closed"))??;
+
+            if packet.data_type != TcptDataType::Ike {
+                trace!("Ignoring TCPT packet of type {:?}", packet.data_type);
+                continue;
+            }
+
+            if let Some(message) = self.codec.decode(&packet.data)? {
+                return Ok(message);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<C: IsakmpMessageCodec + Send + Sync> IsakmpTransport for TcptTransport<C> {
+    async fn send(&mut self, message: &IsakmpMessage) -> anyhow::Result<()> {
+        let data = self.codec.encode(message);
+        self.stream.send(TcptPacket::new(TcptDataType::Ike, data)).await
+    }
+
+    async fn send_receive(&mut self, message: &IsakmpMessage, timeout: Duration) -> anyhow::Result<IsakmpMessage> {
+        self.send(message).await?;
+        tokio::time::timeout(timeout, self.receive()).await?
+    }
+}
+
+/// TCPT connection which carries ESP packets, shared between the datapath tasks.
+/// When the connection is lost the receiver waits until it is reconnected by the tunnel.
+pub struct TcptEspStream {
+    params: Arc<TunnelParams>,
+    address: String,
+    port: u16,
+    reader: Mutex<SplitStream<Framed<TcpStream, TcptCodec>>>,
+    writer: Mutex<SplitSink<Framed<TcpStream, TcptCodec>, TcptPacket>>,
+    lost: Notify,
+    reconnected: Notify,
+}
+
+impl TcptEspStream {
+    pub async fn connect(params: Arc<TunnelParams>, address: &str, port: u16) -> anyhow::Result<Self> {
+        let (writer, reader) = connect(&params, address, port, TcptDataType::Esp).await?.split();
+        Ok(Self {
+            params,
+            address: address.to_owned(),
+            port,
+            reader: Mutex::new(reader),
+            writer: Mutex::new(writer),
+            lost: Notify::new(),
+            reconnected: Notify::new(),
+        })
+    }
+
+    /// Resolves when the receiver detects that the connection is closed
+    pub async fn lost(&self) {
+        self.lost.notified().await
+    }
+
+    /// Open the new connection in place of the lost one, the receiver continues with it
+    pub async fn reconnect(&self) -> anyhow::Result<()> {
+        let (writer, reader) = connect(&self.params, &self.address, self.port, TcptDataType::Esp)
+            .await?
+            .split();
+
+        *self.writer.lock().await = writer;
+        *self.reader.lock().await = reader;
+        self.reconnected.notify_one();
+
+        Ok(())
+    }
+
+    pub async fn send(&self, data: Bytes) -> anyhow::Result<()> {
+        self.writer
+            .lock()
+            .await
+            .send(TcptPacket::new(TcptDataType::Esp, data))
+            .await
+    }
+
+    pub async fn recv(&self) -> anyhow::Result<Bytes> {
+        loop {
+            // the reader lock is released at the end of the statement, before waiting for the reconnect
+            // which replaces the reader
+            let result = self.reader.lock().await.next().await;
+
+            match result {
+                Some(Ok(packet)) if packet.data_type == TcptDataType::Esp => return Ok(packet.data),
+                Some(Ok(packet)) => trace!("Ignoring TCPT packet of type {:?}", packet.data_type),
+                Some(Err(e)) => {
+                    warn!("TCPT connection failed: {}", e);
+                    self.lost.notify_one();
+                    self.reconnected.notified().await;
+                }
+                None => {
+                    warn!("TCPT connection closed");
+                    self.lost.notify_one();
+                    self.reconnected.notified().await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codec_roundtrip() {
+        let mut codec = TcptCodec;
+        let mut buf = BytesMut::new();
+
+        codec
+            .encode(
+                TcptPacket::new(TcptDataType::Esp, Bytes::from_static(b"\x12\x34\x56\x78")),
+                &mut buf,
+            )
+            .unwrap();
+        codec
+            .encode(TcptPacket::new(TcptDataType::Ike, Bytes::from_static(b"ike")), &mut buf)
+            .unwrap();
+
+        assert_eq!(&buf[0..8], &[0, 0, 0, 4, 0, 0, 0, 3]);
+
+        let mut partial = buf.split_to(10);
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(buf);
+
+        let packet = codec.decode(&mut partial).unwrap().unwrap();
+        assert_eq!(packet.data_type, TcptDataType::Esp);
+        assert_eq!(&packet.data[..], b"\x12\x34\x56\x78");
+
+        let packet = codec.decode(&mut partial).unwrap().unwrap();
+        assert_eq!(packet.data_type, TcptDataType::Ike);
+        assert_eq!(&packet.data[..], b"ike");
+
+        assert!(partial.is_empty());
+
+        let mut invalid = BytesMut::from(&[0u8, 0, 0, 0, 0, 0, 0, 9][..]);
+        assert!(codec.decode(&mut invalid).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut server = Framed::new(server, TcptCodec);
+
+        let gateway = tokio::spawn(async move {
+            let request = server.next().await.unwrap().unwrap();
+            server
+                .send(TcptPacket::new(
+                    TcptDataType::Command,
+                    Bytes::from_static(&[0, 0, 0, 0]),
+                ))
+                .await
+                .unwrap();
+            request
+        });
+
+        assert!(handshake(client, TcptDataType::Esp).await.is_ok());
+
+        let request = gateway.await.unwrap();
+        assert_eq!(request.data_type, TcptDataType::Command);
+        assert_eq!(&request.data[..], &[0, 0, 0, 1, 0, 0, 0, 3]);
+    }
+
+    // the frames as they appear on the wire: the handshake request and reply, then the ESP data
+    #[tokio::test]
+    async fn test_wire_exchange() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (client, mut server) = tokio::io::duplex(1024);
+
+        let gateway = tokio::spawn(async move {
+            let mut request = [0u8; 16];
+            server.read_exact(&mut request).await.unwrap();
+            server.write_all(&[0, 0, 0, 4, 0, 0, 0, 1, 0, 0, 0, 0]).await.unwrap();
+            server.write_all(&[0, 0, 0, 2, 0, 0, 0, 3, 0xab, 0xcd]).await.unwrap();
+            request
+        });
+
+        let mut framed = handshake(client, TcptDataType::Esp).await.unwrap();
+
+        assert_eq!(gateway.await.unwrap(), [0, 0, 0, 8, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 3]);
+        assert_eq!(
+            framed.next().await.unwrap().unwrap(),
+            TcptPacket::new(TcptDataType::Esp, Bytes::from_static(&[0xab, 0xcd]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handshake_rejected() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (client, mut server) = tokio::io::duplex(1024);
+
+        tokio::spawn(async move {
+            let mut request = [0u8; 16];
+            server.read_exact(&mut request).await.unwrap();
+            server.write_all(&[0, 0, 0, 4, 0, 0, 0, 1, 0, 0, 0, 2]).await.unwrap();
+        });
+
+        let error = handshake(client, TcptDataType::Ike).await.err().unwrap();
+        assert_eq!(error.to_string(), "TCPT handshake failed, status: 2");
+    }
+}
@@ -0,0 +1,425 @@
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::anyhow;
+use bytes::Bytes;
+use ipnet::Ipv4Net;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::UdpSocket,
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tracing::{debug, trace, warn};
+
+use crate::{
+    model::{params::TunnelParams, IpsecSession, TunnelStatistics},
+    mtu,
+    platform::{self, DnsConfig, IpsecConfigurator, UdpSocketExt},
+    tunnel::{
+        ipsec::{esp::EspSa, tcpt::TcptEspStream},
+        stats::TunnelCounters,
+    },
+};
+
+const MAX_PACKET_SIZE: usize = 65536;
+const NON_ESP_MARKER: [u8; 4] = [0, 0, 0, 0];
+const NATT_KEEPALIVE: [u8; 1] = [0xff];
+
+// the inbound SA of the previous session is kept after rekeying to accept the packets in flight
+struct EspState {
+    outbound: EspSa,
+    inbound: Vec<EspSa>,
+}
+
+impl EspState {
+    fn new(session: &IpsecSession) -> anyhow::Result<Self> {
+        Ok(Self {
+            outbound: EspSa::new(&session.esp_out)?,
+            inbound: vec![EspSa::new(&session.esp_in)?],
+        })
+    }
+
+    fn rekey(&mut self, session: &IpsecSession) -> anyhow::Result<()> {
+        let outbound = EspSa::new(&session.esp_out)?;
+        let inbound = EspSa::new(&session.esp_in)?;
+
+        self.outbound = outbound;
+        self.inbound.insert(0, inbound);
+        self.inbound.truncate(2);
+
+        Ok(())
+    }
+
+    fn decrypt(&mut self, data: &[u8]) -> anyhow::Result<Option<Bytes>> {
+        let spi = u32::from_be_bytes(data[0..4].try_into()?);
+        self.inbound
+            .iter_mut()
+            .find(|sa| sa.spi() == spi)
+            .ok_or_else(|| anyhow!("Unknown SPI: 0x{:x}", spi))?
+            .decrypt(data)
+    }
+}
+
+/// Transport of the ESP packets between the client and the gateway
+#[derive(Clone)]
+pub enum EspTransport {
+    Udp(Arc<UdpSocket>),
+    Tcpt(Arc<TcptEspStream>),
+}
+
+impl EspTransport {
+    async fn send(&self, data: Bytes) -> anyhow::Result<()> {
+        match self {
+            Self::Udp(socket) => {
+                socket.send(&data).await?;
+                Ok(())
+            }
+            Self::Tcpt(stream) => stream.send(data).await,
+        }
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> anyhow::Result<Bytes> {
+        match self {
+            Self::Udp(socket) => loop {
+                // ICMP errors for the connected socket are reported as receive errors
+                match socket.recv(buf).await {
+                    Ok(size) => return Ok(Bytes::copy_from_slice(&buf[..size])),
+                    Err(e) => trace!("Receive error: {}", e),
+                }
+            },
+            Self::Tcpt(stream) => stream.recv().await,
+        }
+    }
+}
+
+/// IPSec datapath which does ESP processing in userspace,
+/// over the TUN device and the NAT-T UDP socket or the TCPT connection, without kernel XFRM support.
+/// Non-ESP packets received from the gateway are passed to the control sender.
+pub struct UserspaceConfigurator {
+    name: String,
+    tunnel_params: Arc<TunnelParams>,
+    ipsec_session: IpsecSession,
+    transport: EspTransport,
+    dest_ip: IpAddr,
+    dest_port: u16,
+    keepalive_ip: Ipv4Addr,
+    subnets: Vec<Ipv4Net>,
+    control_sender: mpsc::Sender<Bytes>,
+    state: Arc<Mutex<EspState>>,
+    counters: Arc<TunnelCounters>,
+    mtu: Arc<AtomicU16>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl UserspaceConfigurator {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        tunnel_params: Arc<TunnelParams>,
+        ipsec_session: IpsecSession,
+        transport: EspTransport,
+        dest_ip: IpAddr,
+        dest_port: u16,
+        keepalive_ip: Ipv4Addr,
+        subnets: Vec<Ipv4Net>,
+        control_sender: mpsc::Sender<Bytes>,
+    ) -> anyhow::Result<Self> {
+        let name = tunnel_params
+            .if_name
+            .clone()
+            .unwrap_or_else(|| TunnelParams::DEFAULT_SSL_IF_NAME.to_owned());
+
+        let state = EspState::new(&ipsec_session)?;
+
+        Ok(Self {
+            name,
+            tunnel_params,
+            ipsec_session,
+            transport,
+            dest_ip,
+            dest_port,
+            keepalive_ip,
+            subnets,
+            control_sender,
+            state: Arc::new(Mutex::new(state)),
+            counters: Arc::new(TunnelCounters::default()),
+            mtu: Arc::new(AtomicU16::new(mtu::DEFAULT_LINK_MTU)),
+            tasks: Vec::new(),
+        })
+    }
+
+    async fn detect_mtu(&self) -> u16 {
+        if let Some(mtu) = self.tunnel_params.mtu {
+            return mtu;
+        }
+
+        let link_mtu = match platform::get_link_mtu(self.dest_ip).await {
+            Ok(link_mtu) => link_mtu,
+            Err(e) => {
+                warn!("Cannot determine link MTU, using default: {}", e);
+                mtu::DEFAULT_LINK_MTU
+            }
+        };
+
+        let link_mtu = mtu::outer_link_mtu(link_mtu, self.dest_ip);
+
+        match self.transport {
+            EspTransport::Udp(_) => mtu::esp_mtu(link_mtu, &self.ipsec_session.esp_out),
+            EspTransport::Tcpt(_) => mtu::tcpt_mtu(link_mtu, &self.ipsec_session.esp_out),
+        }
+    }
+
+    fn setup_device(&mut self, mtu: u16) -> anyhow::Result<()> {
+        let mut config = platform::new_tun_config();
+        config
+            .name(&self.name)
+            .address(self.ipsec_session.address)
+            .netmask(self.ipsec_session.netmask)
+            .mtu(mtu as i32)
+            .up();
+
+        let device = tun::create_as_async(&config)?;
+        let (reader, writer) = tokio::io::split(device);
+
+        debug!("Created tun device: {}, MTU: {}", self.name, mtu);
+
+        let transport = self.transport.clone();
+        let state = self.state.clone();
+        let counters = self.counters.clone();
+        let mss_clamp = self.tunnel_params.mss_clamp.then(|| self.mtu.clone());
+        self.tasks.push(tokio::spawn(async move {
+            if let Err(e) = send_packets(reader, transport, state, counters, mss_clamp).await {
+                warn!("ESP uplink stopped: {}", e);
+            }
+        }));
+
+        let transport = self.transport.clone();
+        let state = self.state.clone();
+        let counters = self.counters.clone();
+        let control_sender = self.control_sender.clone();
+        self.tasks.push(tokio::spawn(async move {
+            if let Err(e) = receive_packets(writer, transport, state, counters, control_sender).await {
+                warn!("ESP downlink stopped: {}", e);
+            }
+        }));
+
+        Ok(())
+    }
+
+    async fn setup_routing(&self) -> anyhow::Result<()> {
+        let mut subnets = self.tunnel_params.add_routes.clone();
+
+        debug!("Ignoring acquired routes to {}", self.dest_ip);
+
+        if !self.tunnel_params.no_routing {
+            if self.tunnel_params.default_route {
+                let _ = platform::add_default_route(&self.name, self.ipsec_session.address).await;
+            } else {
+                subnets.extend(&self.subnets);
+            }
+        }
+
+        if let IpAddr::V4(dest_ip) = self.dest_ip {
+            subnets.retain(|s| !s.contains(&dest_ip));
+        }
+
+        if !subnets.is_empty() {
+            let _ = platform::add_routes(&subnets, &self.name, self.ipsec_session.address).await;
+        }
+
+        // keepalive packets are sent to the gateway address through the tunnel
+        platform::add_keepalive_route(&self.name, self.keepalive_ip, TunnelParams::IPSEC_KEEPALIVE_PORT).await?;
+
+        Ok(())
+    }
+
+    async fn setup_dns(&self) -> anyhow::Result<()> {
+        if !self.tunnel_params.no_dns {
+            debug!("Adding acquired DNS suffixes: {:?}", self.ipsec_session.domains);
+            debug!("Adding provided DNS suffixes: {:?}", self.tunnel_params.search_domains);
+            let config = DnsConfig::new(
+                &self.tunnel_params,
+                &self.ipsec_session.domains,
+                self.ipsec_session.dns.iter().map(|&server| server.into()).collect(),
+            );
+            let dns = platform::new_dns_configurator(&self.tunnel_params).await;
+            if let Err(e) = dns.configure(&self.name, &config).await {
+                warn!("Cannot configure DNS: {}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn send_packets(
+    mut reader: ReadHalf<tun::AsyncDevice>,
+    transport: EspTransport,
+    state: Arc<Mutex<EspState>>,
+    counters: Arc<TunnelCounters>,
+    mss_clamp: Option<Arc<AtomicU16>>,
+) -> anyhow::Result<()> {
+    let mut buf = vec![0u8; MAX_PACKET_SIZE];
+
+    loop {
+        let size = reader.read(&mut buf).await?;
+        if size == 0 {
+            break;
+        }
+
+        if let Some(ref mtu) = mss_clamp {
+            mtu::clamp_tcp_mss(&mut buf[..size], mtu::mss_for_mtu(mtu.load(Ordering::SeqCst)));
+        }
+
+        let result = state.lock().unwrap().outbound.encrypt(&buf[..size]);
+
+        match result {
+            Ok(data) => match transport.send(data).await {
+                Ok(()) => counters.add_sent(size),
+                Err(e) => {
+                    trace!("Cannot send packet: {}", e);
+                    counters.add_error();
+                }
+            },
+            Err(e) => {
+                trace!("Cannot encrypt packet: {}", e);
+                counters.add_error();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn receive_packets(
+    mut writer: WriteHalf<tun::AsyncDevice>,
+    transport: EspTransport,
+    state: Arc<Mutex<EspState>>,
+    counters: Arc<TunnelCounters>,
+    control_sender: mpsc::Sender<Bytes>,
+) -> anyhow::Result<()> {
+    let mut buf = vec![0u8; MAX_PACKET_SIZE];
+
+    loop {
+        let data = transport.recv(&mut buf).await?;
+
+        if data == NATT_KEEPALIVE[..] {
+            continue;
+        }
+
+        if data.starts_with(&NON_ESP_MARKER) {
+            control_sender.send(data).await?;
+            continue;
+        }
+
+        if data.len() < NON_ESP_MARKER.len() {
+            continue;
+        }
+
+        let result = state.lock().unwrap().decrypt(&data);
+
+        match result {
+            Ok(Some(packet)) => {
+                writer.write_all(&packet).await?;
+                counters.add_received(packet.len());
+            }
+            Ok(None) => {}
+            Err(e) => {
+                trace!("Cannot decrypt packet: {}", e);
+                counters.add_error();
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl IpsecConfigurator for UserspaceConfigurator {
+    async fn configure(&mut self) -> anyhow::Result<()> {
+        debug!("Target IP: {}", self.dest_ip);
+
+        self.cleanup().await;
+
+        let mtu = self.detect_mtu().await;
+        self.mtu.store(mtu, Ordering::SeqCst);
+
+        if let EspTransport::Udp(ref socket) = self.transport {
+            socket.connect((self.dest_ip, self.dest_port)).await?;
+        }
+        self.setup_device(mtu)?;
+
+        if !self.tunnel_params.keep_device_managed {
+            platform::unmanage_device(&self.name).await;
+        }
+
+        self.setup_routing().await?;
+        self.setup_dns().await?;
+
+        Ok(())
+    }
+
+    async fn rekey(&mut self, session: &IpsecSession) -> anyhow::Result<()> {
+        trace!(
+            "Rekeying userspace ESP with new session: IN: {:?}, OUT: {:?}",
+            session.esp_in,
+            session.esp_out
+        );
+
+        self.state.lock().unwrap().rekey(session)?;
+        self.ipsec_session = session.clone();
+
+        Ok(())
+    }
+
+    async fn cleanup(&mut self) {
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+
+        if !self.tunnel_params.no_dns {
+            platform::new_dns_configurator(&self.tunnel_params)
+                .await
+                .revert(&self.name)
+                .await;
+        }
+
+        platform::delete_device(&self.name).await;
+
+        platform::delete_keepalive_route(self.keepalive_ip, TunnelParams::IPSEC_KEEPALIVE_PORT).await;
+    }
+
+    async fn statistics(&self) -> anyhow::Result<TunnelStatistics> {
+        Ok(self.counters.snapshot())
+    }
+
+    fn device_name(&self) -> &str {
+        &self.name
+    }
+
+    fn mtu(&self) -> u16 {
+        self.mtu.load(Ordering::SeqCst)
+    }
+
+    async fn set_mtu(&mut self, mtu: u16) -> anyhow::Result<()> {
+        platform::set_link_mtu(&self.name, mtu).await?;
+        self.mtu.store(mtu, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // the connected UDP socket keeps the source address it was connected with.
+    // TCPT connection cannot be moved to the new address, it is reconnected in place once the receiver reports it lost.
+    async fn reanchor(&mut self, source_ip: IpAddr) -> anyhow::Result<()> {
+        if let EspTransport::Udp(ref socket) = self.transport {
+            debug!("Moving ESP socket to {}", source_ip);
+            socket.disconnect()?;
+            socket.connect((self.dest_ip, self.dest_port)).await?;
+
+            // the gateway learns the new address and the NAT mapping is created
+            socket.send(&NATT_KEEPALIVE).await?;
+        }
+        Ok(())
+    }
+}
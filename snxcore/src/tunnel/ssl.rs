@@ -7,98 +7,131 @@ use std::{
 };
 
 use anyhow::anyhow;
-use futures::{
-    channel::mpsc::{self, Receiver, Sender},
-    pin_mut, SinkExt, StreamExt, TryStreamExt,
+use bytes::BytesMut;
+use futures::{future::BoxFuture, pin_mut, FutureExt};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::watch,
+    time::Instant,
 };
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_native_tls::native_tls::{Certificate, TlsConnector};
+use tokio_native_tls::native_tls::{Certificate, Identity, TlsConnector};
+use tokio_rustls::rustls::pki_types::ServerName;
 use tracing::{debug, trace, warn};
-use tun::TunPacket;
 
-use codec::{SslPacketCodec, SslPacketType};
+use channel::{DataReceiver, DataSender, SslChannel};
+use codec::SslPacketType;
 
 use crate::{
-    model::{params::TunnelParams, proto::*, *},
-    platform,
+    ccc::CccHttpClient,
+    model::{
+        params::{CertType, TunnelParams},
+        proto::*,
+        *,
+    },
+    mtu, pkcs11, platform, proxy,
+    server_info::{self, TransportEndpoints},
     sexpr::SExpression,
-    tunnel::{ssl::keepalive::KeepaliveRunner, TunnelCommand, TunnelEvent, VpnTunnel},
+    tunnel::{
+        ssl::keepalive::KeepaliveRunner,
+        stats::{StatsSampler, TunnelCounters, STATS_INTERVAL},
+        TunnelCommand, TunnelEvent, VpnTunnel,
+    },
+    util,
 };
 
+pub mod channel;
 pub mod codec;
 pub mod connector;
 pub mod device;
 pub mod keepalive;
 
 const REAUTH_LEEWAY: Duration = Duration::from_secs(60);
+const REAUTH_RETRY_INTERVAL: Duration = Duration::from_secs(10);
 const SEND_TIMEOUT: Duration = Duration::from_secs(120);
-const CHANNEL_SIZE: usize = 1024;
-
-pub type PacketSender = Sender<SslPacketType>;
-pub type PacketReceiver = Receiver<SslPacketType>;
-
-fn make_channel<S>(stream: S) -> (PacketSender, PacketReceiver)
-where
-    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
-{
-    let framed = tokio_util::codec::Framed::new(stream, SslPacketCodec);
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const DISCONNECT_CODE_USER: u32 = 28;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const RECONNECT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(300);
+const TUN_BUFFER_SIZE: usize = 1024 * 1024;
+const MAX_PACKET_SIZE: usize = 65536;
+
+async fn connect(
+    params: &TunnelParams,
+    endpoints: &TransportEndpoints,
+    data_sender: DataSender,
+) -> anyhow::Result<SslChannel> {
+    if params.cert_type == CertType::Pkcs11 {
+        // the token session is opened before connecting so that the gateway does not wait for the login
+        let tls = tokio_rustls::TlsConnector::from(Arc::new(pkcs11::new_client_config(params).await?));
+        let tcp = proxy::connect(params, &endpoints.server_address(params), endpoints.tcpt_port).await?;
+        let stream = tls
+            .connect(ServerName::try_from(params.server_name.clone())?, tcp)
+            .await?;
+        return Ok(SslChannel::new(stream, data_sender));
+    }
 
-    let (tx_in, rx_in) = mpsc::channel(CHANNEL_SIZE);
-    let (tx_out, rx_out) = mpsc::channel(CHANNEL_SIZE);
+    let mut builder = TlsConnector::builder();
 
-    let channel = async move {
-        let (mut sink, stream) = framed.split();
+    for ca_cert in &params.ca_cert {
+        let data = tokio::fs::read(ca_cert).await?;
+        let cert = Certificate::from_pem(&data).or_else(|_| Certificate::from_der(&data))?;
+        builder.add_root_certificate(cert);
+    }
 
-        let mut rx = rx_out.map(Ok::<_, anyhow::Error>);
-        let to_wire = sink.send_all(&mut rx);
+    if params.no_cert_check {
+        builder.danger_accept_invalid_hostnames(true);
+    }
 
-        let mut tx = tx_in.sink_map_err(anyhow::Error::from);
-        let from_wire = stream.map_err(Into::into).forward(&mut tx);
+    if params.ignore_server_cert {
+        warn!("Disabling all certificate checks!!!");
+        builder.danger_accept_invalid_certs(true);
+    }
 
-        futures::future::select(to_wire, from_wire).await;
-    };
+    if let Some(ref client_cert) = params.cert_path {
+        let data = tokio::fs::read(client_cert).await?;
+        let identity = match params.cert_type {
+            CertType::Pkcs8 => Some(Identity::from_pkcs8(&data, &data)?),
+            CertType::Pkcs12 => Some(Identity::from_pkcs12(
+                &data,
+                params.cert_password.as_deref().unwrap_or_default(),
+            )?),
+            _ => None,
+        };
+        if let Some(identity) = identity {
+            builder.identity(identity);
+        }
+    }
 
-    tokio::spawn(channel);
+    let tls: tokio_native_tls::TlsConnector = builder.build()?.into();
+    let tcp = proxy::connect(params, &endpoints.server_address(params), endpoints.tcpt_port).await?;
+    let stream = tls.connect(params.server_name.as_str(), tcp).await?;
 
-    (tx_out, rx_in)
+    Ok(SslChannel::new(stream, data_sender))
 }
 
+type ReconnectAttempt = BoxFuture<'static, anyhow::Result<(SslChannel, HelloReplyData)>>;
+
 pub(crate) struct SslTunnel {
     params: Arc<TunnelParams>,
     session: Arc<VpnSession>,
     auth_timeout: Duration,
     keepalive: Duration,
     ip_address: String,
-    sender: PacketSender,
-    receiver: Option<PacketReceiver>,
+    endpoints: TransportEndpoints,
+    channel: SslChannel,
+    data_sender: DataSender,
+    data_receiver: Option<DataReceiver>,
     keepalive_counter: Arc<AtomicU64>,
+    counters: Arc<TunnelCounters>,
 }
 
 impl SslTunnel {
     pub(crate) async fn create(params: Arc<TunnelParams>, session: Arc<VpnSession>) -> anyhow::Result<Self> {
-        let tcp = tokio::net::TcpStream::connect((params.server_name.as_str(), 443)).await?;
-
-        let mut builder = TlsConnector::builder();
-
-        for ca_cert in &params.ca_cert {
-            let data = tokio::fs::read(ca_cert).await?;
-            let cert = Certificate::from_pem(&data).or_else(|_| Certificate::from_der(&data))?;
-            builder.add_root_certificate(cert);
-        }
-
-        if params.no_cert_check {
-            builder.danger_accept_invalid_hostnames(true);
-        }
-
-        if params.ignore_server_cert {
-            warn!("Disabling all certificate checks!!!");
-            builder.danger_accept_invalid_certs(true);
-        }
-
-        let tls: tokio_native_tls::TlsConnector = builder.build()?.into();
-        let stream = tls.connect(params.server_name.as_str(), tcp).await?;
-
-        let (sender, receiver) = make_channel(stream);
+        let (data_sender, data_receiver) = channel::data_channel();
+        let endpoints = server_info::get_transport_endpoints(&params).await;
+        let channel = connect(&params, &endpoints, data_sender.clone()).await?;
 
         debug!("Tunnel connected");
 
@@ -108,9 +141,12 @@ impl SslTunnel {
             auth_timeout: Duration::default(),
             keepalive: Duration::default(),
             ip_address: "0.0.0.0".to_string(),
-            sender,
-            receiver: Some(receiver),
+            endpoints,
+            channel,
+            data_sender,
+            data_receiver: Some(data_receiver),
             keepalive_counter: Arc::new(AtomicU64::default()),
+            counters: Arc::new(TunnelCounters::default()),
         })
     }
 
@@ -137,33 +173,134 @@ impl SslTunnel {
         trace!("Hello request: {:?}", req);
         self.send(req).await?;
 
-        let receiver = self.receiver.as_mut().unwrap();
+        let expr = self
+            .channel
+            .control_receiver
+            .recv()
+            .await
+            .ok_or_else(|| anyhow!("Channel closed!"))?;
+
+        trace!("Hello reply: {:?}", expr);
+        if matches!(&expr, SExpression::Object(Some(name), _) if name == "disconnect") {
+            return Err(anyhow!("Tunnel disconnected, last message: {}", expr));
+        }
+
+        let reply = expr.try_into::<HelloReply>()?;
+        self.ip_address.clone_from(&reply.data.office_mode.ipaddr);
+        self.update_timeouts(&reply.data);
 
-        let reply = receiver.next().await.ok_or_else(|| anyhow!("Channel closed!"))?;
+        Ok(reply.data)
+    }
+
+    fn update_timeouts(&mut self, reply: &HelloReplyData) {
+        self.auth_timeout = Duration::from_secs(reply.timeouts.authentication).saturating_sub(REAUTH_LEEWAY);
+        self.keepalive = Duration::from_secs(reply.timeouts.keepalive);
+        debug!(
+            "Authentication timeout: {} secs, keepalive: {} secs",
+            reply.timeouts.authentication, reply.timeouts.keepalive
+        );
+    }
+
+    // returns the pending session if the gateway asks for the user input, which is then completed by the server
+    async fn reauthenticate(&mut self) -> anyhow::Result<Option<Arc<VpnSession>>> {
+        debug!("Re-authenticating session {}", self.session.ccc_session_id);
+
+        let client = CccHttpClient::new(self.params.clone(), Some(self.session.clone()));
+        let session = connector::process_auth_response(client.authenticate().await?)?;
+
+        match session.state {
+            SessionState::Authenticated(_) => self.session = session,
+            SessionState::PendingChallenge(_) => return Ok(Some(session)),
+            SessionState::NoState => return Err(anyhow!("Re-authentication failed!")),
+        }
 
-        let reply = match reply {
-            SslPacketType::Control(expr) => {
-                trace!("Hello reply: {:?}", expr);
-                if matches!(&expr, SExpression::Object(Some(name), _) if name == "disconnect") {
-                    return Err(anyhow!("Tunnel disconnected, last message: {}", expr));
+        self.refresh_session().await?;
+
+        Ok(None)
+    }
+
+    // the hello request with the new active key refreshes the session without changing the office mode address
+    async fn refresh_session(&mut self) -> anyhow::Result<()> {
+        let req = self.new_hello_request(true);
+        trace!("Hello request: {:?}", req);
+        self.send(req).await
+    }
+
+    // the reconnect attempt does not borrow the tunnel so that it could be raced against the commands
+    fn reconnect(&self) -> ReconnectAttempt {
+        debug!("Reconnecting tunnel for session {}", self.session.ccc_session_id);
+
+        let params = self.params.clone();
+        let endpoints = self.endpoints.clone();
+        let data_sender = self.data_sender.clone();
+        let req = self.new_hello_request(true);
+
+        async move {
+            let mut channel =
+                tokio::time::timeout(CONNECT_TIMEOUT, connect(&params, &endpoints, data_sender)).await??;
+
+            trace!("Hello request: {:?}", req);
+            let reply = tokio::time::timeout(CONNECT_TIMEOUT, async {
+                channel.sender.send(req.into()).await?;
+
+                // keepalives of the new connection may arrive before the hello reply
+                loop {
+                    let expr = channel
+                        .control_receiver
+                        .recv()
+                        .await
+                        .ok_or_else(|| anyhow!("Channel closed!"))?;
+
+                    match expr.object_name() {
+                        Some("hello_reply") => break Ok::<_, anyhow::Error>(expr.try_into::<HelloReply>()?.data),
+                        Some("disconnect") => break Err(anyhow!("Tunnel disconnected, last message: {}", expr)),
+                        other => debug!("Ignoring control packet while reconnecting: {}", other.unwrap_or("???")),
+                    }
                 }
-                let hello_reply = expr.try_into::<HelloReply>()?;
-                self.ip_address.clone_from(&hello_reply.data.office_mode.ipaddr);
-                self.auth_timeout = Duration::from_secs(hello_reply.data.timeouts.authentication) - REAUTH_LEEWAY;
-                self.keepalive = Duration::from_secs(hello_reply.data.timeouts.keepalive);
-                hello_reply
-            }
-            _ => return Err(anyhow!("Unexpected reply")),
+            })
+            .await??;
+
+            Ok((channel, reply))
+        }
+        .boxed()
+    }
+
+    fn handle_hello_reply(&mut self, reply: &HelloReplyData) -> anyhow::Result<()> {
+        trace!("Hello reply: {:?}", reply);
+
+        if reply.office_mode.ipaddr != self.ip_address {
+            return Err(anyhow!(
+                "Office mode address changed: {} => {}",
+                self.ip_address,
+                reply.office_mode.ipaddr
+            ));
+        }
+        self.update_timeouts(reply);
+
+        debug!("Session {} refreshed", self.session.ccc_session_id);
+
+        Ok(())
+    }
+
+    async fn disconnect(&mut self) -> anyhow::Result<()> {
+        let req = DisconnectRequestData {
+            code: DISCONNECT_CODE_USER,
+            message: Some("User has disconnected.".into()),
         };
+        trace!("Disconnect request: {:?}", req);
 
-        Ok(reply.data)
+        tokio::time::timeout(DISCONNECT_TIMEOUT, self.send(req)).await??;
+
+        self.channel.close(DISCONNECT_TIMEOUT).await;
+
+        Ok(())
     }
 
     async fn send<P>(&mut self, packet: P) -> anyhow::Result<()>
     where
         P: Into<SslPacketType>,
     {
-        tokio::time::timeout(SEND_TIMEOUT, self.sender.send(packet.into())).await??;
+        tokio::time::timeout(SEND_TIMEOUT, self.channel.sender.send(packet.into())).await??;
 
         Ok(())
     }
@@ -187,85 +324,316 @@ impl VpnTunnel for SslTunnel {
             .as_deref()
             .unwrap_or(TunnelParams::DEFAULT_SSL_IF_NAME);
 
-        let tun = device::TunDevice::new(tun_name, &reply)?;
-        tun.setup_dns_and_routing(&self.params).await?;
+        let mtu = device::tunnel_mtu(&self.params, &self.endpoints).await;
+        let mss = self.params.mss_clamp.then(|| mtu::mss_for_mtu(mtu));
 
+        let tun = device::TunDevice::new(tun_name, &reply, mtu)?;
         let dev_name = tun.name().to_owned();
 
-        crate::platform::unmanage_device(&dev_name).await;
+        // must be done before the DNS setup, the NetworkManager backend takes the device over
+        if !self.params.keep_device_managed {
+            crate::platform::unmanage_device(&dev_name).await;
+        }
 
-        let (mut tun_sender, mut tun_receiver) = tun.into_inner().into_framed().split();
+        tun.setup_dns_and_routing(&self.params, &self.endpoints).await?;
+        let tunnel_config = tun.tunnel_config(&self.params, &self.endpoints, mtu)?;
 
-        let mut snx_receiver = self.receiver.take().unwrap();
+        let (mut tun_reader, mut tun_writer) = tokio::io::split(tun.into_inner());
 
-        let dev_name2 = dev_name.clone();
-        let keepalive_counter = self.keepalive_counter.clone();
+        // the uplink task is given the sender of the current connection, none while reconnecting
+        let (uplink_sender, mut uplink_receiver) = watch::channel(Some(self.channel.sender.clone()));
 
-        tokio::spawn(async move {
-            while let Some(item) = snx_receiver.next().await {
-                match item {
-                    SslPacketType::Control(expr) => {
-                        debug!("Control packet received");
-                        match expr {
-                            SExpression::Object(Some(name), _) if name == "keepalive" => {
-                                let _ = keepalive_counter
-                                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |v| (v > 0).then_some(v - 1));
-                            }
-                            _ => {}
-                        }
-                    }
-                    SslPacketType::Data(data) => {
-                        trace!("snx => {}: {}", dev_name2, data.len());
-                        let tun_packet = TunPacket::new(data);
-                        tun_sender.send(tun_packet).await?;
-                        keepalive_counter.store(0, Ordering::SeqCst);
+        let name = dev_name.clone();
+        let counters = self.counters.clone();
+        let mut uplink = tokio::spawn(async move {
+            let mut buf = BytesMut::with_capacity(TUN_BUFFER_SIZE);
+            let mut sender = uplink_receiver.borrow_and_update().clone();
+            loop {
+                buf.reserve(MAX_PACKET_SIZE);
+                if tun_reader.read_buf(&mut buf).await? == 0 {
+                    break;
+                }
+                let mut data = buf.split();
+                if let Some(mss) = mss {
+                    mtu::clamp_tcp_mss(&mut data, mss);
+                }
+                let data = data.freeze();
+
+                if uplink_receiver.has_changed()? {
+                    sender = uplink_receiver.borrow_and_update().clone();
+                }
+
+                if let Some(ref sender) = sender {
+                    trace!("{} => snx: {}", name, data.len());
+                    let len = data.len();
+                    match sender.send(data.into()).await {
+                        Ok(()) => counters.add_sent(len),
+                        Err(_) => counters.add_error(),
                     }
                 }
             }
             Ok::<_, anyhow::Error>(())
         });
 
+        let name = dev_name.clone();
+        let counter = self.keepalive_counter.clone();
+        let counters = self.counters.clone();
+        let mut data_receiver = self.data_receiver.take().unwrap();
+        let mut downlink = tokio::spawn(async move {
+            while let Some(data) = data_receiver.recv().await {
+                trace!("snx => {}: {}", name, data.len());
+                tun_writer.write_all(&data).await?;
+                counter.store(0, Ordering::SeqCst);
+                counters.add_received(data.len());
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+
+        let _ = event_sender.send(TunnelEvent::Configured(tunnel_config)).await;
         let _ = event_sender.send(TunnelEvent::Connected).await;
 
-        let command_fut = command_receiver.recv();
-        pin_mut!(command_fut);
+        let mut ka_run = Box::pin(
+            KeepaliveRunner::new(
+                self.keepalive,
+                self.channel.sender.clone(),
+                self.keepalive_counter.clone(),
+                self.counters.clone(),
+            )
+            .run(),
+        );
+
+        let reauth = tokio::time::sleep(self.auth_timeout);
+        pin_mut!(reauth);
+
+        let mut session_deadline = Instant::now() + self.auth_timeout + REAUTH_LEEWAY;
 
-        let keepalive_runner =
-            KeepaliveRunner::new(self.keepalive, self.sender.clone(), self.keepalive_counter.clone());
-        let ka_run = keepalive_runner.run();
-        pin_mut!(ka_run);
+        // set while the user input for re-authentication is awaited, the tunnel keeps running until the session expires
+        let mut reauth_pending = false;
+
+        // while the transport is down the tunnel device is kept and reconnect is attempted with backoff
+        let reconnect = tokio::time::sleep(Duration::ZERO);
+        pin_mut!(reconnect);
+
+        let mut reconnect_delay: Option<Duration> = None;
+        let mut reconnect_deadline = Instant::now();
+        let mut reconnect_attempt: Option<ReconnectAttempt> = None;
+
+        let mut stats_interval = tokio::time::interval(STATS_INTERVAL);
+        let mut stats_sampler = StatsSampler::default();
 
         let result = loop {
+            let connected = reconnect_delay.is_none();
+
             tokio::select! {
-                event = &mut command_fut => {
+                event = command_receiver.recv() => {
                     match event {
                         Some(TunnelCommand::Terminate) | None => {
+                            if connected {
+                                if let Err(e) = self.disconnect().await {
+                                    warn!("Cannot send disconnect request: {}", e);
+                                }
+                            }
                             break Ok(());
                         }
+                        Some(TunnelCommand::UpdateSession(session)) => {
+                            debug!("Session re-authenticated with the user input");
+                            self.session = session;
+                            reauth_pending = false;
+                            // while reconnecting the new session is used by the next hello request
+                            if connected {
+                                if let Err(e) = self.refresh_session().await {
+                                    warn!("Cannot refresh the session: {}", e);
+                                }
+                            }
+                        }
                         _ => {}
                     }
                 }
-                () = &mut ka_run => {
-                    warn!("Keepalive failed, exiting");
-                    break Err(anyhow!("Keepalive failed"));
+
+                () = &mut ka_run, if connected => {
+                    warn!("Keepalive failed, reconnecting");
+                    let _ = uplink_sender.send(None);
+                    reconnect_delay = Some(RECONNECT_INITIAL_DELAY);
+                    reconnect_deadline = Instant::now() + RECONNECT_TIMEOUT;
+                    reconnect.as_mut().reset(Instant::now());
+                }
+
+                () = &mut reconnect, if !connected && reconnect_attempt.is_none() => {
+                    reconnect_attempt = Some(self.reconnect());
+                }
+
+                result = util::await_optional(reconnect_attempt.as_mut()) => {
+                    reconnect_attempt = None;
+                    match result {
+                        Ok((channel, reply)) => {
+                            self.channel = channel;
+                            if let Err(e) = self.handle_hello_reply(&reply) {
+                                break Err(e);
+                            }
+                            self.keepalive_counter.store(0, Ordering::SeqCst);
+                            debug!("Tunnel reconnected");
+                            let _ = uplink_sender.send(Some(self.channel.sender.clone()));
+                            reconnect_delay = None;
+                            ka_run = Box::pin(
+                                KeepaliveRunner::new(
+                                    self.keepalive,
+                                    self.channel.sender.clone(),
+                                    self.keepalive_counter.clone(),
+                                    self.counters.clone(),
+                                )
+                                .run(),
+                            );
+                            session_deadline = Instant::now() + self.auth_timeout + REAUTH_LEEWAY;
+                            reauth.as_mut().reset(Instant::now() + self.auth_timeout);
+                        }
+                        Err(e) => {
+                            let delay = reconnect_delay.unwrap_or(RECONNECT_INITIAL_DELAY);
+                            let next = Instant::now() + delay;
+                            if next >= reconnect_deadline || (!self.auth_timeout.is_zero() && next >= session_deadline) {
+                                warn!("Reconnect failed, exiting: {}", e);
+                                break Err(e);
+                            }
+                            warn!("Reconnect failed, retrying in {} secs: {}", delay.as_secs(), e);
+                            reconnect_delay = Some((delay * 2).min(RECONNECT_MAX_DELAY));
+                            reconnect.as_mut().reset(next);
+                        }
+                    }
                 }
 
-                result = tun_receiver.next() => {
-                    if let Some(Ok(item)) = result {
-                        let data = item.into_bytes().to_vec();
-                        trace!("{} => snx: {}", dev_name, data.len());
-                        self.send(data).await?;
-                    } else {
-                        break Err(anyhow!("Receive failed"));
+                () = &mut reauth, if connected && !reauth_pending && !self.auth_timeout.is_zero() => {
+                    match self.reauthenticate().await {
+                        Ok(None) => {
+                            reauth.as_mut().reset(Instant::now() + self.auth_timeout);
+                        }
+                        Ok(Some(session)) => {
+                            warn!("Gateway requires user input for re-authentication");
+                            reauth_pending = true;
+                            let _ = event_sender.send(TunnelEvent::ChallengeRequired(session)).await;
+                        }
+                        Err(e) if Instant::now() + REAUTH_RETRY_INTERVAL < session_deadline => {
+                            warn!("Re-authentication failed, retrying: {}", e);
+                            reauth.as_mut().reset(Instant::now() + REAUTH_RETRY_INTERVAL);
+                        }
+                        Err(e) => {
+                            warn!("Re-authentication failed, exiting: {}", e);
+                            break Err(e);
+                        }
                     }
                 }
+
+                item = self.channel.control_receiver.recv(), if connected => match item {
+                    Some(expr) => {
+                        debug!("Control packet received: {}", expr.object_name().unwrap_or("???"));
+                        match expr.object_name() {
+                            Some("keepalive") => {
+                                self.counters.keepalive_received();
+                                let _ = self.keepalive_counter.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |v| {
+                                    (v > 0).then_some(v - 1)
+                                });
+                            }
+                            Some("hello_reply") => {
+                                let result = expr
+                                    .try_into::<HelloReply>()
+                                    .and_then(|reply| self.handle_hello_reply(&reply.data));
+                                if let Err(e) = result {
+                                    break Err(e);
+                                }
+                                session_deadline = Instant::now() + self.auth_timeout + REAUTH_LEEWAY;
+                                reauth.as_mut().reset(Instant::now() + self.auth_timeout);
+                            }
+                            Some("disconnect") => {
+                                let data = expr.try_into::<DisconnectRequest>().map(|r| r.data).unwrap_or_default();
+                                let message = data.message.map(|m| m.0).unwrap_or_default();
+                                warn!("Tunnel disconnected by the gateway, code: {}, message: {}", data.code, message);
+                                let _ = event_sender
+                                    .send(TunnelEvent::RemoteDisconnected(data.code, message.clone()))
+                                    .await;
+                                break Err(anyhow!("Tunnel disconnected by the gateway: [{}] {}", data.code, message));
+                            }
+                            other => {
+                                debug!("Ignoring control packet: {}", other.unwrap_or("???"));
+                            }
+                        }
+                    }
+                    None => {
+                        warn!("Connection to the gateway lost, reconnecting");
+                        let _ = uplink_sender.send(None);
+                        reconnect_delay = Some(RECONNECT_INITIAL_DELAY);
+                        reconnect_deadline = Instant::now() + RECONNECT_TIMEOUT;
+                        reconnect.as_mut().reset(Instant::now());
+                    }
+                },
+
+                _ = stats_interval.tick() => {
+                    let stats = stats_sampler.sample(&self.counters);
+                    let _ = event_sender.send(TunnelEvent::Statistics(stats)).await;
+                }
+
+                result = &mut uplink => {
+                    break Err(anyhow!("Tunnel device read failed: {:?}", result));
+                }
+
+                result = &mut downlink => {
+                    break Err(anyhow!("Tunnel device write failed: {:?}", result));
+                }
             }
         };
 
+        uplink.abort();
+        downlink.abort();
+
         let _ = event_sender.send(TunnelEvent::Disconnected).await;
 
+        if !self.params.no_dns {
+            platform::new_dns_configurator(&self.params)
+                .await
+                .revert(&dev_name)
+                .await;
+        }
+
         platform::delete_device(&dev_name).await;
 
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // mirrors the select! loop of the tunnel: the branches must be safe to build while no reconnect is pending
+    #[tokio::test]
+    async fn test_select_without_reconnect_attempt() {
+        let (command_sender, mut command_receiver) = tokio::sync::mpsc::channel(1);
+        let mut reconnect_attempt: Option<BoxFuture<'static, anyhow::Result<()>>> = None;
+        let mut iterations = 0;
+
+        let reconnect = tokio::time::sleep(Duration::from_millis(10));
+        pin_mut!(reconnect);
+
+        let result = loop {
+            iterations += 1;
+            tokio::select! {
+                event = command_receiver.recv() => {
+                    assert!(matches!(event, Some(TunnelCommand::Terminate)));
+                    break Ok(());
+                }
+
+                () = &mut reconnect, if reconnect_attempt.is_none() => {
+                    reconnect_attempt = Some(async { Err(anyhow!("reconnect failed")) }.boxed());
+                }
+
+                result = util::await_optional(reconnect_attempt.as_mut()) => {
+                    reconnect_attempt = None;
+                    let _ = command_sender.try_send(TunnelCommand::Terminate);
+                    if result.is_ok() {
+                        break result;
+                    }
+                }
+            }
+        };
+
+        assert!(result.is_ok());
+        assert!(iterations >= 3);
+    }
+}
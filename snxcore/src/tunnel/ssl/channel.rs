@@ -0,0 +1,151 @@
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use tokio_util::codec::{Encoder, FramedRead};
+use tracing::debug;
+
+use crate::{
+    sexpr::SExpression,
+    tunnel::ssl::codec::{SslPacketCodec, SslPacketType},
+};
+
+const CHANNEL_SIZE: usize = 1024;
+const CONTROL_CHANNEL_SIZE: usize = 16;
+const WRITE_BATCH_SIZE: usize = 64;
+const WRITE_BUFFER_SIZE: usize = 256 * 1024;
+const READ_BUFFER_SIZE: usize = 256 * 1024;
+
+pub type PacketSender = mpsc::Sender<SslPacketType>;
+pub type PacketReceiver = mpsc::Receiver<SslPacketType>;
+pub type DataSender = mpsc::Sender<Bytes>;
+pub type DataReceiver = mpsc::Receiver<Bytes>;
+pub type ControlReceiver = mpsc::Receiver<SExpression>;
+
+pub fn data_channel() -> (DataSender, DataReceiver) {
+    mpsc::channel(CHANNEL_SIZE)
+}
+
+/// TLS connection to the gateway, served by the independent reader and writer tasks.
+/// Data frames received from the gateway are passed to the data sender,
+/// control frames are delivered via the control receiver.
+pub struct SslChannel {
+    pub sender: PacketSender,
+    pub control_receiver: ControlReceiver,
+    closer: Option<oneshot::Sender<()>>,
+    reader: JoinHandle<()>,
+    writer: JoinHandle<()>,
+}
+
+impl SslChannel {
+    pub fn new<S>(stream: S, data_sender: DataSender) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (reader, writer) = tokio::io::split(stream);
+
+        let (sender, receiver) = mpsc::channel(CHANNEL_SIZE);
+        let (control_sender, control_receiver) = mpsc::channel(CONTROL_CHANNEL_SIZE);
+        let (closer, close_receiver) = oneshot::channel();
+
+        let writer = tokio::spawn(async move {
+            if let Err(e) = write_packets(writer, receiver, close_receiver).await {
+                debug!("Writer stopped: {}", e);
+            }
+        });
+
+        let reader = tokio::spawn(async move {
+            if let Err(e) = read_packets(reader, data_sender, control_sender).await {
+                debug!("Reader stopped: {}", e);
+            }
+        });
+
+        Self {
+            sender,
+            control_receiver,
+            closer: Some(closer),
+            reader,
+            writer,
+        }
+    }
+
+    /// Write out all pending packets and close the connection
+    pub async fn close(&mut self, timeout: Duration) {
+        if let Some(closer) = self.closer.take() {
+            let _ = closer.send(());
+            let _ = tokio::time::timeout(timeout, &mut self.writer).await;
+        }
+    }
+}
+
+impl Drop for SslChannel {
+    fn drop(&mut self) {
+        self.reader.abort();
+        self.writer.abort();
+    }
+}
+
+// all packets which are ready at the moment are encoded into one buffer and flushed at once
+async fn write_packets<W>(
+    mut writer: W,
+    mut receiver: PacketReceiver,
+    mut close_receiver: oneshot::Receiver<()>,
+) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut batch = Vec::with_capacity(WRITE_BATCH_SIZE);
+    let mut buf = BytesMut::with_capacity(WRITE_BUFFER_SIZE);
+    let mut closing = false;
+
+    loop {
+        tokio::select! {
+            _ = &mut close_receiver, if !closing => {
+                closing = true;
+                receiver.close();
+            }
+
+            count = receiver.recv_many(&mut batch, WRITE_BATCH_SIZE) => {
+                if count == 0 {
+                    break;
+                }
+
+                for packet in batch.drain(..) {
+                    SslPacketCodec.encode(packet, &mut buf)?;
+                }
+
+                writer.write_all_buf(&mut buf).await?;
+                writer.flush().await?;
+            }
+        }
+    }
+
+    writer.shutdown().await?;
+
+    Ok(())
+}
+
+async fn read_packets<R>(
+    reader: R,
+    data_sender: DataSender,
+    control_sender: mpsc::Sender<SExpression>,
+) -> anyhow::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut framed = FramedRead::with_capacity(reader, SslPacketCodec, READ_BUFFER_SIZE);
+
+    while let Some(packet) = framed.next().await {
+        match packet? {
+            SslPacketType::Control(expr) => control_sender.send(expr).await?,
+            SslPacketType::Data(data) => data_sender.send(data).await?,
+        }
+    }
+
+    Ok(())
+}
@@ -1,7 +1,7 @@
 use std::fmt;
 
 use anyhow::anyhow;
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use serde::Serialize;
 use tokio_util::codec::{Decoder, Encoder};
 
@@ -14,7 +14,7 @@ use crate::{
 
 pub enum SslPacketType {
     Control(SExpression),
-    Data(Vec<u8>),
+    Data(Bytes),
 }
 
 impl fmt::Debug for SslPacketType {
@@ -35,8 +35,8 @@ impl SslPacketType {
     }
 }
 
-impl From<Vec<u8>> for SslPacketType {
-    fn from(value: Vec<u8>) -> Self {
+impl From<Bytes> for SslPacketType {
+    fn from(value: Bytes) -> Self {
         SslPacketType::Data(value)
     }
 }
@@ -59,7 +59,7 @@ impl From<DisconnectRequestData> for SslPacketType {
     }
 }
 
-pub(crate) struct SslPacketCodec;
+pub struct SslPacketCodec;
 
 impl Decoder for SslPacketCodec {
     type Item = SslPacketType;
@@ -73,21 +73,22 @@ impl Decoder for SslPacketCodec {
         let len = u32::from_be_bytes(src[0..4].try_into()?) as usize;
 
         if src.remaining() < 8 + len {
+            src.reserve(8 + len - src.remaining());
             return Ok(None);
         }
 
         let packet_type = u32::from_be_bytes(src[4..8].try_into()?);
+
+        // data frames are split off the read buffer without copying
+        let mut packet = src.split_to(8 + len);
+        packet.advance(8);
+
         match packet_type {
             1 => {
-                let s_data = String::from_utf8_lossy(&src[8..8 + len]).into_owned();
-                src.advance(8 + len);
+                let s_data = String::from_utf8_lossy(&packet);
                 Ok(Some(SslPacketType::Control(s_data.parse()?)))
             }
-            2 => {
-                let data = src[8..8 + len].to_vec();
-                src.advance(8 + len);
-                Ok(Some(SslPacketType::Data(data)))
-            }
+            2 => Ok(Some(SslPacketType::Data(packet.freeze()))),
             _ => Err(anyhow!("Unknown packet type!")),
         }
     }
@@ -101,20 +102,53 @@ impl Encoder<SslPacketType> for SslPacketCodec {
             SslPacketType::Control(expr) => {
                 let mut data = expr.to_string().into_bytes();
                 data.push(b'\x00');
-                (data, 1u32)
+                (Bytes::from(data), 1u32)
             }
             SslPacketType::Data(data) => (data, 2u32),
         };
 
         dst.reserve(data.len() + 8);
 
-        let data_len = (data.len() as u32).to_be_bytes();
-        let packet_type = packet_type.to_be_bytes();
-
-        dst.put_slice(&data_len);
-        dst.put_slice(&packet_type);
+        dst.put_u32(data.len() as u32);
+        dst.put_u32(packet_type);
         dst.put_slice(&data);
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codec_roundtrip() {
+        let mut codec = SslPacketCodec;
+        let mut buf = BytesMut::new();
+
+        codec
+            .encode(SslPacketType::Data(Bytes::from_static(b"\x45\x00\x00\x14")), &mut buf)
+            .unwrap();
+        codec
+            .encode(KeepaliveRequestData { id: "0".to_owned() }.into(), &mut buf)
+            .unwrap();
+
+        assert_eq!(&buf[0..8], &[0, 0, 0, 4, 0, 0, 0, 2]);
+
+        let mut partial = buf.split_to(6);
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(buf);
+
+        match codec.decode(&mut partial).unwrap() {
+            Some(SslPacketType::Data(data)) => assert_eq!(&data[..], b"\x45\x00\x00\x14"),
+            other => panic!("Unexpected packet: {other:?}"),
+        }
+
+        match codec.decode(&mut partial).unwrap() {
+            Some(SslPacketType::Control(expr)) => assert_eq!(expr.object_name(), Some("keepalive")),
+            other => panic!("Unexpected packet: {other:?}"),
+        }
+
+        assert!(partial.is_empty());
+    }
+}
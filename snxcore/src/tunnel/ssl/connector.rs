@@ -11,6 +11,72 @@ use crate::{
     tunnel::{ssl::SslTunnel, TunnelCommand, TunnelConnector, TunnelEvent, VpnTunnel},
 };
 
+// SAML challenges carry the identity provider URL instead of the text prompt
+fn saml_url(prompt: &str) -> Option<&str> {
+    let url = prompt
+        .trim()
+        .trim_start_matches("CPSC_SP_URL")
+        .trim_start_matches([':', '=', ' ']);
+
+    (url.starts_with("https://") || url.starts_with("http://")).then_some(url)
+}
+
+pub(crate) fn process_auth_response(data: AuthResponse) -> anyhow::Result<Arc<VpnSession>> {
+    let session_id = data.session_id.unwrap_or_default();
+
+    match data.authn_status.as_str() {
+        "continue" => {
+            let prompt = data.prompt.map(|p| p.0).unwrap_or_default();
+
+            let challenge = match saml_url(&prompt) {
+                Some(url) => {
+                    debug!("SAML challenge received");
+                    MfaChallenge {
+                        mfa_type: MfaType::SamlSso,
+                        prompt: url.to_owned(),
+                    }
+                }
+                None => MfaChallenge {
+                    mfa_type: MfaType::PasswordInput,
+                    prompt,
+                },
+            };
+
+            return Ok(Arc::new(VpnSession {
+                ccc_session_id: session_id,
+                state: SessionState::PendingChallenge(challenge),
+                ipsec_session: None,
+            }));
+        }
+        "done" => {}
+        other => {
+            warn!("Authn status: {}", other);
+            return Err(anyhow!("Authentication failed!"));
+        }
+    }
+
+    let active_key = match (data.is_authenticated, data.active_key) {
+        (Some(true), Some(ref key)) => key.clone(),
+        _ => {
+            let msg = match (data.error_message, data.error_id, data.error_code) {
+                (Some(message), Some(id), Some(code)) => format!("[{} {}] {}", code, id.0, message.0),
+                _ => "Authentication failed!".to_owned(),
+            };
+            warn!("{}", msg);
+            return Err(anyhow!(msg));
+        }
+    };
+
+    debug!("Authentication OK, session id: {session_id}");
+
+    let session = Arc::new(VpnSession {
+        ccc_session_id: session_id,
+        state: SessionState::Authenticated(active_key.0),
+        ipsec_session: None,
+    });
+    Ok(session)
+}
+
 pub struct CccTunnelConnector {
     params: Arc<TunnelParams>,
     command_sender: Option<Sender<TunnelCommand>>,
@@ -23,49 +89,6 @@ impl CccTunnelConnector {
             command_sender: None,
         })
     }
-
-    async fn process_auth_response(&self, data: AuthResponse) -> anyhow::Result<Arc<VpnSession>> {
-        let session_id = data.session_id.unwrap_or_default();
-
-        match data.authn_status.as_str() {
-            "continue" => {
-                return Ok(Arc::new(VpnSession {
-                    ccc_session_id: session_id,
-                    state: SessionState::PendingChallenge(MfaChallenge {
-                        mfa_type: MfaType::PasswordInput,
-                        prompt: data.prompt.map(|p| p.0).unwrap_or_default(),
-                    }),
-                    ipsec_session: None,
-                }))
-            }
-            "done" => {}
-            other => {
-                warn!("Authn status: {}", other);
-                return Err(anyhow!("Authentication failed!"));
-            }
-        }
-
-        let active_key = match (data.is_authenticated, data.active_key) {
-            (Some(true), Some(ref key)) => key.clone(),
-            _ => {
-                let msg = match (data.error_message, data.error_id, data.error_code) {
-                    (Some(message), Some(id), Some(code)) => format!("[{} {}] {}", code, id.0, message.0),
-                    _ => "Authentication failed!".to_owned(),
-                };
-                warn!("{}", msg);
-                return Err(anyhow!(msg));
-            }
-        };
-
-        debug!("Authentication OK, session id: {session_id}");
-
-        let session = Arc::new(VpnSession {
-            ccc_session_id: session_id,
-            state: SessionState::Authenticated(active_key.0),
-            ipsec_session: None,
-        });
-        Ok(session)
-    }
 }
 
 #[async_trait]
@@ -76,7 +99,7 @@ impl TunnelConnector for CccTunnelConnector {
 
         let data = client.authenticate().await?;
 
-        self.process_auth_response(data).await
+        process_auth_response(data)
     }
 
     async fn challenge_code(&mut self, session: Arc<VpnSession>, user_input: &str) -> anyhow::Result<Arc<VpnSession>> {
@@ -88,7 +111,7 @@ impl TunnelConnector for CccTunnelConnector {
 
         let data = client.challenge_code(user_input).await?;
 
-        self.process_auth_response(data).await
+        process_auth_response(data)
     }
 
     async fn create_tunnel(
@@ -107,6 +130,13 @@ impl TunnelConnector for CccTunnelConnector {
         Ok(())
     }
 
+    async fn update_session(&mut self, session: Arc<VpnSession>) -> anyhow::Result<()> {
+        match self.command_sender {
+            Some(ref sender) => Ok(sender.send(TunnelCommand::UpdateSession(session)).await?),
+            None => Err(anyhow!("No tunnel to update the session for!")),
+        }
+    }
+
     async fn handle_tunnel_event(&mut self, event: TunnelEvent) -> anyhow::Result<()> {
         match event {
             TunnelEvent::Connected => {
@@ -115,11 +145,41 @@ impl TunnelConnector for CccTunnelConnector {
             TunnelEvent::Disconnected => {
                 debug!("Tunnel disconnected");
             }
-            TunnelEvent::RekeyCheck => {}
+            TunnelEvent::ChallengeRequired(_) => {
+                debug!("Re-authentication requires user input");
+            }
+            TunnelEvent::Configured(_)
+            | TunnelEvent::RekeyCheck
+            | TunnelEvent::Statistics(_)
+            | TunnelEvent::AddressChanged(_) => {}
             TunnelEvent::RemoteControlData(_) => {
                 warn!("Tunnel data received: shouldn't happen for SSL tunnel!");
             }
+            TunnelEvent::RemoteDisconnected(code, message) => {
+                debug!(
+                    "Tunnel disconnected by the gateway, code: {}, message: {}",
+                    code, message
+                );
+            }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_saml_url() {
+        assert_eq!(
+            saml_url("https://login.example.com/saml?id=1"),
+            Some("https://login.example.com/saml?id=1")
+        );
+        assert_eq!(
+            saml_url("CPSC_SP_URL:https://login.example.com/saml"),
+            Some("https://login.example.com/saml")
+        );
+        assert_eq!(saml_url("Please enter your verification code"), None);
+    }
+}
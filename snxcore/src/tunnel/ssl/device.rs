@@ -1,11 +1,18 @@
-use std::net::{Ipv4Addr, ToSocketAddrs};
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs};
 
-use tracing::debug;
-use tun::{Device, IntoAddress};
+use anyhow::anyhow;
+use ipnet::Ipv4Net;
+use tracing::{debug, warn};
+use tun::Device;
 
 use crate::{
     model::{params::TunnelParams, proto::HelloReplyData},
-    platform, util,
+    mtu,
+    platform::{self, DnsConfig},
+    proxy,
+    server_info::TransportEndpoints,
+    tunnel::TunnelConfig,
+    util,
 };
 
 pub struct TunDevice {
@@ -16,12 +23,13 @@ pub struct TunDevice {
 }
 
 impl TunDevice {
-    pub fn new(name: &str, reply: &HelloReplyData) -> anyhow::Result<Self> {
+    pub fn new(name: &str, reply: &HelloReplyData, mtu: u16) -> anyhow::Result<Self> {
         let mut config = platform::new_tun_config();
         let ipaddr = reply.office_mode.ipaddr.parse::<Ipv4Addr>()?;
 
         config.address(reply.office_mode.ipaddr.as_str()).up();
         config.name(name);
+        config.mtu(mtu as i32);
 
         if let Some(ref netmask) = reply.optional {
             config.netmask(netmask.subnet.as_str());
@@ -31,7 +39,7 @@ impl TunDevice {
 
         let dev_name = dev.get_ref().name()?;
 
-        debug!("Created tun device: {dev_name}");
+        debug!("Created tun device: {dev_name}, MTU: {mtu}");
 
         Ok(Self {
             inner: dev,
@@ -49,11 +57,12 @@ impl TunDevice {
         self.inner
     }
 
-    pub async fn setup_dns_and_routing(&self, params: &TunnelParams) -> anyhow::Result<()> {
-        let dest_ips = format!("{}:443", params.server_name)
-            .to_socket_addrs()?
-            .flat_map(|s| s.into_address().ok())
-            .collect::<Vec<_>>();
+    pub async fn setup_dns_and_routing(
+        &self,
+        params: &TunnelParams,
+        endpoints: &TransportEndpoints,
+    ) -> anyhow::Result<()> {
+        let dest_ips = transport_addresses(params, endpoints)?;
 
         debug!("Ignoring acquired routes to {:?}", dest_ips);
 
@@ -67,34 +76,113 @@ impl TunDevice {
             }
         }
 
-        subnets.retain(|s| dest_ips.iter().all(|i| !s.contains(i)));
+        subnets.retain(|s| {
+            dest_ips.iter().all(|i| match i {
+                IpAddr::V4(i) => !s.contains(i),
+                IpAddr::V6(_) => true,
+            })
+        });
 
         if !subnets.is_empty() {
             let _ = platform::add_routes(&subnets, &self.dev_name, self.ipaddr).await;
         }
 
         if !params.no_dns {
-            if let Some(ref suffixes) = self.reply.office_mode.dns_suffix {
-                debug!("Adding acquired DNS suffixes: {:?}", suffixes.0);
-                debug!("Adding provided DNS suffixes: {:?}", params.search_domains);
-                let suffixes = suffixes.0.iter().chain(params.search_domains.iter()).filter(|&s| {
-                    !s.is_empty()
-                        && !params
-                            .ignore_search_domains
-                            .iter()
-                            .any(|d| d.to_lowercase() == s.to_lowercase())
-                });
-                let _ = platform::add_dns_suffixes(suffixes, &self.dev_name).await;
-            }
+            let config = self.dns_config(params);
+            debug!("Adding DNS configuration: {:?}", config);
 
-            if let Some(ref servers) = self.reply.office_mode.dns_servers {
-                debug!("Adding DNS servers: {servers:?}");
-                let _ = platform::add_dns_servers(servers, &self.dev_name).await;
+            let dns = platform::new_dns_configurator(params).await;
+            if let Err(e) = dns.configure(&self.dev_name, &config).await {
+                warn!("Cannot configure DNS: {}", e);
             }
         }
 
         Ok(())
     }
+
+    pub fn tunnel_config(
+        &self,
+        params: &TunnelParams,
+        endpoints: &TransportEndpoints,
+        mtu: u16,
+    ) -> anyhow::Result<TunnelConfig> {
+        let gateway = transport_addresses(params, endpoints)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("No transport address"))?;
+
+        let address = self
+            .reply
+            .optional
+            .as_ref()
+            .and_then(|optional| optional.subnet.parse().ok())
+            .and_then(|netmask| Ipv4Net::with_netmask(self.ipaddr, netmask).ok())
+            .unwrap_or_else(|| self.ipaddr.into());
+
+        Ok(TunnelConfig {
+            device: self.dev_name.clone(),
+            address,
+            gateway,
+            routes: util::ranges_to_subnets(&self.reply.range).collect(),
+            dns: self.dns_config(params),
+            mtu,
+        })
+    }
+
+    fn dns_config(&self, params: &TunnelParams) -> DnsConfig {
+        let suffixes = self
+            .reply
+            .office_mode
+            .dns_suffix
+            .as_ref()
+            .map(|s| s.0.as_slice())
+            .unwrap_or_default();
+
+        let servers = self
+            .reply
+            .office_mode
+            .dns_servers
+            .iter()
+            .flatten()
+            .filter_map(|s| s.parse().ok())
+            .collect::<Vec<_>>();
+
+        DnsConfig::new(params, suffixes, servers)
+    }
+}
+
+// when connected via proxy, it is the proxy address which must stay outside of the tunnel
+fn transport_addresses(params: &TunnelParams, endpoints: &TransportEndpoints) -> anyhow::Result<Vec<IpAddr>> {
+    let dest = match proxy::proxy_address(params)? {
+        Some((host, port)) => (host, port),
+        None => (endpoints.server_address(params), endpoints.tcpt_port),
+    };
+
+    Ok(dest.to_socket_addrs()?.map(|s| s.ip()).collect())
+}
+
+async fn link_mtu(params: &TunnelParams, endpoints: &TransportEndpoints) -> anyhow::Result<u16> {
+    let dest = transport_addresses(params, endpoints)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("No transport address"))?;
+
+    Ok(mtu::outer_link_mtu(platform::get_link_mtu(dest).await?, dest))
+}
+
+/// Tunnel MTU, either configured or calculated from the MTU of the outgoing interface
+pub async fn tunnel_mtu(params: &TunnelParams, endpoints: &TransportEndpoints) -> u16 {
+    if let Some(mtu) = params.mtu {
+        return mtu;
+    }
+
+    match link_mtu(params, endpoints).await {
+        Ok(link_mtu) => mtu::ssl_mtu(link_mtu),
+        Err(e) => {
+            warn!("Cannot determine link MTU, using default: {}", e);
+            mtu::ssl_mtu(mtu::DEFAULT_LINK_MTU)
+        }
+    }
 }
 
 #[cfg(test)]
@@ -6,13 +6,13 @@ use std::{
     time::Duration,
 };
 
-use futures::{channel::oneshot, SinkExt};
+use futures::channel::oneshot;
 use tracing::{trace, warn};
 
 use crate::{
     model::proto::KeepaliveRequestData,
     platform::{self},
-    tunnel::ssl::PacketSender,
+    tunnel::{ssl::channel::PacketSender, stats::TunnelCounters},
 };
 
 const KEEPALIVE_MAX_RETRIES: u64 = 3;
@@ -22,23 +22,31 @@ pub struct KeepaliveRunner {
     interval: Duration,
     sender: PacketSender,
     keepalive_counter: Arc<AtomicU64>,
+    counters: Arc<TunnelCounters>,
 }
 
 impl KeepaliveRunner {
-    pub fn new(interval: Duration, sender: PacketSender, counter: Arc<AtomicU64>) -> Self {
+    pub fn new(
+        interval: Duration,
+        sender: PacketSender,
+        counter: Arc<AtomicU64>,
+        counters: Arc<TunnelCounters>,
+    ) -> Self {
         Self {
             interval,
             sender,
             keepalive_counter: counter,
+            counters,
         }
     }
 
-    pub async fn run(&self) {
+    pub async fn run(self) {
         let (stop_sender, stop_receiver) = oneshot::channel();
 
         let interval = self.interval;
         let keepalive_counter = self.keepalive_counter.clone();
-        let mut sender = self.sender.clone();
+        let sender = self.sender.clone();
+        let counters = self.counters.clone();
 
         tokio::spawn(async move {
             loop {
@@ -53,10 +61,12 @@ impl KeepaliveRunner {
                     trace!("Keepalive request: {:?}", req);
 
                     keepalive_counter.fetch_add(1, Ordering::SeqCst);
+                    counters.keepalive_sent();
 
                     match tokio::time::timeout(SEND_TIMEOUT, sender.send(req.into())).await {
                         Ok(Ok(())) => {}
                         _ => {
+                            counters.keepalive_failed();
                             warn!("Cannot send keepalive packet, exiting");
                             break;
                         }
@@ -0,0 +1,158 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::model::TunnelStatistics;
+
+pub const STATS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Counters shared between the tunnel tasks
+#[derive(Default)]
+pub struct TunnelCounters {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    packets_sent: AtomicU64,
+    packets_received: AtomicU64,
+    errors: AtomicU64,
+    keepalive_rtt_us: AtomicU64,
+    keepalive_failures: AtomicU64,
+    keepalive_sent_at: Mutex<Option<Instant>>,
+}
+
+impl TunnelCounters {
+    pub fn add_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_received(&self, bytes: usize) {
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // for the tunnels where the traffic is counted outside of the application
+    pub fn set_traffic(&self, stats: &TunnelStatistics) {
+        self.bytes_sent.store(stats.bytes_sent, Ordering::Relaxed);
+        self.bytes_received.store(stats.bytes_received, Ordering::Relaxed);
+        self.packets_sent.store(stats.packets_sent, Ordering::Relaxed);
+        self.packets_received.store(stats.packets_received, Ordering::Relaxed);
+        self.errors.store(stats.errors, Ordering::Relaxed);
+    }
+
+    // keepalive request which is still waiting for the reply is counted as a failure
+    pub fn keepalive_sent(&self) {
+        if self.keepalive_sent_at.lock().unwrap().replace(Instant::now()).is_some() {
+            self.keepalive_failed();
+        }
+    }
+
+    pub fn keepalive_received(&self) {
+        if let Some(sent_at) = self.keepalive_sent_at.lock().unwrap().take() {
+            self.set_keepalive_rtt(sent_at.elapsed());
+        }
+    }
+
+    pub fn set_keepalive_rtt(&self, rtt: Duration) {
+        self.keepalive_rtt_us
+            .store((rtt.as_micros() as u64).max(1), Ordering::Relaxed);
+    }
+
+    pub fn keepalive_failed(&self) {
+        self.keepalive_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> TunnelStatistics {
+        let rtt = self.keepalive_rtt_us.load(Ordering::Relaxed);
+
+        TunnelStatistics {
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            packets_sent: self.packets_sent.load(Ordering::Relaxed),
+            packets_received: self.packets_received.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            keepalive_rtt: (rtt > 0).then(|| Duration::from_micros(rtt)),
+            keepalive_failures: self.keepalive_failures.load(Ordering::Relaxed),
+            ..Default::default()
+        }
+    }
+}
+
+/// Calculates the throughput between the consecutive samples
+#[derive(Default)]
+pub struct StatsSampler {
+    last: Option<(Instant, u64, u64)>,
+}
+
+impl StatsSampler {
+    pub fn sample(&mut self, counters: &TunnelCounters) -> TunnelStatistics {
+        let mut stats = counters.snapshot();
+        let now = Instant::now();
+
+        if let Some((last_time, last_sent, last_received)) = self.last {
+            let elapsed = now.duration_since(last_time).as_secs_f64();
+            if elapsed > 0.0 {
+                stats.send_rate = (stats.bytes_sent.saturating_sub(last_sent) as f64 / elapsed) as u64;
+                stats.receive_rate = (stats.bytes_received.saturating_sub(last_received) as f64 / elapsed) as u64;
+            }
+        }
+
+        self.last = Some((now, stats.bytes_sent, stats.bytes_received));
+
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters() {
+        let counters = TunnelCounters::default();
+        counters.add_sent(100);
+        counters.add_sent(50);
+        counters.add_received(1000);
+        counters.add_error();
+
+        counters.keepalive_sent();
+        counters.keepalive_sent();
+        counters.keepalive_received();
+        counters.keepalive_received();
+
+        let stats = counters.snapshot();
+        assert_eq!(stats.bytes_sent, 150);
+        assert_eq!(stats.packets_sent, 2);
+        assert_eq!(stats.bytes_received, 1000);
+        assert_eq!(stats.packets_received, 1);
+        assert_eq!(stats.errors, 1);
+        assert_eq!(stats.keepalive_failures, 1);
+        assert!(stats.keepalive_rtt.is_some());
+    }
+
+    #[test]
+    fn test_sampler() {
+        let counters = TunnelCounters::default();
+        let mut sampler = StatsSampler::default();
+
+        let stats = sampler.sample(&counters);
+        assert_eq!(stats.send_rate, 0);
+
+        sampler.last = sampler
+            .last
+            .map(|(time, sent, received)| (time - Duration::from_secs(2), sent, received));
+        counters.add_sent(2000);
+        counters.add_received(4000);
+
+        let stats = sampler.sample(&counters);
+        assert!(stats.send_rate > 0 && stats.send_rate <= 1000);
+        assert!(stats.receive_rate > 0 && stats.receive_rate <= 2000);
+    }
+}
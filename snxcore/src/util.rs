@@ -81,6 +81,15 @@ where
     rt.block_on(f)
 }
 
+/// Await the optional future, never resolve if there is none.
+/// Used in select! branches where the future expression is evaluated even when the branch is disabled.
+pub async fn await_optional<F: Future>(fut: Option<F>) -> F::Output {
+    match fut {
+        Some(fut) => fut.await,
+        None => std::future::pending().await,
+    }
+}
+
 pub fn ranges_to_subnets(ranges: &[NetworkRange]) -> impl Iterator<Item = Ipv4Net> + '_ {
     ranges.iter().flat_map(|r| Ipv4Subnets::new(r.from, r.to, 0))
 }
@@ -119,10 +128,43 @@ pub fn get_device_id() -> String {
         .to_owned()
 }
 
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 * 1024), "5.0 GiB");
+    }
+
+    #[tokio::test]
+    async fn test_await_optional() {
+        let none: Option<std::future::Ready<u32>> = None;
+        let result = tokio::time::timeout(std::time::Duration::from_millis(10), await_optional(none)).await;
+        assert!(result.is_err());
+
+        assert_eq!(await_optional(Some(std::future::ready(1))).await, 1);
+    }
+
     #[test]
     fn test_encode_decode() {
         let username = "testuser";
@@ -88,6 +88,11 @@ async fn main() -> anyhow::Result<()> {
                     },
                     since
                 );
+                if let Some(ref stats) = status.statistics {
+                    println!("Statistics: {stats}");
+                }
+            } else if let Some(ref reason) = status.last_disconnect_reason {
+                println!("Disconnected, last reason: {reason}");
             } else {
                 println!("Disconnected");
             }